@@ -0,0 +1,10 @@
+use std::process;
+
+use lycee::kv::exec;
+
+fn main() {
+    if let Err(e) = exec(std::env::args().collect()) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -18,7 +18,7 @@ impl Greeter for MyGreeter {
         let b = catch_backtrace(0, 5);
         println!("symbol:\n {}\nbacktrace:\n{:?}", innermost_symbol(&b), b);
         let reply = HelloReply {
-            message: format!("Hello {}!", request.into_inner().name).into(),
+            message: format!("Hello {}!", request.into_inner().name),
         };
         Ok(Response::new(reply))
     }
@@ -1,2 +1,4 @@
 mod server;
 mod storage;
+
+pub use storage::{corrupt_s, exec, kvdb_s, options_s};
@@ -1,115 +1,487 @@
-use std::{io, mem};
-use std::io::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+use std::io::{Error, ErrorKind, Result};
 
-use crate::kv::storage::inner::{BUSY_PAGE_NUM_POS, busy_page_num_s, FILE_META_LEN, gpid_t, kvdb_assert, MAX_CHUNK_NUM, PAGE_BITMAP_LEN, PAGE_BITMAP_PAGES, page_bitmap_s, PAGE_NUM_PER_CK, PAGE_SIZE};
+use crate::kv::storage::dwb::{dwb_s, DWB_BITMAP};
+use crate::kv::storage::inner::{BUSY_PAGE_NUM_POS, busy_page_num_s, corrupt, FILE_META_LEN, file_header_s, gpid_t, kvdb_assert, MAX_CHUNK_NUM, PAGE_BITMAP_LEN, bitmap_pages, page_bitmap_s, PAGE_NUM_PER_CK, PAGE_NUM_USABLE_PER_CK};
 use crate::kv::storage::kvdb::kvdb_s;
 use crate::kv::storage::mmap::{CFile, MapT};
+use crate::kv::storage::wal::wal_s;
 
 pub type ckid_t = usize;
 //local page id
 pub type lpid_t = usize;
 
 
+/*
+ * allocator_s -- the page allocator, it is shared by the users of a database
+ *                under the lock in kvdb_s. The lock of the header is taken
+ *                after it when both are needed. The busy page numbers and the
+ *                bitmaps are private maps, which are written by write() only
+ *                after the log of their changes, see wal.rs.
+ */
 pub struct allocator_s {
     pub(crate) curr_ck: ckid_t,
     pub(crate) bpn: MapT<busy_page_num_s>,
-    pb: Option<MapT<page_bitmap_s>>,
+    /* the bitmaps changed since the last write(), and the one of the current chunk */
+    pbs: BTreeMap<ckid_t, MapT<page_bitmap_s>>,
+    /* the pages taken by operations which are not logged yet, their bits are not written */
+    pub(crate) pending: BTreeSet<gpid_t>,
+    /* the chunks below it are full, find_ck() starts from it, and a page released below lowers it */
+    pub(crate) low_ck: ckid_t,
+    pg_size: usize,
+    /* the file grows to a multiple of it */
+    grow_size: u64,
 }
 
-impl kvdb_s {
-    pub fn init_allocator(&mut self) -> Result<()> {
-        let mut ck: ckid_t = 0;
-        /*
-        * if the file size smaller than the area of busy page number, the file is
-        * a new one, so it is needed to be expanded.
-        */
-        self.alc = Some(allocator_s {
+impl allocator_s {
+    /* new() -- map the busy page numbers, the file is expanded to hold them if it is a new one */
+    pub(crate) fn new(file: &CFile, h: &mut file_header_s, grow_size: u64) -> Result<allocator_s> {
+        file_allocate(file, h, BUSY_PAGE_NUM_POS, mem::size_of::<busy_page_num_s>(), grow_size)?;
+        Ok(allocator_s {
             curr_ck: ckid_t::MAX,
-            bpn: self.file.map_mut(BUSY_PAGE_NUM_POS as u64)?,
-            pb: None,
-        });
+            bpn: file.map_copy(BUSY_PAGE_NUM_POS as u64)?,
+            pbs: BTreeMap::new(),
+            pending: BTreeSet::new(),
+            low_ck: 0,
+            pg_size: h.page_size.get() as usize,
+            grow_size,
+        })
+    }
+    /*
+     * write() -- write the busy page numbers and the changed bitmaps to the
+     *            file, once the log is durable. The bitmaps go through
+     *            the double-write area. The pending pages are written as
+     *            free, a crash before their operation is logged would leak
+     *            them otherwise. Only the bitmaps of the current chunk and
+     *            of the pending pages stay mapped after that.
+     */
+    pub(crate) fn write(&mut self, file: &CFile, wal: &wal_s, dwb: &dwb_s) -> Result<()> {
+        wal.flush(wal.lsn())?;
+        self.mark_pending(false);
+        /* the bitmaps go first, a chunk counted busy never has a bitmap all zero, see pb_intact() */
+        let pg_size = self.pg_size;
+        let w: Vec<(u32, usize, &[u8])> = self.pbs.iter_mut().map(|(ck, pb)| {
+            pb.seal();
+            (DWB_BITMAP, ck_pos(*ck, pg_size), pb.as_bytes())
+        }).collect();
+        let ret = dwb.write(file, &w).and_then(|_| file.write_at(self.bpn.as_bytes(), BUSY_PAGE_NUM_POS as u64));
+        self.mark_pending(true);
+        ret?;
+        let curr_ck = self.curr_ck;
+        let pending = &self.pending;
+        self.pbs.retain(|ck, _| *ck == curr_ck || pending.iter().any(|gpid| kvdb_s::get_ck(*gpid) == *ck));
+        Ok(())
+    }
+    /* mark_pending() -- set or clear the bits of the pending pages, and count them in or off their chunks */
+    fn mark_pending(&mut self, busy: bool) {
+        for &gpid in self.pending.iter() {
+            let (ck, lpid) = (kvdb_s::get_ck(gpid), kvdb_s::get_lpid(gpid));
+            let pb = self.pbs.get_mut(&ck).unwrap();
+            let n = &mut self.bpn.n[ck];
+            if busy {
+                pb.set_busy(lpid);
+                n.set(n.get() + 1);
+            } else {
+                pb.set_free(lpid);
+                n.set(n.get() - 1);
+            }
+        }
+    }
+    /* close_curr_ck() -- stop allocating from the current chunk, its bitmap stays until it is written */
+    pub(crate) fn close_curr_ck(&mut self) {
+        kvdb_assert(self.curr_ck != ckid_t::MAX);
+        self.curr_ck = ckid_t::MAX;
+    }
+    /* pb() -- the bitmap of the chunk ck, it is mapped and checked if it is not yet */
+    pub(crate) fn pb(&mut self, file: &CFile, ck: ckid_t) -> Result<&mut MapT<page_bitmap_s>> {
+        if !self.pbs.contains_key(&ck) {
+            let pb: MapT<page_bitmap_s> = file.map_copy(ck_pos(ck, self.pg_size) as u64)?;
+            if !self.pb_intact(ck, &pb) {
+                return Err(corrupt(kvdb_s::get_gpid(ck, 0)));
+            }
+            self.pbs.insert(ck, pb);
+        }
+        Ok(self.pbs.get_mut(&ck).unwrap())
+    }
+    /* peek_pb() -- f on the bitmap of the chunk ck as it is now, one which is not mapped is read but not kept */
+    pub(crate) fn peek_pb<R, F>(&self, file: &CFile, ck: ckid_t, f: F) -> Result<R>
+        where F: FnOnce(&page_bitmap_s) -> R {
+        if let Some(pb) = self.pbs.get(&ck) {
+            return Ok(f(pb));
+        }
+        let pb: MapT<page_bitmap_s> = file.map_copy(ck_pos(ck, self.pg_size) as u64)?;
+        if !self.pb_intact(ck, &pb) {
+            return Err(corrupt(kvdb_s::get_gpid(ck, 0)));
+        }
+        Ok(f(&pb))
+    }
+    /*
+     * pb_intact() -- the bitmap of the chunk ck read from the file is intact.
+     *                One all zero is only taken for a chunk which is never
+     *                opened, or is cut off the file, and has no busy pages
+     *                but the ones of its bitmap.
+     */
+    fn pb_intact(&self, ck: ckid_t, pb: &page_bitmap_s) -> bool {
+        pb.is_intact() || (self.bpn.n[ck].get() as usize <= bitmap_pages(self.pg_size) && pb.is_zero())
+    }
+    fn curr_pb(&self) -> &MapT<page_bitmap_s> {
+        &self.pbs[&self.curr_ck]
+    }
+    pub(crate) fn pb_set(&mut self, pg: lpid_t) {
+        self.pbs.get_mut(&self.curr_ck).unwrap().set_busy(pg);
+    }
+    /* find the first page in the current chunk whose bit is clear */
+    pub(crate) fn pb_find_free(&self) -> Option<lpid_t> {
+        find_free(self.curr_pb())
+    }
+    /*
+     * find_ck() -- find the lowest chunk which has free pages to allocate.
+     *              A chunk is a candidate again as soon as a page of it is
+     *              released, its busy page number is below the usable one.
+     *              The full chunks below low_ck are not looked at again.
+     */
+    pub(crate) fn find_ck(&mut self) -> ckid_t {
+        let ck = (self.low_ck..MAX_CHUNK_NUM).find(|ck| (self.bpn.n[*ck].get() as usize) < PAGE_NUM_USABLE_PER_CK);
+        self.low_ck = ck.unwrap_or(MAX_CHUNK_NUM);
+        ck.unwrap_or(ckid_t::MAX)
+    }
+}
 
-        if self.h.file_size < BUSY_PAGE_NUM_POS as u64 + mem::size_of::<busy_page_num_s>() as u64 {
-            self.h.file_size = self.file.metadata()?.len()
+/* find_free() -- the first page of a chunk whose bit is clear in its bitmap */
+fn find_free(pb: &page_bitmap_s) -> Option<lpid_t> {
+    for (w, word) in pb.w.iter().enumerate() {
+        if word.get() != u64::MAX {
+            return Some((w << 6) + word.get().trailing_ones() as usize);
         }
+    }
+    None
+}
 
-        ck = self.find_ck(0);
-        kvdb_assert(ck != ckid_t::MAX);
+/*
+ * file_allocate() -- make sure the file covers the range [pos, pos + len).
+ *                    It grows to the next multiple of grow_size, but not
+ *                    beyond the last chunk.
+ */
+pub(crate) fn file_allocate(file: &CFile, h: &mut file_header_s, pos: usize, len: usize, grow_size: u64) -> Result<()> {
+    let end = (pos + len) as u64;
+    if h.file_size.get() >= end {
+        return Ok(());
+    }
+    let max = ck_pos(MAX_CHUNK_NUM, h.page_size.get() as usize) as u64;
+    let size = match grow_size {
+        0 => end,
+        g => (end.div_ceil(g) * g).min(max).max(end),
+    };
+    file.allocate(size)?;
+    h.file_size.set(size);
+    Ok(())
+}
+
+/* full() -- the error of an allocation when every chunk is busy */
+fn full() -> Error {
+    Error::new(ErrorKind::StorageFull, format!("the database is full, all the {} chunks are busy", MAX_CHUNK_NUM))
+}
+
+/* ck_pos() -- the offset of the chunk ck, its bitmap is at the head of it */
+pub(crate) fn ck_pos(ck: ckid_t, page_size: usize) -> usize {
+    kvdb_s::get_page_pos(kvdb_s::get_gpid(ck, 0), page_size)
+}
 
-        self.open_ck(ck)
+impl kvdb_s {
+    /* init_allocator() -- open the first chunk which has free pages */
+    pub fn init_allocator(&self) -> Result<()> {
+        let mut alc = self.alc.lock().unwrap();
+        let ck = alc.find_ck();
+        if ck == ckid_t::MAX {
+            return Err(full());
+        }
+        self.open_ck(&mut alc, ck)
     }
 
     /*
      * open_ck() -- load a page bitmap into memory. At any moment, there is only
      * 				one ck could be staying in the memory to provide free pages.
      */
-    pub(crate) fn open_ck(&mut self, ck: ckid_t) -> Result<()> {
-        let mut pos: usize;
-        if let Some(ref mut alc) = self.alc {
-            kvdb_assert(alc.curr_ck == ckid_t::MAX);
-            kvdb_assert(ck != ckid_t::MAX);
-
-            alc.curr_ck = ck;
-            pos = Self::get_ck_pos(ck);
-
-            alc.pb = Some(self.file.map_mut(pos as u64)?);
-
-            if alc.bpn.n[ck as usize] == 0 {
-                if self.h.file_size < pos as u64 + PAGE_BITMAP_LEN as u64 {
-                    self.h.file_size = self.file.metadata()?.len()
-                }
-                alc.bpn.n[ck as usize] = PAGE_BITMAP_PAGES;
-                for i in 0..PAGE_BITMAP_PAGES {
-                    self.pb_set(i as lpid_t);
-                }
+    pub(crate) fn open_ck(&self, alc: &mut allocator_s, ck: ckid_t) -> Result<()> {
+        kvdb_assert(ck != ckid_t::MAX);
+        file_allocate(&self.file, &mut self.hd(), self.get_ck_pos(ck), PAGE_BITMAP_LEN, alc.grow_size)?;
+        let bitmap_pages = bitmap_pages(self.pg_size);
+        alc.pb(&self.file, ck)?;
+        kvdb_assert(alc.curr_ck == ckid_t::MAX);
+        alc.curr_ck = ck;
+        /* the bitmap pages at the head of a new chunk are always busy, a chunk cut off the file is a new one again */
+        if !alc.curr_pb().is_busy(0) {
+            alc.bpn.n[ck].set(bitmap_pages as u32);
+            for i in 0..bitmap_pages {
+                alc.pb_set(i as lpid_t);
             }
         }
         Ok(())
     }
-    pub(crate) fn close_curr_ck(&mut self) -> Result<()> {
-        let alc = self.alc.as_mut().unwrap();
-        kvdb_assert(alc.curr_ck != ckid_t::MAX);
-        if let Some(pb) = alc.pb.as_mut() {
-            pb.flush()?;
-            alc.pb = None;
-            alc.curr_ck = ckid_t::MAX;
-        };
+    /* turn_ck() -- allocate from the chunk ck from now on, the current one stays if ck can not be opened */
+    pub(crate) fn turn_ck(&self, alc: &mut allocator_s, ck: ckid_t) -> Result<()> {
+        let curr_ck = alc.curr_ck;
+        alc.close_curr_ck();
+        if let Err(e) = self.open_ck(alc, ck) {
+            alc.curr_ck = curr_ck;
+            return Err(e);
+        }
         Ok(())
     }
-    pub(crate) fn pb_set(&mut self, pg: lpid_t) {
-        let w = pg >> 6;
-        let b = pg & 63;
-        if let Some(ref mut alc) = self.alc {
-            alc.pb.unwrap().w[w as usize] |= 1 << b;
+    /* alloc_page() -- take a free page, it is logged with the running operation */
+    pub(crate) fn alloc_page(&self) -> Result<gpid_t> {
+        let mut alc = self.alc.lock().unwrap();
+        let mut ck = alc.curr_ck;
+        kvdb_assert(ck != ckid_t::MAX);
+        /*
+         * If there is not any free page in the chunk, then we find the lowest
+         * one which has and turn to it, the pages freed before the file grows
+         */
+        if alc.bpn.n[ck].get() as usize >= PAGE_NUM_USABLE_PER_CK {
+            ck = alc.find_ck();
+            if ck == ckid_t::MAX {
+                return Err(full());
+            }
+            self.turn_ck(&mut alc, ck)?;
         }
+        /* Find a free page in the chunk */
+        let lpid = alc.pb_find_free();
+        kvdb_assert(lpid.is_some());
+        let lpid = lpid.unwrap();
+        let gpid = kvdb_s::get_gpid(ck, lpid);
+        file_allocate(&self.file, &mut self.hd(), kvdb_s::get_page_pos(gpid, self.pg_size), self.pg_size, alc.grow_size)?;
+        alc.pb_set(lpid);
+        let n = alc.bpn.n[ck].get();
+        alc.bpn.n[ck].set(n + 1);
+        alc.pending.insert(gpid);
+        drop(alc);
+        self.mtr_alloc(gpid)?;
+        Ok(gpid)
     }
-    /* find a chunk which has free pages to allocate */
-    pub(crate) fn find_ck(&mut self, ck: ckid_t) -> ckid_t {
-        if let Some(ref alc) = self.alc {
-            for i in 0..MAX_CHUNK_NUM {
-                let r = (ck + i) % MAX_CHUNK_NUM;
-                if alc.bpn.n[i] < PAGE_NUM_PER_CK {
-                    return r;
-                }
+    /*
+     * alloc_page_below() -- take the lowest free page, if there is one below
+     *                       limit. It is logged with the running operation.
+     *                       The chunks never opened are left out.
+     */
+    pub(crate) fn alloc_page_below(&self, limit: gpid_t) -> Result<Option<gpid_t>> {
+        let mut alc = self.alc.lock().unwrap();
+        for ck in alc.low_ck..=kvdb_s::get_ck(limit) {
+            let n = alc.bpn.n[ck].get() as usize;
+            if n == 0 || n >= PAGE_NUM_USABLE_PER_CK {
+                continue;
             }
+            let pb = alc.pb(&self.file, ck)?;
+            let lpid = match find_free(pb) {
+                Some(lpid) => lpid,
+                None => continue,
+            };
+            let gpid = kvdb_s::get_gpid(ck, lpid);
+            if gpid >= limit {
+                break;
+            }
+            pb.set_busy(lpid);
+            alc.bpn.n[ck].set(n as u32 + 1);
+            alc.pending.insert(gpid);
+            drop(alc);
+            self.mtr_alloc(gpid)?;
+            return Ok(Some(gpid));
         }
-        return ckid_t::MAX;
+        Ok(None)
+    }
+    /*
+     * free_page() -- give a page back to the allocator. It stays busy until
+     *                the running operation is logged, or another one could
+     *                reuse it before the operation is durable.
+     */
+    pub(crate) fn free_page(&self, gpid: gpid_t) -> Result<()> {
+        kvdb_assert(kvdb_s::get_lpid(gpid) >= bitmap_pages(self.pg_size));
+        self.mtr_free(gpid)
+    }
+    /*
+     * release_page() -- clear the bit of a freed page and count it off its
+     *                   chunk, which find_ck() takes again then. The bitmap
+     *                   of the chunk stays mapped until it is written.
+     */
+    pub(crate) fn release_page(&self, gpid: gpid_t) -> Result<()> {
+        let ck = kvdb_s::get_ck(gpid);
+        let lpid = kvdb_s::get_lpid(gpid);
+        let mut alc = self.alc.lock().unwrap();
+        let pb = alc.pb(&self.file, ck)?;
+        kvdb_assert(pb.is_busy(lpid));
+        pb.set_free(lpid);
+        let n = alc.bpn.n[ck].get();
+        alc.bpn.n[ck].set(n - 1);
+        alc.low_ck = alc.low_ck.min(ck);
+        Ok(())
     }
     pub(crate) fn get_gpid(ck: ckid_t, lpid: lpid_t) -> gpid_t {
-        (ck as gpid_t) * PAGE_NUM_PER_CK + lpid as usize
+        (ck * PAGE_NUM_PER_CK + lpid) as gpid_t
     }
-    pub(crate) fn pb_isset(&self, pg: lpid_t) -> bool {
-        let w = pg >> 6;
-        let b = pg & 63;
-        return (self.alc.unwrap().pb.unwrap().w[w] & (1 << b)) != 0;
+    /* get_ck(), get_lpid() -- the chunk of the page gpid, and the page in it */
+    pub(crate) fn get_ck(gpid: gpid_t) -> ckid_t {
+        (gpid / PAGE_NUM_PER_CK as gpid_t) as ckid_t
     }
-    pub(crate) fn get_page_pos(gpid: gpid_t) -> usize {
-        FILE_META_LEN + gpid * PAGE_SIZE
+    pub(crate) fn get_lpid(gpid: gpid_t) -> lpid_t {
+        (gpid % PAGE_NUM_PER_CK as gpid_t) as lpid_t
     }
-    fn get_ck_pos(ck: ckid_t) -> usize {
-        let gpid = Self::get_gpid(ck, 0);
-        let pos = Self::get_page_pos(gpid);
-        return pos;
+    pub(crate) fn get_page_pos(gpid: gpid_t, page_size: usize) -> usize {
+        FILE_META_LEN + gpid as usize * page_size
+    }
+    fn get_ck_pos(&self, ck: ckid_t) -> usize {
+        ck_pos(ck, self.pg_size)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::mem;
+    use std::path::PathBuf;
+
+    use crate::kv::storage::allocator::ck_pos;
+    use crate::kv::storage::inner::{BUSY_PAGE_NUM_POS, busy_page_num_s, gpid_t, MAX_CHUNK_NUM, PAGE_BITMAP_CRC, PAGE_NUM_USABLE_PER_CK, page_bitmap_s};
+    use crate::kv::storage::kvdb::{kvdb_s, options_s};
+
+    fn db_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("lycee-allocator-{}-{}.db", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /* take all the pages left in the current chunk, without logging it */
+    fn fill_curr_ck(db: &kvdb_s) -> usize {
+        let mut alc = db.alc.lock().unwrap();
+        let ck = alc.curr_ck;
+        let pb = alc.pb(&db.file, ck).unwrap();
+        for w in pb.w[..PAGE_BITMAP_CRC / 8].iter_mut() {
+            w.set(u64::MAX);
+        }
+        alc.bpn.n[ck].set(PAGE_NUM_USABLE_PER_CK as u32);
+        ck
+    }
+
+    #[test]
+    fn test_free_reuse() {
+        let path = db_path("free_reuse");
+        let opts = options_s { page_size: 512, cache_size: 64 * 512, ..Default::default() };
+        let db = kvdb_s::open_with(&path, &opts).unwrap();
+        let ck = kvdb_s::get_ck;
+        let alloc = || db.mtr(|| db.alloc_page()).unwrap();
+        let free = |gpid: gpid_t| db.mtr(|| db.free_page(gpid)).unwrap();
+        let busy = |gpid: gpid_t| {
+            let alc = db.alc.lock().unwrap();
+            alc.peek_pb(&db.file, ck(gpid), |pb| pb.is_busy(kvdb_s::get_lpid(gpid))).unwrap()
+        };
+        let busy_num = |c: usize| db.alc.lock().unwrap().bpn.n[c].get() as usize;
+
+        let a = alloc();
+        assert_eq!(0, fill_curr_ck(&db));
+        let b = alloc();
+        assert_eq!((0, 1), (ck(a), ck(b)));
+
+        /* a freed page is clear and counted off at once, its chunk is taken again before a new one */
+        free(a);
+        assert!(!busy(a) && busy(b));
+        assert_eq!(PAGE_NUM_USABLE_PER_CK - 1, busy_num(0));
+        assert_eq!(1, fill_curr_ck(&db));
+        let c = alloc();
+        assert_eq!(a, c);
+        let file_size = db.hd().file_size.get();
+        let d = alloc();
+        assert_eq!(2, ck(d));
+        assert!(db.hd().file_size.get() > file_size);
+
+        /* the lowest chunk with a free page comes first */
+        free(b);
+        free(c);
+        assert_eq!(2, fill_curr_ck(&db));
+        let file_size = db.hd().file_size.get();
+        assert_eq!((a, b), (alloc(), alloc()));
+        assert!(busy(a) && busy(b));
+        assert_eq!([PAGE_NUM_USABLE_PER_CK; 3], [busy_num(0), busy_num(1), busy_num(2)]);
+        assert_eq!(file_size, db.hd().file_size.get());
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_grow() {
+        let path = db_path("grow");
+        for &grow_size in [0u64, 1 << 20].iter() {
+            let opts = options_s { page_size: 512, cache_size: 64 * 512, grow_size, ..Default::default() };
+            let db = kvdb_s::open_with(&path, &opts).unwrap();
+            let mut last = 0;
+            for _ in 0..2000 {
+                let gpid = db.mtr(|| db.alloc_page()).unwrap();
+                last = last.max(gpid);
+                let size = db.hd().file_size.get();
+                assert_eq!(size, db.file.len().unwrap());
+                /* the file grows by the page past the busy page numbers, or to the next multiple of the grow size */
+                let end = (kvdb_s::get_page_pos(last, 512) + 512).max(BUSY_PAGE_NUM_POS + mem::size_of::<busy_page_num_s>()) as u64;
+                match grow_size {
+                    0 => assert_eq!(end, size),
+                    g => assert!(size.is_multiple_of(g) && size >= end && size - end < g),
+                }
+            }
+            drop(db);
+            let db = kvdb_s::open_with(&path, &opts).unwrap();
+            assert_eq!(db.file.len().unwrap(), db.hd().file_size.get());
+            drop(db);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_full() {
+        let path = db_path("full");
+        let opts = options_s { page_size: 512, cache_size: 64 * 512, ..Default::default() };
+        let db = kvdb_s::open_with(&path, &opts).unwrap();
+        let a = db.mtr(|| db.alloc_page()).unwrap();
+        fill_curr_ck(&db);
+        for n in db.alc.lock().unwrap().bpn.n[1..MAX_CHUNK_NUM].iter_mut() {
+            n.set(PAGE_NUM_USABLE_PER_CK as u32);
+        }
+        let file_size = db.hd().file_size.get();
+        let e = db.mtr(|| db.alloc_page()).err().unwrap();
+        assert_eq!(ErrorKind::StorageFull, e.kind());
+        assert_eq!(file_size, db.hd().file_size.get());
+        assert_eq!(MAX_CHUNK_NUM, db.alc.lock().unwrap().low_ck);
+
+        /* a freed page is taken again once it is released, its chunk is looked at again */
+        db.mtr(|| db.free_page(a)).unwrap();
+        assert_eq!(0, db.alc.lock().unwrap().low_ck);
+        assert_eq!(a, db.mtr(|| db.alloc_page()).unwrap());
+        assert_eq!(ErrorKind::StorageFull, db.mtr(|| db.alloc_page()).err().unwrap().kind());
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_pending() {
+        let path = db_path("pending");
+        let opts = options_s { page_size: 512, cache_size: 64 * 512, ..Default::default() };
+        let db = kvdb_s::open_with(&path, &opts).unwrap();
+        let on_disk = |gpid: gpid_t| {
+            let ck = kvdb_s::get_ck(gpid);
+            let pb = db.file.map_copy::<page_bitmap_s>(ck_pos(ck, 512) as u64).unwrap();
+            let bpn = db.file.map_copy::<busy_page_num_s>(BUSY_PAGE_NUM_POS as u64).unwrap();
+            (pb.is_busy(kvdb_s::get_lpid(gpid)), bpn.n[ck].get())
+        };
+
+        /* a checkpoint before the operation is logged writes its page as free */
+        let a = db.mtr(|| {
+            let a = db.alloc_page()?;
+            db.sync()?;
+            Ok(a)
+        }).unwrap();
+        let (busy, n) = on_disk(a);
+        assert!(!busy);
+        assert!(db.alc.lock().unwrap().pending.is_empty());
+        db.sync().unwrap();
+        assert_eq!((true, n + 1), on_disk(a));
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,439 @@
+use std::cmp::Ordering;
+use std::io::Result;
+use std::sync::RwLockWriteGuard;
+
+use crate::kv::storage::inner::gpid_t;
+use crate::kv::storage::kvdb::{FOUND_EXACT, FOUND_GREATER, kvdb_s, OK, PAGE_DELETED, PAGE_SPLITTED, pg_t, REC_INSERTED, REC_NOT_FOUND, REC_REPLACED};
+use crate::kv::storage::page::{max_record_size, packed_size, page_s, record_s, separator, split_point};
+
+/*
+ * The tree is latched top-down. A reader holds the latch of a page until
+ * the one of its child is taken, and so does a writer, which keeps the
+ * exclusive latches of the ancestors only while the page below may still
+ * split or underflow, and let them go as soon as it is safe. A writer tries
+ * first with shared latches down to the leaf, and takes the exclusive path
+ * only if the leaf is not safe. The pages a writer modifies stay latched
+ * until its mini-transaction is logged, see wal.rs, and it may latch their
+ * siblings meanwhile, under their parent. So nobody waits for a sibling
+ * while holding a page, a reader goes to the next leaf from the root again,
+ * by the fence of the leaf, and the leaves are only linked forward.
+ */
+
+/*
+ * leaf_s -- a latched leaf, the least key it may hold, None for the first
+ *           leaf, and the least key of the leaves after it, None for the
+ *           last leaf.
+ */
+pub(crate) struct leaf_s {
+    pub(crate) p: pg_t,
+    pub(crate) fence: Option<Vec<u8>>,
+    pub(crate) hi_fence: Option<Vec<u8>>,
+    pub(crate) root: bool,
+}
+
+/* the pages latched exclusive on the way down, and the position of the child in each */
+type path_t = Vec<(pg_t, usize)>;
+
+impl kvdb_s {
+    /*
+     * bpt_search() -- look up k in the tree. FOUND_EXACT is returned if the
+     *                 key exists, FOUND_GREATER if only a greater key exists,
+     *                 and rec holds the record found, with its value read
+     *                 out of the overflow pages under the latch of the leaf.
+     */
+    pub(crate) fn bpt_search(&self, k: &[u8], rec: &mut record_s) -> Result<i8> {
+        let mut key = k.to_vec();
+        loop {
+            let leaf = match self.bpt_leaf(Some(&key), false, false)? {
+                Some(leaf) => leaf,
+                None => return Ok(REC_NOT_FOUND),
+            };
+            let p = leaf.p;
+            let (pos, exact) = p.search(&key);
+            if pos < p.record_num() {
+                *rec = self.rec_resolve(p.record(pos))?;
+                return Ok(if exact && key == k { FOUND_EXACT } else { FOUND_GREATER });
+            }
+            /* all keys of the leaf are less than k, the next leaves start from the fence */
+            match leaf.hi_fence {
+                Some(hi) => key = hi,
+                None => return Ok(REC_NOT_FOUND),
+            }
+        }
+    }
+
+    /*
+     * bpt_leaf() -- crab down from the root to the leaf of k, or to the last
+     *               leaf if k is None. With below, it is the leaf of the keys
+     *               right below k instead. The leaf is latched exclusive if
+     *               excl, the internal pages are only latched shared. None
+     *               is returned if the tree is empty.
+     */
+    pub(crate) fn bpt_leaf(&self, k: Option<&[u8]>, below: bool, excl: bool) -> Result<Option<leaf_s>> {
+        /* the root does not change while its latch is being taken */
+        let (mut p, mut level) = {
+            let _t = self.tree.read().unwrap();
+            let (level, root) = self.root();
+            if level == 0 {
+                return Ok(None);
+            }
+            /* a new root goes on top, and only a root is collapsed, so the depth below it stays */
+            (if excl && level == 1 { self.get_page_mut(root)? } else { self.get_page(root)? }, level)
+        };
+        let root = level == 1;
+        let mut fence = None;
+        let mut hi_fence = None;
+        while level > 1 {
+            let i = match k {
+                Some(k) => {
+                    let i = p.child_pos(k);
+                    if below && i > 0 && p.cmp_key(i, k) == Ordering::Equal { i - 1 } else { i }
+                }
+                None => p.record_num() - 1,
+            };
+            if i > 0 {
+                fence = Some(p.key(i));
+            }
+            if i + 1 < p.record_num() {
+                hi_fence = Some(p.key(i + 1));
+            }
+            let child = p.child(i);
+            p = if excl && level == 2 { self.get_page_mut(child)? } else { self.get_page(child)? };
+            level -= 1;
+        }
+        assert!(p.is_leaf(), "the page {} at level 1 is not a leaf", p.gpid());
+        Ok(Some(leaf_s { p, fence, hi_fence, root }))
+    }
+
+    /*
+     * bpt_path() -- crab down from the root to the leaf of k with exclusive
+     *               latches. Once a page is safe, which safe() tells with
+     *               whether it is the root, the ancestors are released, and
+     *               so is the tree lock if it is the root. The leaf and the
+     *               pages still latched above it are returned.
+     */
+    fn bpt_path<F>(&self, t: &mut Option<RwLockWriteGuard<()>>, k: &[u8], safe: F) -> Result<(pg_t, path_t)>
+        where F: Fn(&page_s, bool) -> bool {
+        let mut path = Vec::new();
+        let mut p = self.get_page_mut(self.root().1)?;
+        if safe(&p, true) {
+            *t = None;
+        }
+        while !p.is_leaf() {
+            let i = p.child_pos(k);
+            let child = self.get_page_mut(p.child(i))?;
+            path.push((p, i));
+            if safe(&child, false) {
+                *t = None;
+                path.clear();
+            }
+            p = child;
+        }
+        Ok((p, path))
+    }
+
+    /*
+     * bpt_insert() -- insert rec into the tree, and return REC_INSERTED or
+     *                 REC_REPLACED. It is done in the leaf alone if there is
+     *                 room, or along the exclusive path otherwise. A new
+     *                 record is counted with the running operation.
+     */
+    pub(crate) fn bpt_insert(&self, rec: &record_s) -> Result<i8> {
+        if let Some(ret) = self.bpt_insert_leaf(rec)? {
+            return Ok(ret);
+        }
+        let mut t = Some(self.tree.write().unwrap());
+        let old = match self.bpt_insert_path(&mut t, rec) {
+            Ok(old) => old,
+            /* the changes are undone before anyone else could take the tree lock */
+            Err(e) => {
+                self.mtr_abort()?;
+                return Err(e);
+            }
+        };
+        if t.is_some() {
+            self.mtr_split()?;
+        }
+        drop(t);
+        /* the overflow pages of the old value are not used any more */
+        if let Some(old) = old.as_ref().filter(|r| r.ovf) {
+            self.ovf_free(old)?;
+        }
+        Ok(if old.is_some() { REC_REPLACED } else { REC_INSERTED })
+    }
+
+    /*
+     * bpt_insert_path() -- insert rec along the exclusive path, and return
+     *                      the record it replaces, if any. The tree lock t
+     *                      is dropped once the root is safe.
+     */
+    fn bpt_insert_path(&self, t: &mut Option<RwLockWriteGuard<()>>, rec: &record_s) -> Result<Option<record_s>> {
+        /* the tree lock is kept for a new root, or a rollback would empty the tree again after it is released */
+        let made = self.root().0 == 0;
+        if made {
+            self.make_root(true)?;
+        }
+        let old_root = self.root().1;
+        let max = max_record_size(self.pg_size);
+        let (mut p, mut path) = self.bpt_path(t, &rec.k, |p, root| !(made && root) && p.fits(if p.is_leaf() { rec.size() } else { max }))?;
+
+        let (pos, exact) = p.search(&rec.k);
+        let mut old = None;
+        if exact {
+            old = Some(p.record(pos));
+            p.remove_at(pos);
+        } else {
+            self.mtr_note(|m| m.records += 1)?;
+        }
+        let mut split = None;
+        self.bpt_place(p, pos, rec, &mut split)?;
+        /* a splitted page is linked into its parent, right after itself */
+        while let Some(s) = split.take() {
+            match path.pop() {
+                Some((p, i)) => {
+                    self.bpt_place(p, i + 1, &s, &mut split)?;
+                }
+                /* the root has been splitted, so the tree grows up by one level */
+                None => {
+                    assert!(t.is_some(), "the root is split without the tree lock");
+                    self.bpt_new_root(old_root, &s)?;
+                }
+            }
+        }
+        Ok(old)
+    }
+
+    /*
+     * bpt_insert_leaf() -- insert rec if its leaf surely has room for it, None
+     *                      is returned if not. The leaf is left untouched
+     *                      then, or it would be logged.
+     */
+    fn bpt_insert_leaf(&self, rec: &record_s) -> Result<Option<i8>> {
+        let mut p = match self.bpt_leaf(Some(&rec.k), false, true)? {
+            Some(leaf) => leaf.p,
+            None => return Ok(None),
+        };
+        let (pos, exact) = p.search(&rec.k);
+        if !p.fits(rec.size()) {
+            return Ok(None);
+        }
+        let old = if exact { Some(p.record(pos)) } else { None };
+        if exact {
+            p.remove_at(pos);
+        } else {
+            self.mtr_note(|m| m.records += 1)?;
+        }
+        let ret = p.insert_rec(pos, rec);
+        assert!(ret, "the record does not fit in the leaf {}", p.gpid());
+        self.put_page(p);
+        if let Some(old) = old.filter(|r| r.ovf) {
+            self.ovf_free(&old)?;
+        }
+        Ok(Some(if exact { REC_REPLACED } else { REC_INSERTED }))
+    }
+
+    /*
+     * bpt_place() -- put rec at position pos of the page p. If there is no
+     *                room for it, the page is splitted and PAGE_SPLITTED is
+     *                returned with split filled.
+     */
+    fn bpt_place(&self, mut p: pg_t, pos: usize, rec: &record_s, split: &mut Option<record_s>) -> Result<i8> {
+        if p.insert_rec(pos, rec) {
+            self.put_page(p);
+            return Ok(OK);
+        }
+
+        let leaf = p.is_leaf();
+        let mut recs = p.records();
+        recs.insert(pos, rec.clone());
+        let m = split_point(&recs, p.capacity(), usize::MAX, leaf)
+            .unwrap_or_else(|| panic!("no split point for the page {}", p.gpid()));
+        let sep = separator(&recs, m, leaf);
+        /* the first key of an internal page is never used */
+        if !leaf {
+            recs[m].k.clear();
+        }
+
+        let right_gpid = self.alloc_page()?;
+        let mut right = self.new_page(right_gpid)?;
+        right.init(p.flags());
+        right.rebuild(&recs[m..]);
+        p.rebuild(&recs[..m]);
+        if leaf {
+            right.set_next(p.next());
+            p.set_next(right_gpid);
+        }
+        *split = Some(record_s::child(sep, right_gpid));
+        self.put_page(right);
+        self.put_page(p);
+        Ok(PAGE_SPLITTED)
+    }
+
+    /*
+     * bpt_delete() -- delete k from the tree, REC_NOT_FOUND is returned if
+     *                 there is no such key. It is done in the leaf alone if
+     *                 the leaf does not underflow, or along the exclusive
+     *                 path otherwise, where underflowed pages are rebalanced
+     *                 on the way back.
+     */
+    pub(crate) fn bpt_delete(&self, k: &[u8]) -> Result<i8> {
+        if let Some(ret) = self.bpt_delete_leaf(k)? {
+            return Ok(ret);
+        }
+        let mut t = Some(self.tree.write().unwrap());
+        let old = match self.bpt_delete_path(&mut t, k) {
+            Ok(Some(old)) => old,
+            Ok(None) => return Ok(REC_NOT_FOUND),
+            /* the changes are undone before anyone else could take the tree lock */
+            Err(e) => {
+                self.mtr_abort()?;
+                return Err(e);
+            }
+        };
+        if t.is_some() {
+            self.mtr_split()?;
+        }
+        drop(t);
+        if old.ovf {
+            self.ovf_free(&old)?;
+        }
+        Ok(OK)
+    }
+
+    /*
+     * bpt_delete_path() -- delete k along the exclusive path, and return the
+     *                      record deleted, None if there is no such key. The
+     *                      root is collapsed if the tree lock t is still held.
+     */
+    fn bpt_delete_path(&self, t: &mut Option<RwLockWriteGuard<()>>, k: &[u8]) -> Result<Option<record_s>> {
+        if self.root().0 == 0 {
+            return Ok(None);
+        }
+        let (mut p, mut path) = self.bpt_path(t, k, |p, root| {
+            let n = p.record_num();
+            match (p.is_leaf(), root) {
+                (true, true) => n > 1,
+                (false, true) => n > 2,
+                (true, false) => match p.search(k) {
+                    (pos, true) => p.can_remove(p.record_space(pos)),
+                    _ => true,
+                },
+                (false, false) => p.can_remove(p.max_record_space()),
+            }
+        })?;
+
+        let (pos, exact) = p.search(k);
+        if !exact {
+            return Ok(None);
+        }
+        let old = p.record(pos);
+        p.remove_at(pos);
+        self.mtr_note(|m| m.records -= 1)?;
+        while let Some((mut parent, i)) = path.pop() {
+            if !p.is_underflow() {
+                break;
+            }
+            self.put_page(p);
+            self.bpt_rebalance(&mut parent, i)?;
+            p = parent;
+        }
+        drop(p);
+        drop(path);
+        if t.is_some() {
+            self.collapse_root()?;
+        }
+        Ok(Some(old))
+    }
+
+    /* bpt_delete_leaf() -- delete k if its leaf is left big enough, None is returned if not */
+    fn bpt_delete_leaf(&self, k: &[u8]) -> Result<Option<i8>> {
+        let leaf = match self.bpt_leaf(Some(k), false, true)? {
+            Some(leaf) => leaf,
+            None => return Ok(Some(REC_NOT_FOUND)),
+        };
+        let mut p = leaf.p;
+        let (pos, exact) = p.search(k);
+        if !exact {
+            return Ok(Some(REC_NOT_FOUND));
+        }
+        let safe = if leaf.root { p.record_num() > 1 } else { p.can_remove(p.record_space(pos)) };
+        if !safe {
+            return Ok(None);
+        }
+        let old = p.record(pos);
+        p.remove_at(pos);
+        self.mtr_note(|m| m.records -= 1)?;
+        self.put_page(p);
+        if old.ovf {
+            self.ovf_free(&old)?;
+        }
+        Ok(Some(OK))
+    }
+
+    /*
+     * bpt_rebalance() -- fix the underflowed child i of the internal page p
+     *                    with one of its siblings, the child should be put
+     *                    by the caller. If both of them fit in one
+     *                    page, the right one is merged into the left one and
+     *                    freed, and PAGE_DELETED is returned. Otherwise the
+     *                    records are shared evenly between them, as long as
+     *                    the new separator fits in the parent.
+     */
+    fn bpt_rebalance(&self, p: &mut pg_t, i: usize) -> Result<i8> {
+        assert!(p.record_num() > 1, "the page {} has no sibling to rebalance with", p.gpid());
+        let ri = if i > 0 { i } else { i + 1 };
+        let left_gpid = p.child(ri - 1);
+        let right_gpid = p.child(ri);
+        let mut l = self.get_page_mut(left_gpid)?;
+        let mut r = self.get_page_mut(right_gpid)?;
+        let leaf = l.is_leaf();
+
+        let mut recs = l.records();
+        let mut rrecs = r.records();
+        /* the first key of an internal page is never used, bring the separator back */
+        if !leaf {
+            rrecs[0].k = p.key(ri);
+        }
+        recs.append(&mut rrecs);
+
+        if packed_size(&recs, leaf) <= l.capacity() {
+            l.rebuild(&recs);
+            if leaf {
+                l.set_next(r.next());
+            }
+            p.remove_at(ri);
+            self.put_page(r);
+            self.put_page(l);
+            self.free_page(right_gpid)?;
+            return Ok(PAGE_DELETED);
+        }
+
+        /* the new separator should fit in the parent, whose prefix may get shorter */
+        let mut precs = p.records();
+        let max_sep = precs[ri].k.len() + p.free_space();
+        if let Some(m) = split_point(&recs, l.capacity(), max_sep, leaf) {
+            precs[ri].k = separator(&recs, m, leaf);
+            if packed_size(&precs, false) <= p.capacity() {
+                if !leaf {
+                    recs[m].k.clear();
+                }
+                l.rebuild(&recs[..m]);
+                r.rebuild(&recs[m..]);
+                p.rebuild(&precs);
+            }
+        }
+        self.put_page(r);
+        self.put_page(l);
+        Ok(OK)
+    }
+
+    /* bpt_new_root() -- put a new internal root over old_root and its new right sibling */
+    pub(crate) fn bpt_new_root(&self, old_root: gpid_t, split: &record_s) -> Result<()> {
+        self.make_root(false)?;
+        let mut p = self.get_page_mut(self.root().1)?;
+        p.insert_at(0, &[], &record_s::child(Vec::new(), old_root).v);
+        p.insert_at(1, &split.k, &split.v);
+        self.put_page(p);
+        Ok(())
+    }
+}
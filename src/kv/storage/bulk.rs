@@ -0,0 +1,187 @@
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+
+use crate::kv::storage::inner::{GPID_NIL, gpid_t, kvdb_assert};
+use crate::kv::storage::kvdb::kvdb_s;
+use crate::kv::storage::page::{packed_size, PAGE_HEADER_LEN, PAGE_LEAF, record_s, shortest_separator, split_point};
+
+/*
+ * One level of the tree being built. The records of the page being filled
+ * are in recs, and the last full page is held back until the next one is
+ * started, so that the last two pages can be evened out at the end.
+ */
+struct level_s {
+    leaf: bool,
+    /* the room for records in a page, and how much of it is filled */
+    cap: usize,
+    limit: usize,
+    recs: Vec<record_s>,
+    held: Option<Vec<record_s>>,
+    /* the last written page, and the last key of it */
+    prev: gpid_t,
+    last_key: Vec<u8>,
+    /* the records pointing to the written pages, for the level above */
+    parents: Vec<record_s>,
+}
+
+impl level_s {
+    fn new(leaf: bool, cap: usize, limit: usize) -> level_s {
+        level_s { leaf, cap, limit, recs: Vec::new(), held: None, prev: GPID_NIL, last_key: Vec::new(), parents: Vec::new() }
+    }
+}
+
+impl kvdb_s {
+    /*
+     * bulk_load() -- build the tree of an empty database bottom-up from items
+     *                sorted by key. Pages are filled up to fill (0, 1] of
+     *                their room, and the number of records is returned.
+     *                Unsorted or duplicated keys are rejected, nothing is
+     *                left in the database then.
+     */
+    pub fn bulk_load<I>(&self, items: I, fill: f64) -> Result<usize>
+        where I: IntoIterator<Item=(Vec<u8>, Vec<u8>)> {
+        let _t = self.tree.write().unwrap();
+        if self.root().0 > 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "bulk load needs an empty database"));
+        }
+        if !(fill > 0.0 && fill <= 1.0) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("fill factor {} is not in (0, 1]", fill)));
+        }
+        let cap = self.pg_size - PAGE_HEADER_LEN;
+        let limit = (cap as f64 * fill) as usize;
+        let mut pages = Vec::new();
+        let mut lv = level_s::new(true, cap, limit);
+        let mut n = 0;
+        let ret = self.bulk_leaves(&mut lv, items, &mut pages, &mut n);
+        if let Err(e) = ret {
+            self.bulk_abort(&lv, &pages)?;
+            return Err(e);
+        }
+
+        let mut parents = self.bulk_finish(&mut lv, &mut pages)?;
+        let mut level = 1;
+        while parents.len() > 1 {
+            let mut lv = level_s::new(false, cap, limit);
+            for rec in parents {
+                self.bulk_push(&mut lv, rec, &mut pages)?;
+            }
+            parents = self.bulk_finish(&mut lv, &mut pages)?;
+            level += 1;
+        }
+        if let Some(root) = parents.first() {
+            self.mtr(|| {
+                self.set_root(root.gpid(), level)?;
+                self.mtr_note(|m| m.records += n as isize)
+            })?;
+        }
+        self.commit(self.wal_sync)?;
+        Ok(n)
+    }
+
+    fn bulk_leaves<I>(&self, lv: &mut level_s, items: I, pages: &mut Vec<gpid_t>, n: &mut usize) -> Result<()>
+        where I: IntoIterator<Item=(Vec<u8>, Vec<u8>)> {
+        let mut last: Option<Vec<u8>> = None;
+        for (k, v) in items {
+            if let Some(l) = &last {
+                if k <= *l {
+                    return Err(Error::new(ErrorKind::InvalidInput,
+                                          format!("keys are not sorted: {:?} after {:?}",
+                                                  String::from_utf8_lossy(&k), String::from_utf8_lossy(l))));
+                }
+            }
+            let rec = self.new_record(&k, &v)?;
+            self.bulk_push(lv, rec, pages)?;
+            last = Some(k);
+            *n += 1;
+        }
+        Ok(())
+    }
+
+    /* bulk_push() -- append rec to the level, a new page is started when the current one is full */
+    fn bulk_push(&self, lv: &mut level_s, rec: record_s, pages: &mut Vec<gpid_t>) -> Result<()> {
+        lv.recs.push(rec);
+        if lv.recs.len() > 1 && packed_size(&lv.recs, lv.leaf) > lv.limit {
+            let rec = lv.recs.pop().unwrap();
+            let full = mem::replace(&mut lv.recs, vec![rec]);
+            if let Some(held) = lv.held.replace(full) {
+                self.bulk_write(lv, held, pages)?;
+            }
+        }
+        Ok(())
+    }
+
+    /* bulk_finish() -- write the last pages of the level, an underflowed last page borrows from the one before */
+    fn bulk_finish(&self, lv: &mut level_s, pages: &mut Vec<gpid_t>) -> Result<Vec<record_s>> {
+        let last = mem::take(&mut lv.recs);
+        match lv.held.take() {
+            Some(mut held) if packed_size(&last, lv.leaf) < lv.cap / 4 => {
+                let first = held.len();
+                held.extend(last);
+                if packed_size(&held, lv.leaf) <= lv.cap {
+                    self.bulk_write(lv, held, pages)?;
+                } else {
+                    let m = split_point(&held, lv.cap, usize::MAX, lv.leaf).unwrap_or(first);
+                    let right = held.split_off(m);
+                    self.bulk_write(lv, held, pages)?;
+                    self.bulk_write(lv, right, pages)?;
+                }
+            }
+            Some(held) => {
+                self.bulk_write(lv, held, pages)?;
+                self.bulk_write(lv, last, pages)?;
+            }
+            None if !last.is_empty() => self.bulk_write(lv, last, pages)?,
+            None => {}
+        }
+        Ok(mem::take(&mut lv.parents))
+    }
+
+    /* bulk_write() -- put recs in a new page, and link it to the page written before, as one operation */
+    fn bulk_write(&self, lv: &mut level_s, recs: Vec<record_s>, pages: &mut Vec<gpid_t>) -> Result<()> {
+        self.mtr(|| self.bulk_page(lv, recs, pages))
+    }
+
+    fn bulk_page(&self, lv: &mut level_s, mut recs: Vec<record_s>, pages: &mut Vec<gpid_t>) -> Result<()> {
+        let gpid = self.alloc_page()?;
+        pages.push(gpid);
+        let sep = if !lv.leaf {
+            /* the first key of an internal page is never used, it goes up to the parent */
+            mem::take(&mut recs[0].k)
+        } else if lv.prev == GPID_NIL {
+            Vec::new()
+        } else {
+            shortest_separator(&lv.last_key, &recs[0].k)
+        };
+        let mut p = self.new_page(gpid)?;
+        p.init(if lv.leaf { PAGE_LEAF } else { 0 });
+        kvdb_assert(packed_size(&recs, lv.leaf) <= p.capacity());
+        p.rebuild(&recs);
+        if lv.leaf {
+            if lv.prev != GPID_NIL {
+                let mut q = self.get_page_mut(lv.prev)?;
+                q.set_next(gpid);
+                self.put_page(q);
+            }
+            lv.last_key = recs.pop().map(|r| r.k).unwrap_or_default();
+        }
+        self.put_page(p);
+        lv.prev = gpid;
+        lv.parents.push(record_s::child(sep, gpid));
+        Ok(())
+    }
+
+    /* bulk_abort() -- give back the pages of a failed bulk load, with their overflow pages */
+    fn bulk_abort(&self, lv: &level_s, pages: &[gpid_t]) -> Result<()> {
+        let mut recs: Vec<record_s> = lv.recs.iter().chain(lv.held.iter().flatten()).cloned().collect();
+        for &gpid in pages {
+            let p = self.get_page(gpid)?;
+            recs.extend(p.records());
+            self.put_page(p);
+            self.free_page(gpid)?;
+        }
+        for rec in recs.iter().filter(|r| r.ovf) {
+            self.ovf_free(rec)?;
+        }
+        Ok(())
+    }
+}
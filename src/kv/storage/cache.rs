@@ -1,69 +1,885 @@
-use std::borrow::{Borrow, BorrowMut};
-use std::cell::RefCell;
-use std::collections::LinkedList;
-use std::io::Result;
-use std::mem;
+use std::io::{Error, Result};
 use std::ptr;
-use std::rc::Rc;
+use std::ptr::NonNull;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::kv::storage::inner::{gpid_t, Node, page_s, PAGE_SIZE};
+use crate::kv::storage::dwb::{dwb_s, DWB_PAGE};
+use crate::kv::storage::inner::{corrupt, gpid_t, kvdb_assert, Node, pg_s};
 use crate::kv::storage::kvdb::kvdb_s;
+use crate::kv::storage::mmap::CFile;
+use crate::kv::storage::page::{page_intact, seal_page};
+use crate::kv::storage::wal::wal_s;
 
-// 1MB for test
-const MAX_CACHE_SIZE: usize = 1 << 20;
-const MAX_MAPPED_PG: usize = MAX_CACHE_SIZE / PAGE_SIZE;
+pub const DEFAULT_CACHE_SIZE: usize = 64 << 20;
+/* a cache holds at least this number of pages, no operation pins more of them at a time */
+pub const MIN_MAPPED_PG: usize = 16;
 const EVECT_NUM: i32 = 128;
 
-const PAGE_HASH_NUM: usize = MAX_MAPPED_PG;
-const PAGE_HASH_MASK: usize = MAX_MAPPED_PG - 1;
+const PAGE_HASH_NUM: usize = 256;
+/* the number of shards of a cache, unless it is too small to give each one MIN_MAPPED_PG pages */
+pub const SHARD_NUM: usize = 16;
 
 const PG_DIRTY: usize = 1 << 0;
 const PG_BUSY: usize = 1 << 1;
+/* a copy of the page is being written by the flusher */
+const PG_WRITING: usize = 1 << 2;
+/* the page is being read into the frame, out of the lock of the shard */
+const PG_LOADING: usize = 1 << 3;
 
-
+/*
+ * cache_s -- the buffer pool of the data pages. A mapped page is in the hash
+ *            chain of its gpid and in the busy list, which is kept in LRU
+ *            order, the most recently used page first. Frames without a page
+ *            are in the free list. A pinned page is never evicted, and a
+ *            dirty one is written back before its frame is reused. A page
+ *            is written only once the log is durable up to its lsn, with
+ *            its checksum stamped, which is checked when it is read. The
+ *            pages are written in batches through the double-write area
+ *            of the file, if there is one.
+ */
 pub struct cache_s {
+    /* the number of frames, in use or free */
     mapped_num: usize,
     busy_num: usize,
     free_num: usize,
-    pub hash: Box<[Node; PAGE_HASH_NUM as usize]>,
+    dirty_num: usize,
+    /* the number of pages being written by the flusher */
+    writing_num: usize,
+    /* the pages get() finds cached, and the ones it reads from the file */
+    hits: u64,
+    misses: u64,
+    /* the maximum number of frames, and the size of their pages */
+    cap: usize,
+    pg_size: usize,
+    /* above this ratio of dirty pages, the flusher writes out the oldest ones until half of it is left */
+    pub(crate) dirty_ratio: f64,
+    pub hash: Box<[Node]>,
     // free list head
-    free: Option<Node>,
+    free: Box<Node>,
     // busy list head
-    busy: Option<Node>,
+    busy: Box<Node>,
+    /* the frames are boxed, they do not move once they are linked */
+    #[allow(clippy::vec_box)]
+    pages: Vec<Box<pg_s>>,
+    /* the error the flusher met, it is reported by the next sync */
+    err: Option<Error>,
+    pub(crate) wal: Option<Arc<wal_s>>,
+    pub(crate) dwb: Option<Arc<dwb_s>>,
+}
+
+/* batch_t -- the copies of dirty pages being written, with their gpids */
+type batch_t = Vec<(gpid_t, Box<[u8]>)>;
+
+/* load_t -- what cache_s::get() did for a page */
+pub(crate) enum load_t {
+    /* the page is cached, or new, and pinned */
+    Ready(NonNull<pg_s>),
+    /* a frame is pinned for the page, the caller reads it and calls loaded() */
+    Read(NonNull<pg_s>),
+    /* the page is being read by someone else */
+    Wait,
+    /* no frame is left but dirty ones */
+    Full,
+}
+
+/* shard_s -- a part of the pool, cv is signaled when a batch of it is written, or a page is read */
+pub(crate) struct shard_s {
+    pub(crate) c: Mutex<cache_s>,
+    pub(crate) cv: Condvar,
+}
+
+/*
+ * pool_s -- the buffer pool, made of caches each under its own lock, which
+ *           a page goes to by its gpid. cv wakes up the flusher, and stop
+ *           tells it to exit.
+ */
+pub(crate) struct pool_s {
+    shards: Box<[shard_s]>,
+    stop: Mutex<bool>,
+    cv: Condvar,
+    pub(crate) wal: Option<Arc<wal_s>>,
+}
+
+/* flush_opts_s -- when the flusher writes dirty pages out */
+#[derive(Debug, Clone)]
+pub(crate) struct flush_opts_s {
+    /* how often the flusher looks at the cache */
+    pub(crate) interval: Duration,
+    /* a page dirty for longer than this is written out */
+    pub(crate) expire: Duration,
 }
 
+unsafe impl Send for cache_s {}
+
+impl pg_s {
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.flags as usize & PG_DIRTY != 0
+    }
+    pub(crate) fn is_pinned(&self) -> bool {
+        self.flags as usize & PG_BUSY != 0
+    }
+    fn is_writing(&self) -> bool {
+        self.flags as usize & PG_WRITING != 0
+    }
+    fn is_loading(&self) -> bool {
+        self.flags as usize & PG_LOADING != 0
+    }
+    fn pin(&mut self) {
+        self.pin += 1;
+        self.flags |= PG_BUSY as u32;
+    }
+}
 
 impl cache_s {
-    pub fn new() -> cache_s {
-        const node: Node = Node::new();
-        let c = cache_s {
+    pub fn with_capacity(cap: usize, pg_size: usize) -> cache_s {
+        let mut hash: Box<[Node]> = (0..PAGE_HASH_NUM.max(cap.next_power_of_two())).map(|_| Node::new()).collect();
+        hash.iter_mut().for_each(|h| h.init());
+        let mut c = cache_s {
             mapped_num: 0,
             busy_num: 0,
             free_num: 0,
-            hash: Box::new([node; PAGE_HASH_NUM]),
-            free: None,
-            busy: None,
+            dirty_num: 0,
+            writing_num: 0,
+            hits: 0,
+            misses: 0,
+            cap: cap.max(1),
+            pg_size,
+            dirty_ratio: 1.0,
+            hash,
+            free: Box::new(Node::new()),
+            busy: Box::new(Node::new()),
+            pages: Vec::new(),
+            err: None,
+            wal: None,
+            dwb: None,
         };
+        c.free.init();
+        c.busy.init();
         c
     }
-    // fn list_add(h: &mut RcNode, mut n: ListNode) {
-    //     let third = (&**h).borrow().next.clone();
-    //     n.next = third.clone();
-    //     n.prev = Some(Rc::clone(h));
-    //     let mut n = Rc::new(RefCell::new(n));
-    //     (&**h).borrow_mut().next = Some(Rc::clone(&n));
-    //     if let Some(ref node) = third {
-    //         (&**node).borrow_mut().prev = Some(n);
-    //     }
-
-    // golang:
-    // third := h.next;
-    // n.next = third;
-    // n.prev = h;
-    // h.next = n;
-    // if third.prev != nil {
-    //  third.prev = n;
-    // }
-    // }
+
+    fn bucket(&mut self, gpid: gpid_t) -> &mut Node {
+        let mask = self.hash.len() - 1;
+        &mut self.hash[gpid as usize & mask]
+    }
+
+    /* lookup() -- the frame which holds gpid */
+    fn lookup(&mut self, gpid: gpid_t) -> Option<NonNull<pg_s>> {
+        let head = self.bucket(gpid) as *const Node;
+        let mut n = unsafe { (*head).next() };
+        while !ptr::eq(n.as_ptr(), head) {
+            let pg = unsafe { pg_s::from_hash(n).as_mut() };
+            if pg.gpid == gpid {
+                return Some(NonNull::from(pg));
+            }
+            n = pg.hash.next();
+        }
+        None
+    }
+
+    /*
+     * get() -- pin the page gpid if it is cached. Otherwise a frame is
+     *          taken for it and pinned, and marked loading, for the caller
+     *          to read the page into it out of the lock, see shard_s::get().
+     *          A new page, just allocated, is not read but zeroed, it may
+     *          never have been written. The caller unpins it by unpin().
+     */
+    pub(crate) fn get(&mut self, gpid: gpid_t, new: bool) -> load_t {
+        if let Some(mut p) = self.lookup(gpid) {
+            let pg = unsafe { p.as_mut() };
+            if pg.is_loading() {
+                return load_t::Wait;
+            }
+            pg.pin();
+            self.hits += 1;
+            /* move it to the head of the LRU list */
+            unsafe {
+                pg.link.del();
+                self.busy.add(&mut pg.link);
+            }
+            return load_t::Ready(p);
+        }
+
+        let mut p = match self.alloc_frame() {
+            Some(p) => p,
+            None => return load_t::Full,
+        };
+        let pg = unsafe { p.as_mut() };
+        pg.gpid = gpid;
+        pg.flags = 0;
+        pg.pin();
+        unsafe {
+            self.bucket(gpid).add(&mut pg.hash);
+            self.busy.add(&mut pg.link);
+        }
+        self.busy_num += 1;
+        if new {
+            pg.buf.as_mut().unwrap().fill(0);
+            return load_t::Ready(p);
+        }
+        self.misses += 1;
+        pg.flags |= PG_LOADING as u32;
+        load_t::Read(p)
+    }
+
+    /* loaded() -- the page p has been read into its frame, which is given back if the read failed */
+    fn loaded(&mut self, mut p: NonNull<pg_s>, ok: bool) {
+        let pg = unsafe { p.as_mut() };
+        pg.flags &= !(PG_LOADING as u32);
+        if ok {
+            return;
+        }
+        unsafe {
+            pg.hash.del();
+            pg.link.del();
+            self.free.add(&mut pg.link);
+        }
+        pg.pin = 0;
+        pg.flags = 0;
+        self.busy_num -= 1;
+        self.free_num += 1;
+    }
+
+    /*
+     * unpin() -- a user is done with the page, and may have modified it in
+     *            the group of the log from rec_lsn. true is returned if
+     *            there are too many dirty pages, and the flusher should be
+     *            woken up.
+     */
+    pub(crate) fn unpin(&mut self, mut p: NonNull<pg_s>, dirty: bool, rec_lsn: u64) -> bool {
+        let pg = unsafe { p.as_mut() };
+        if dirty && !pg.is_dirty() {
+            pg.flags |= PG_DIRTY as u32;
+            pg.dirtied = Some(Instant::now());
+            self.dirty_num += 1;
+        }
+        /* a page being written by the flusher keeps the lsn of the changes in the write */
+        if dirty && pg.rec_lsn.is_none() {
+            pg.rec_lsn = Some(rec_lsn);
+        }
+        pg.pin -= 1;
+        if pg.pin == 0 {
+            pg.flags &= !(PG_BUSY as u32);
+        }
+        dirty && self.too_dirty()
+    }
+
+    fn too_dirty(&self) -> bool {
+        self.dirty_num as f64 > self.cap as f64 * self.dirty_ratio
+    }
+
+    /* alloc_frame() -- a frame out of any list, the clean pages are evicted if there is none left */
+    fn alloc_frame(&mut self) -> Option<NonNull<pg_s>> {
+        if self.free.is_empty() && self.mapped_num < self.cap {
+            let mut pg = Box::new(pg_s::new());
+            pg.buf = Some(vec![0u8; self.pg_size].into_boxed_slice());
+            let p = NonNull::from(&mut *pg);
+            self.pages.push(pg);
+            self.mapped_num += 1;
+            return Some(p);
+        }
+        if self.free.is_empty() {
+            self.evict();
+        }
+        if self.free.is_empty() {
+            return None;
+        }
+        let pg = unsafe { pg_s::from_link(self.free.next()).as_mut() };
+        unsafe { pg.link.del() };
+        self.free_num -= 1;
+        Some(NonNull::from(pg))
+    }
+
+    /*
+     * victims() -- up to EVECT_NUM unpinned pages from the tail of the LRU
+     *              list. A page being written stays, or it could be read
+     *              again from the file before the write is done.
+     */
+    fn victims(&mut self) -> Vec<NonNull<pg_s>> {
+        let head = &*self.busy as *const Node;
+        let mut victims = Vec::new();
+        let mut n = self.busy.prev();
+        while !ptr::eq(n.as_ptr(), head) && victims.len() < EVECT_NUM as usize {
+            let pg = unsafe { pg_s::from_link(n).as_mut() };
+            n = pg.link.prev();
+            if !pg.is_pinned() && !pg.is_writing() {
+                victims.push(NonNull::from(pg));
+            }
+        }
+        victims
+    }
+
+    /* evict() -- move the clean victims to the free list */
+    fn evict(&mut self) {
+        for mut p in self.victims() {
+            let pg = unsafe { p.as_mut() };
+            if pg.is_dirty() {
+                continue;
+            }
+            unsafe {
+                pg.hash.del();
+                pg.link.del();
+                self.free.add(&mut pg.link);
+            }
+            self.busy_num -= 1;
+            self.free_num += 1;
+        }
+    }
+
+    /*
+     * write_out() -- copy out the dirty victims in the order of their
+     *                positions in the file, to be written back once the
+     *                lock of the shard is released. They can be evicted
+     *                after that.
+     */
+    fn write_out(&mut self) -> (batch_t, u64) {
+        let mut victims = self.victims();
+        victims.sort_by_key(|p| unsafe { p.as_ref().gpid });
+        self.copy_out(&victims)
+    }
+
+    /*
+     * copy_out() -- copy the dirty pages of pgs into a batch to be written,
+     *               with the lsn the log should be durable up to before.
+     *               They are clean from now on, until they are modified
+     *               again. The caller keeps them from being modified
+     *               meanwhile.
+     */
+    fn copy_out(&mut self, pgs: &[NonNull<pg_s>]) -> (batch_t, u64) {
+        let mut batch = Vec::new();
+        let mut lsn = 0;
+        for mut p in pgs.iter().copied() {
+            let pg = unsafe { p.as_mut() };
+            if !pg.is_dirty() {
+                continue;
+            }
+            lsn = lsn.max(pg.lsn);
+            batch.push((pg.gpid, pg.buf.clone().unwrap()));
+            self.clean(pg);
+            pg.flags |= PG_WRITING as u32;
+            self.writing_num += 1;
+        }
+        (batch, lsn)
+    }
+
+    fn clean(&mut self, pg: &mut pg_s) {
+        pg.flags &= !(PG_DIRTY as u32);
+        pg.dirtied = None;
+        self.dirty_num -= 1;
+    }
+
+    /*
+     * flush() -- copy out up to EVECT_NUM dirty pages which old tells, and
+     *            return whether there are more of them, or some are being
+     *            modified, so they should be tried again. The writes in
+     *            flight are waited for by the caller, an old copy of a page
+     *            must not land after the page itself.
+     */
+    fn flush<F>(&mut self, old: &F) -> (batch_t, u64, bool)
+        where F: Fn(&pg_s) -> bool {
+        kvdb_assert(self.writing_num == 0);
+        let mut dirty: Vec<NonNull<pg_s>> = self.pages.iter_mut()
+            .filter(|pg| pg.is_dirty() && old(pg))
+            .map(|pg| NonNull::from(&mut **pg))
+            .collect();
+        dirty.sort_by_key(|p| unsafe { p.as_ref().gpid });
+        let more = dirty.len() > EVECT_NUM as usize;
+        dirty.truncate(EVECT_NUM as usize);
+        /* a pinned page may be under modification, it is only copied under the latch */
+        let latched: Vec<NonNull<pg_s>> = dirty.iter().copied()
+            .filter(|p| unsafe { p.as_ref() }.latch.try_lock_shared())
+            .collect();
+        let busy = latched.len() < dirty.len();
+        let (batch, lsn) = self.copy_out(&latched);
+        latched.iter().for_each(|p| unsafe { p.as_ref() }.latch.unlock_shared());
+        (batch, lsn, more || busy)
+    }
+
+    /*
+     * flush_batch() -- copy out the unpinned pages which have been dirty for
+     *                  too long, and the oldest ones if there are too many
+     *                  dirty pages.
+     */
+    fn flush_batch(&mut self, opts: &flush_opts_s) -> (batch_t, u64) {
+        let now = Instant::now();
+        let mut dirty: Vec<NonNull<pg_s>> = self.pages.iter_mut()
+            .filter(|pg| pg.is_dirty() && !pg.is_pinned() && !pg.is_writing())
+            .map(|pg| NonNull::from(&mut **pg))
+            .collect();
+        dirty.sort_by_key(|p| unsafe { p.as_ref().dirtied });
+        let low = (self.cap as f64 * self.dirty_ratio / 2.0) as usize;
+        let over = if self.too_dirty() { self.dirty_num - low } else { 0 };
+        let n = dirty.iter().enumerate()
+            .take_while(|(i, p)| *i < over || now.duration_since(unsafe { p.as_ref() }.dirtied.unwrap()) >= opts.expire)
+            .count();
+        self.copy_out(&dirty[..n])
+    }
+
+    /* end_batch() -- the pages of a batch are written, or they are dirty again if it failed */
+    fn end_batch(&mut self, batch: &batch_t, ok: bool) {
+        for (gpid, _) in batch {
+            let mut p = self.lookup(*gpid).unwrap();
+            let pg = unsafe { p.as_mut() };
+            pg.flags &= !(PG_WRITING as u32);
+            if !ok && !pg.is_dirty() {
+                pg.flags |= PG_DIRTY as u32;
+                pg.dirtied = Some(Instant::now());
+                self.dirty_num += 1;
+            }
+            if !pg.is_dirty() {
+                pg.rec_lsn = None;
+            }
+        }
+        self.writing_num -= batch.len();
+    }
+
+    /*
+     * discard() -- drop the cached pages from gpid from on, they are free
+     *              and cut off the file, so their changes are never to be
+     *              written. A pinned one is only made clean. false is
+     *              returned if some of them are being written, which the
+     *              caller waits for first, see pool_s::discard().
+     */
+    fn discard(&mut self, from: gpid_t) -> bool {
+        let mut cut: Vec<NonNull<pg_s>> = self.pages.iter_mut()
+            .filter(|pg| pg.gpid >= from)
+            .map(|pg| NonNull::from(&mut **pg))
+            .collect();
+        /* the frames in the free list keep the gpid they had */
+        cut.retain(|p| self.lookup(unsafe { p.as_ref() }.gpid) == Some(*p));
+        if cut.iter().any(|p| unsafe { p.as_ref() }.is_writing()) {
+            return false;
+        }
+        for mut p in cut {
+            let pg = unsafe { p.as_mut() };
+            if pg.is_dirty() {
+                self.clean(pg);
+            }
+            pg.rec_lsn = None;
+            if pg.is_pinned() {
+                continue;
+            }
+            unsafe {
+                pg.hash.del();
+                pg.link.del();
+                self.free.add(&mut pg.link);
+            }
+            self.busy_num -= 1;
+            self.free_num += 1;
+        }
+        true
+    }
+
+    /* the number of cached pages, and how many of them are pinned or dirty */
+    pub(crate) fn counts(&self) -> (usize, usize, usize) {
+        let pinned = self.pages.iter().filter(|pg| pg.is_pinned()).count();
+        (self.busy_num, pinned, self.dirty_num)
+    }
+    /* the pages found cached and the ones read so far */
+    pub(crate) fn hits(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
 }
 
+impl shard_s {
+    /*
+     * get() -- pin the page gpid, as cache_s::get() does, and read it from
+     *          the file if it is not cached, with the lock of the shard
+     *          released. The others who want it meanwhile wait for the
+     *          read. A page whose checksum does not match is a corrupt_s
+     *          error. When the frames left to evict are all dirty, a batch
+     *          of them is written back with the lock released too, and it
+     *          is tried again.
+     */
+    pub(crate) fn get(&self, file: &CFile, gpid: gpid_t, new: bool) -> Result<NonNull<pg_s>> {
+        loop {
+            let mut c = self.c.lock().unwrap();
+            match c.get(gpid, new) {
+                load_t::Ready(p) => return Ok(p),
+                load_t::Read(p) => {
+                    let pos = kvdb_s::get_page_pos(gpid, c.pg_size) as u64;
+                    drop(c);
+                    return self.read(file, p, pos);
+                }
+                load_t::Wait => {
+                    drop(self.cv.wait(c).unwrap());
+                    continue;
+                }
+                load_t::Full => {}
+            }
+            let (batch, lsn) = c.write_out();
+            if batch.is_empty() {
+                if c.writing_num == 0 {
+                    return Err(Error::other(format!("all the {} pages in the cache are pinned", c.mapped_num)));
+                }
+                /* the victims are being written by someone else */
+                drop(self.cv.wait(c).unwrap());
+                continue;
+            }
+            self.write_batch(file, c, batch, lsn)?;
+        }
+    }
+
+    /* read() -- read the page p at pos into its frame, which is pinned and loading, out of the lock */
+    fn read(&self, file: &CFile, mut p: NonNull<pg_s>, pos: u64) -> Result<NonNull<pg_s>> {
+        let pg = unsafe { p.as_mut() };
+        let gpid = pg.gpid;
+        let buf = pg.buf.as_mut().unwrap();
+        let ret = file.read_at(buf, pos)
+            .and_then(|_| if page_intact(buf) { Ok(()) } else { Err(corrupt(gpid)) });
+        self.c.lock().unwrap().loaded(p, ret.is_ok());
+        self.cv.notify_all();
+        ret.map(|_| p)
+    }
+
+    /*
+     * write_batch() -- stamp the copies of a batch and write them once the
+     *                  log is durable up to lsn, out of the lock of the
+     *                  shard, which c is the guard of.
+     */
+    fn write_batch(&self, file: &CFile, c: MutexGuard<cache_s>, mut batch: batch_t, lsn: u64) -> Result<()> {
+        let (wal, dwb, pg_size) = (c.wal.clone(), c.dwb.clone(), c.pg_size);
+        drop(c);
+        batch.iter_mut().for_each(|(_, buf)| seal_page(buf));
+        let ret = wal.as_ref().map_or(Ok(()), |wal| wal.flush(lsn)).and_then(|_| {
+            let w: Vec<(gpid_t, &[u8])> = batch.iter().map(|(gpid, buf)| (*gpid, &buf[..])).collect();
+            write_pages(file, dwb.as_deref(), pg_size, &w)
+        });
+        self.c.lock().unwrap().end_batch(&batch, ret.is_ok());
+        self.cv.notify_all();
+        ret
+    }
+
+    /* sync() -- write back the dirty pages which old tells, after the writes in flight are done */
+    fn sync<F>(&self, file: &CFile, old: &F) -> Result<()>
+        where F: Fn(&pg_s) -> bool {
+        loop {
+            let mut c = self.c.lock().unwrap();
+            while c.writing_num > 0 {
+                c = self.cv.wait(c).unwrap();
+            }
+            /* the error the flusher met */
+            if let Some(e) = c.err.take() {
+                return Err(e);
+            }
+            let (batch, lsn, again) = c.flush(old);
+            if !batch.is_empty() {
+                self.write_batch(file, c, batch, lsn)?;
+            } else {
+                drop(c);
+            }
+            if !again {
+                return Ok(());
+            }
+            thread::yield_now();
+        }
+    }
+}
+
+impl pool_s {
+    /*
+     * new() -- a pool of cap pages split into up to shard_num shards, whose
+     *          pages are logged in wal, and written through dwb.
+     */
+    pub(crate) fn new(cap: usize, pg_size: usize, shard_num: usize, dirty_ratio: f64,
+                      wal: Option<Arc<wal_s>>, dwb: Option<Arc<dwb_s>>) -> pool_s {
+        let n = shard_num.min(cap / MIN_MAPPED_PG).max(1);
+        let shards = (0..n).map(|i| {
+            let mut c = cache_s::with_capacity(cap / n + if i < cap % n { 1 } else { 0 }, pg_size);
+            c.dirty_ratio = dirty_ratio;
+            c.wal = wal.clone();
+            c.dwb = dwb.clone();
+            shard_s { c: Mutex::new(c), cv: Condvar::new() }
+        }).collect();
+        pool_s { shards, stop: Mutex::new(false), cv: Condvar::new(), wal }
+    }
+
+    /* shard() -- the shard which caches the page gpid */
+    pub(crate) fn shard(&self, gpid: gpid_t) -> &shard_s {
+        &self.shards[(gpid % self.shards.len() as gpid_t) as usize]
+    }
+
+    /* wake() -- the flusher should look at the pool now, rather than after its interval */
+    pub(crate) fn wake(&self) {
+        self.cv.notify_all();
+    }
+
+    /* sync() -- write back all the pages dirtied before the call, shard by shard */
+    pub(crate) fn sync(&self, file: &CFile) -> Result<()> {
+        let now = Instant::now();
+        for sh in self.shards.iter() {
+            sh.sync(file, &|pg: &pg_s| pg.dirtied.unwrap() <= now)?;
+        }
+        Ok(())
+    }
+
+    /* discard() -- drop the cached pages from gpid from on, once the writes of them in flight are done */
+    pub(crate) fn discard(&self, from: gpid_t) {
+        for sh in self.shards.iter() {
+            let mut c = sh.c.lock().unwrap();
+            while !c.discard(from) {
+                c = sh.cv.wait(c).unwrap();
+            }
+        }
+    }
+
+    /* sync_older() -- write back the pages with changes logged before lsn */
+    pub(crate) fn sync_older(&self, file: &CFile, lsn: u64) -> Result<()> {
+        for sh in self.shards.iter() {
+            sh.sync(file, &|pg: &pg_s| pg.rec_lsn.is_some_and(|l| l < lsn))?;
+        }
+        Ok(())
+    }
+
+    /*
+     * dirty_table() -- the pages whose changes may not be in the file, with
+     *                  the lsn of the first of them, those being written by
+     *                  the flusher included.
+     */
+    pub(crate) fn dirty_table(&self) -> Vec<(gpid_t, u64)> {
+        let mut dpt = Vec::new();
+        for sh in self.shards.iter() {
+            let c = sh.c.lock().unwrap();
+            dpt.extend(c.pages.iter().filter_map(|pg| pg.rec_lsn.map(|l| (pg.gpid, l))));
+        }
+        dpt
+    }
+
+    /* the number of cached pages, and how many of them are pinned or dirty, in all the shards */
+    pub(crate) fn counts(&self) -> (usize, usize, usize) {
+        self.shards.iter().map(|sh| sh.c.lock().unwrap().counts())
+            .fold((0, 0, 0), |a, b| (a.0 + b.0, a.1 + b.1, a.2 + b.2))
+    }
+
+    /* the pages found cached and the ones read so far, in all the shards */
+    pub(crate) fn hits(&self) -> (u64, u64) {
+        self.shards.iter().map(|sh| sh.c.lock().unwrap().hits())
+            .fold((0, 0), |a, b| (a.0 + b.0, a.1 + b.1))
+    }
+
+    /*
+     * start_flusher() -- a thread which trickles dirty pages out of the pool
+     *                    by their age, and by the ratio of dirty pages in
+     *                    each shard. It runs until stop_flusher().
+     */
+    pub(crate) fn start_flusher(pool: Arc<pool_s>, file: CFile, opts: flush_opts_s) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut stop = pool.stop.lock().unwrap();
+            loop {
+                stop = pool.cv.wait_timeout(stop, opts.interval).unwrap().0;
+                if *stop {
+                    break;
+                }
+                drop(stop);
+                for sh in pool.shards.iter() {
+                    let mut c = sh.c.lock().unwrap();
+                    let (batch, lsn) = c.flush_batch(&opts);
+                    if batch.is_empty() {
+                        continue;
+                    }
+                    /* it is reported by the next sync */
+                    if let Err(e) = sh.write_batch(&file, c, batch, lsn) {
+                        sh.c.lock().unwrap().err = Some(e);
+                    }
+                }
+                stop = pool.stop.lock().unwrap();
+            }
+        })
+    }
+
+    pub(crate) fn stop_flusher(&self, flusher: thread::JoinHandle<()>) {
+        *self.stop.lock().unwrap() = true;
+        self.cv.notify_all();
+        let _ = flusher.join();
+    }
+}
+
+/* write_pages() -- write the pages to their places in the file, through the double-write area if there is one */
+fn write_pages(file: &CFile, dwb: Option<&dwb_s>, pg_size: usize, pages: &[(gpid_t, &[u8])]) -> Result<()> {
+    match dwb {
+        Some(dwb) => {
+            let w: Vec<(u32, usize, &[u8])> = pages.iter()
+                .map(|(gpid, b)| (DWB_PAGE, kvdb_s::get_page_pos(*gpid, pg_size), *b))
+                .collect();
+            dwb.write(file, &w)
+        }
+        None => pages.iter().try_for_each(|(gpid, b)| file.write_at(b, kvdb_s::get_page_pos(*gpid, pg_size) as u64)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::kv::storage::cache::{EVECT_NUM, flush_opts_s, MIN_MAPPED_PG, pool_s, SHARD_NUM};
+    use crate::kv::storage::inner::{corrupt_s, PAGE_SIZE};
+    use crate::kv::storage::kvdb::kvdb_s;
+    use crate::kv::storage::mmap::CFile;
+    use crate::kv::storage::page::seal_page;
+
+    #[test]
+    fn test_pin_evict() {
+        let path = std::env::temp_dir().join(format!("lycee-cache-{}.db", std::process::id()));
+        let file = CFile::open(&path).unwrap();
+        let n = 1000;
+        file.set_len((kvdb_s::get_page_pos(n, PAGE_SIZE) + PAGE_SIZE) as u64).unwrap();
+        let pool = pool_s::new(200, PAGE_SIZE, 1, 1.0, None, None);
+        let sh = pool.shard(0);
+        let unpin = |p, dirty| sh.c.lock().unwrap().unpin(p, dirty, 0);
+
+        /* every page is written back before it is evicted */
+        for gpid in 0..n {
+            let mut p = sh.get(&file, gpid, true).unwrap();
+            let pg = unsafe { p.as_mut() };
+            pg.buf.as_mut().unwrap()[..8].copy_from_slice(&gpid.to_le_bytes());
+            unpin(p, true);
+        }
+        {
+            let c = sh.c.lock().unwrap();
+            assert_eq!((200, 0), (c.mapped_num, c.writing_num));
+            assert!(c.busy_num > 200 - EVECT_NUM as usize);
+        }
+        for gpid in 0..n {
+            let p = sh.get(&file, gpid, false).unwrap();
+            assert_eq!(&gpid.to_le_bytes(), &unsafe { p.as_ref() }.buf.as_ref().unwrap()[..8]);
+            unpin(p, false);
+        }
+
+        /* pinned pages stay, until there is nothing else to evict */
+        let pinned: Vec<_> = (0..199).map(|gpid| sh.get(&file, gpid, false).unwrap()).collect();
+        for gpid in 500..600 {
+            let p = sh.get(&file, gpid, false).unwrap();
+            unpin(p, false);
+        }
+        assert_eq!((200, 199, 0), pool.counts());
+        let p = sh.get(&file, 0, false).unwrap();
+        assert_eq!(pinned[0], p);
+        unpin(p, false);
+        let last = sh.get(&file, 999, false).unwrap();
+        assert!(sh.get(&file, 998, false).is_err());
+        for p in pinned.into_iter().chain(Some(last)) {
+            unpin(p, false);
+        }
+        assert!(sh.get(&file, 998, false).is_ok());
+        drop(file);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_flusher() {
+        let path = std::env::temp_dir().join(format!("lycee-flusher-{}.db", std::process::id()));
+        let file = CFile::open(&path).unwrap();
+        file.set_len((kvdb_s::get_page_pos(100, PAGE_SIZE)) as u64).unwrap();
+        let pool = Arc::new(pool_s::new(100, PAGE_SIZE, 4, 0.5, None, None));
+        let opts = flush_opts_s { interval: Duration::from_millis(5), expire: Duration::from_millis(50) };
+        let flusher = pool_s::start_flusher(pool.clone(), file.try_clone().unwrap(), opts);
+
+        let dirty = |from: u64, to: u64| {
+            /*
+             * the pages are taken first, and the flusher is woken up at the
+             * end, or it may run in between in a debug build, and leave the
+             * shards it is done with a little less than too dirty
+             */
+            for gpid in from..to {
+                let p = pool.shard(gpid).get(&file, gpid, true).unwrap();
+                pool.shard(gpid).c.lock().unwrap().unpin(p, false, 0);
+            }
+            let mut wake = false;
+            for gpid in from..to {
+                let mut p = pool.shard(gpid).get(&file, gpid, false).unwrap();
+                unsafe { p.as_mut() }.buf.as_mut().unwrap()[0] = 1;
+                wake |= pool.shard(gpid).c.lock().unwrap().unpin(p, true, 0);
+            }
+            if wake {
+                pool.wake();
+            }
+        };
+        /* old pages are written out by age */
+        dirty(0, 10);
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(0, pool.counts().2);
+        /* too many dirty pages are written out down to half of the ratio, at once */
+        dirty(10, 70);
+        thread::sleep(Duration::from_millis(30));
+        assert!(pool.counts().2 <= 25);
+        pool.sync(&file).unwrap();
+        assert_eq!(0, pool.counts().2);
+        pool.stop_flusher(flusher);
+
+        let mut b = [0u8; 1];
+        for gpid in 0..70 {
+            file.read_at(&mut b, kvdb_s::get_page_pos(gpid, PAGE_SIZE) as u64).unwrap();
+            assert_eq!(1, b[0]);
+        }
+        drop(file);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load() {
+        let path = std::env::temp_dir().join(format!("lycee-load-{}.db", std::process::id()));
+        let file = CFile::open(&path).unwrap();
+        for gpid in 0..50u64 {
+            let mut b = vec![gpid as u8; PAGE_SIZE];
+            seal_page(&mut b);
+            file.write_at(&b, kvdb_s::get_page_pos(gpid, PAGE_SIZE) as u64).unwrap();
+        }
+        file.write_at(&vec![1u8; PAGE_SIZE], kvdb_s::get_page_pos(50, PAGE_SIZE) as u64).unwrap();
+        let pool = Arc::new(pool_s::new(100, PAGE_SIZE, 1, 1.0, None, None));
+
+        /* a page missed by several threads at once is read once, out of the lock */
+        let threads: Vec<_> = (0..8).map(|_| {
+            let (pool, file) = (pool.clone(), file.try_clone().unwrap());
+            thread::spawn(move || {
+                for gpid in 0..50u64 {
+                    let p = pool.shard(gpid).get(&file, gpid, false).unwrap();
+                    assert_eq!(gpid as u8, unsafe { p.as_ref() }.buf.as_ref().unwrap()[100]);
+                    pool.shard(gpid).c.lock().unwrap().unpin(p, false, 0);
+                }
+            })
+        }).collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+        assert_eq!((8 * 50 - 50, 50), pool.shard(0).c.lock().unwrap().hits());
+
+        /* the frame of a page which fails its checksum is given back */
+        let e = pool.shard(50).get(&file, 50, false).err().unwrap();
+        assert_eq!(Some(50), corrupt_s::of(&e).map(|c| c.gpid));
+        assert_eq!((50, 0, 0), pool.counts());
+        drop(file);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_shards() {
+        let path = std::env::temp_dir().join(format!("lycee-shards-{}.db", std::process::id()));
+        let file = CFile::open(&path).unwrap();
+        file.set_len((kvdb_s::get_page_pos(1000, PAGE_SIZE)) as u64).unwrap();
+        /* every shard has at least MIN_MAPPED_PG pages */
+        let pool = pool_s::new(100, PAGE_SIZE, SHARD_NUM, 1.0, None, None);
+        assert_eq!(100 / MIN_MAPPED_PG, pool.shards.len());
+        assert_eq!(100, pool.shards.iter().map(|sh| sh.c.lock().unwrap().cap).sum::<usize>());
+        assert_eq!(1, pool_s::new(MIN_MAPPED_PG, PAGE_SIZE, SHARD_NUM, 1.0, None, None).shards.len());
+
+        /* a shard whose pages are all pinned does not stop the other ones */
+        let n = pool.shards.len() as u64;
+        let cap = pool.shard(0).c.lock().unwrap().cap;
+        let pinned: Vec<_> = (0..cap as u64).map(|i| pool.shard(i * n).get(&file, i * n, true).unwrap()).collect();
+        assert!(pool.shard(cap as u64 * n).get(&file, cap as u64 * n, true).is_err());
+        for gpid in (0..500).filter(|gpid| gpid % n != 0) {
+            let p = pool.shard(gpid).get(&file, gpid, true).unwrap();
+            pool.shard(gpid).c.lock().unwrap().unpin(p, false, 0);
+        }
+        assert_eq!(cap, pool.counts().1);
+        for (i, p) in (0..).zip(pinned) {
+            pool.shard(i * n).c.lock().unwrap().unpin(p, false, 0);
+        }
+        assert_eq!(0, pool.counts().1);
+        drop(file);
+        fs::remove_file(&path).unwrap();
+    }
+}
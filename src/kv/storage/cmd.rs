@@ -1,18 +1,23 @@
-use std::{mem, time};
+use std::time;
 use std::io::{Error, ErrorKind, Result};
 
 use crate::kv::storage::crc64::{as_ne_bytes, kv_crc64};
 use crate::kv::storage::kvdb::kvdb_s;
 
 fn usage() {
-    println!("{}{}{}{}{}{}{}{}", "    kv help                   -- this message \n",
+    println!(concat!("    kv help                   -- this message \n",
              "    kv get <key>              -- get a key\n",
              "    kv put <key> <val>        -- set key\n",
              "    kv del <key>              -- delete a key\n",
              "    kv list                   -- list all key in the db\n",
              "    kv ins <start_key> <num>  -- insert records in batch mode\n",
+             "    kv load <start_key> <num> -- build an empty db from sorted records\n",
              "    kv clr                    -- remove all records in the database\n",
-             "    kv verify                 -- get all records and verify them\n");
+             "    kv verify                 -- an offline check of the tree and the allocator, print a report;\n",
+             "                                 the reads and writes wait for the whole walk, use stats in service\n",
+             "    kv vacuum                 -- move the pages to the front, and give the free space back\n",
+             "    kv stats                  -- print the pages used and free, the fill of the leaves and the cache;\n",
+             "                                 the numbers are approximate while the db is written\n"));
 }
 
 struct cmd_s {
@@ -20,56 +25,69 @@ struct cmd_s {
     func: fn(db: &mut kvdb_s, args: Vec<String>) -> Result<()>,
 }
 
-const cmds: [cmd_s; 8] = [
+const cmds: [cmd_s; 11] = [
     cmd_s { cmd: "get", func: fn_get },
     cmd_s { cmd: "put", func: fn_put },
     cmd_s { cmd: "del", func: fn_del },
     cmd_s { cmd: "list", func: fn_list },
     cmd_s { cmd: "dump", func: fn_dump },
     cmd_s { cmd: "ins", func: fn_ins },
+    cmd_s { cmd: "load", func: fn_load },
     cmd_s { cmd: "clr", func: fn_clr },
     cmd_s { cmd: "verify", func: fn_verify },
+    cmd_s { cmd: "vacuum", func: fn_vacuum },
+    cmd_s { cmd: "stats", func: fn_stats },
 ];
 
 fn args_err(error: &str) -> Error {
     Error::new(ErrorKind::InvalidInput, error)
 }
 
-fn assert_args(args: &Vec<String>, count: usize) -> Result<()> {
+fn assert_args(args: &[String], count: usize) -> Result<()> {
     if args.len() != count {
         return Result::Err(args_err(format!("number of args must be {}", count).as_str()))
     }
     Ok(())
 }
 
-fn parse_u64(args: &Vec<String>, index: usize) -> Result<u64> {
-    args[index].parse().map_err(|e|
+fn parse_u64(args: &[String], index: usize) -> Result<u64> {
+    args[index].parse().map_err(|_|
         args_err(format!("type of the {} args must be u64", index).as_str()))
 }
 
+/* numbers are stored in big endian, so that the order of the keys is the numeric one */
+fn fmt_bytes(b: &[u8]) -> String {
+    if b.len() == 8 {
+        let mut w = [0u8; 8];
+        w.copy_from_slice(b);
+        return u64::from_be_bytes(w).to_string();
+    }
+    String::from_utf8_lossy(b).into_owned()
+}
+
 fn fn_get(db: &mut kvdb_s, args: Vec<String>) -> Result<()> {
     assert_args(&args, 3)?;
     let k: u64 = parse_u64(&args, 2)?;
-    match db.get(k) {
+    match db.get(&k.to_be_bytes()) {
         Ok(v) =>
-            println!("found, key = {}, value = {}", k, v),
+            println!("found, key = {}, value = {}", k, fmt_bytes(&v)),
         Err(e) =>
             eprintln!("record not found: {}", e),
     }
-    return Ok(());
+    Ok(())
 }
 
 fn fn_put(db: &mut kvdb_s, args: Vec<String>) -> Result<()> {
     assert_args(&args, 4)?;
     let k: u64 = parse_u64(&args, 2)?;
     let v: u64 = parse_u64(&args, 3)?;
-    db.put(k, v)
+    db.put(&k.to_be_bytes(), &v.to_be_bytes())
 }
 
 fn fn_del(db: &mut kvdb_s, args: Vec<String>) -> Result<()> {
     assert_args(&args, 3)?;
     let k: u64 = parse_u64(&args, 2)?;
-    match db.del(k) {
+    match db.del(&k.to_be_bytes()) {
         Ok(_) =>
             println!("deletion success"),
         Err(e) =>
@@ -80,11 +98,14 @@ fn fn_del(db: &mut kvdb_s, args: Vec<String>) -> Result<()> {
 
 fn fn_list(db: &mut kvdb_s, args: Vec<String>) -> Result<()> {
     assert_args(&args, 2)?;
-    let k: u64 = parse_u64(&args, 2)?;
-    for (k, v) in db.iter(0, u64::MAX)? {
-        println!("k = {:>5}, v = {:>21}", k, v);
+    let mut c = db.iter(&[], None)?;
+    for (k, v) in &mut c {
+        println!("k = {:>5}, v = {:>21}", fmt_bytes(&k), fmt_bytes(&v));
+    }
+    match c.err.take() {
+        Some(e) => Err(e),
+        None => Ok(()),
     }
-    Ok(())
 }
 
 fn fn_dump(db: &mut kvdb_s, args: Vec<String>) -> Result<()> {
@@ -99,13 +120,13 @@ fn fn_ins(db: &mut kvdb_s, args: Vec<String>) -> Result<()> {
     let n = parse_u64(&args, 3)?;
     let mut last_i = 0_u64;
     let t0 = time::Instant::now();
-    let mut last = t0.clone();
+    let mut last = t0;
     let mut seq;
     for i in 0..n {
         seq = start_k + i;
         let k = kv_crc64(as_ne_bytes(&seq));
         let v = kv_crc64(as_ne_bytes(&k));
-        db.put(k, v)?;
+        db.put(&k.to_be_bytes(), &v.to_be_bytes())?;
         if (i % 100) == 0 {
             let now = time::Instant::now();
             if (now - last).as_secs() >= 1 {
@@ -123,33 +144,58 @@ fn fn_ins(db: &mut kvdb_s, args: Vec<String>) -> Result<()> {
     Ok(())
 }
 
-fn fn_clr(db: &mut kvdb_s, args: Vec<String>) -> Result<()> {
+fn fn_load(db: &mut kvdb_s, args: Vec<String>) -> Result<()> {
+    assert_args(&args, 4)?;
+    let start_k = parse_u64(&args, 2)?;
+    let n = parse_u64(&args, 3)?;
+    let t0 = time::Instant::now();
+    let items = (start_k..start_k + n).map(|k| (k.to_be_bytes().to_vec(), kv_crc64(as_ne_bytes(&k)).to_be_bytes().to_vec()));
+    let n = db.bulk_load(items, 0.9)?;
+    println!("total: {} in {} ms", n, (time::Instant::now() - t0).as_millis());
+    Ok(())
+}
+
+fn fn_clr(_db: &mut kvdb_s, _args: Vec<String>) -> Result<()> {
     Ok(())
 }
 
 fn fn_verify(db: &mut kvdb_s, args: Vec<String>) -> Result<()> {
+    assert_args(&args, 2)?;
+    let v = db.verify()?;
+    print!("{}", v);
+    if !v.is_ok() {
+        return Err(Error::new(ErrorKind::InvalidData, format!("{} problems are found", v.problems.len())));
+    }
     Ok(())
 }
 
+fn fn_vacuum(db: &mut kvdb_s, args: Vec<String>) -> Result<()> {
+    assert_args(&args, 2)?;
+    print!("{}", db.vacuum()?);
+    Ok(())
+}
 
-fn exec(args: Vec<String>) -> Result<()> {
+fn fn_stats(db: &mut kvdb_s, args: Vec<String>) -> Result<()> {
+    assert_args(&args, 2)?;
+    print!("{}", db.stats()?);
+    Ok(())
+}
+
+
+pub fn exec(args: Vec<String>) -> Result<()> {
 // struct cmd_s *c;
     if args.len() < 2 {
         usage();
         return Ok(());
     }
     let mut db = kvdb_s::open("aaa.db")?;
-    let mut found = false;
+    /* the error of a command, as the problems verify finds, goes to the caller */
     for c in &cmds {
         if c.cmd == args[1] {
-            (c.func)(&mut db, args);
-            found = true;
-            break;
+            return (c.func)(&mut db, args);
         }
     }
-    if !found {
-        usage();
-    }
-    return Ok(());
+    usage();
+    Ok(())
 }
 
@@ -0,0 +1,328 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/*
+ * A file system in memory for the crash tests. The files under the
+ * directory of a mounted crashfs_s live in it instead of on the disk,
+ * CFile and the file operations of mmap.rs go to it for them.
+ *
+ * Every write, set_len, create, rename and remove of the files, and every
+ * sync, is an I/O point, they are counted from the mount. A crash can be
+ * set at any of them, the I/O point and everything after it fail then,
+ * until the file system is rebooted. What is not synced at the crash is
+ * lost in any way a disk may lose it: each write since the last sync of
+ * its file is kept, dropped, or torn at a sector, by a seeded random, no
+ * matter the order they came in. The names of the files are durable once
+ * the directory is synced, a new file also keeps its name once it is
+ * synced itself, as on ext4.
+ */
+const SECTOR_SIZE: usize = 512;
+
+/* the mounted ones, by the directory they take */
+static MOUNTS: Mutex<Vec<Arc<crashfs_s>>> = Mutex::new(Vec::new());
+
+pub(crate) struct crashfs_s {
+    dir: PathBuf,
+    s: Mutex<fs_s>,
+}
+
+struct fs_s {
+    files: Vec<file_s>,
+    names: BTreeMap<PathBuf, usize>,
+    durable_names: BTreeMap<PathBuf, usize>,
+    /* the I/O points so far, and the one to crash at */
+    ops: u64,
+    crash_at: u64,
+    crashed: bool,
+    rand: u64,
+}
+
+#[derive(Default)]
+struct file_s {
+    data: Vec<u8>,
+    durable: Vec<u8>,
+    unsynced: Vec<io_t>,
+}
+
+enum io_t {
+    Write(usize, Vec<u8>),
+    SetLen(usize),
+}
+
+/* fd_s -- an open file of a crashfs_s, the clones of it share the file */
+#[derive(Clone)]
+pub(crate) struct fd_s {
+    fs: Arc<crashfs_s>,
+    id: usize,
+}
+
+impl crashfs_s {
+    /* mount() -- a new empty file system for the files under dir, seed drives what a crash loses */
+    pub(crate) fn mount<P: AsRef<Path>>(dir: P, seed: u64) -> Arc<crashfs_s> {
+        let fs = Arc::new(crashfs_s {
+            dir: dir.as_ref().to_path_buf(),
+            s: Mutex::new(fs_s {
+                files: Vec::new(),
+                names: BTreeMap::new(),
+                durable_names: BTreeMap::new(),
+                ops: 0,
+                crash_at: u64::MAX,
+                crashed: false,
+                rand: seed | 1,
+            }),
+        });
+        let mut m = MOUNTS.lock().unwrap();
+        m.retain(|f| f.dir != fs.dir);
+        m.push(fs.clone());
+        fs
+    }
+    pub(crate) fn unmount(&self) {
+        MOUNTS.lock().unwrap().retain(|f| f.dir != self.dir);
+    }
+    /* lookup() -- the file system the path is in, if it is in a mounted one */
+    pub(crate) fn lookup(path: &Path) -> Option<Arc<crashfs_s>> {
+        MOUNTS.lock().unwrap().iter().find(|f| path.starts_with(&f.dir)).cloned()
+    }
+    /* ops() -- the I/O points since the mount or the last reboot */
+    pub(crate) fn ops(&self) -> u64 {
+        self.s.lock().unwrap().ops
+    }
+    /* crash_at() -- crash at the n-th I/O point from now on, 1 is the next one */
+    pub(crate) fn crash_at(&self, n: u64) {
+        let mut s = self.s.lock().unwrap();
+        s.crash_at = s.ops + n;
+    }
+    pub(crate) fn crashed(&self) -> bool {
+        self.s.lock().unwrap().crashed
+    }
+    /* reboot() -- lose what a crash would lose now, the file system works again after it */
+    pub(crate) fn reboot(&self) {
+        let mut s = self.s.lock().unwrap();
+        let s = &mut *s;
+        for f in s.files.iter_mut() {
+            let mut b = std::mem::take(&mut f.durable);
+            for io in f.unsynced.drain(..) {
+                let r = next_rand(&mut s.rand);
+                match io {
+                    io_t::Write(pos, w) if !w.is_empty() => {
+                        let len = match r % 3 {
+                            0 => 0,
+                            1 => w.len(),
+                            _ => (r as usize >> 8) % w.len().div_ceil(SECTOR_SIZE) * SECTOR_SIZE,
+                        };
+                        write_into(&mut b, pos, &w[..len]);
+                    }
+                    io_t::Write(..) => {}
+                    io_t::SetLen(len) if r.is_multiple_of(2) => b.resize(len, 0),
+                    io_t::SetLen(_) => {}
+                }
+            }
+            f.data = b.clone();
+            f.durable = b;
+        }
+        s.names = s.durable_names.clone();
+        s.ops = 0;
+        s.crash_at = u64::MAX;
+        s.crashed = false;
+    }
+
+    /* open() -- the file at path, it is created if there is none */
+    pub(crate) fn open(self: &Arc<crashfs_s>, path: &Path) -> Result<fd_s> {
+        let mut s = self.s.lock().unwrap();
+        s.check()?;
+        let id = match s.names.get(path) {
+            Some(id) => *id,
+            None => {
+                s.step()?;
+                s.files.push(file_s::default());
+                let id = s.files.len() - 1;
+                s.names.insert(path.to_path_buf(), id);
+                id
+            }
+        };
+        Ok(fd_s { fs: self.clone(), id })
+    }
+    pub(crate) fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut s = self.s.lock().unwrap();
+        s.check()?;
+        let id = *s.names.get(from).ok_or_else(|| not_found(from))?;
+        s.step()?;
+        s.names.remove(from);
+        s.names.insert(to.to_path_buf(), id);
+        Ok(())
+    }
+    pub(crate) fn remove(&self, path: &Path) -> Result<()> {
+        let mut s = self.s.lock().unwrap();
+        s.check()?;
+        if !s.names.contains_key(path) {
+            return Err(not_found(path));
+        }
+        s.step()?;
+        s.names.remove(path);
+        Ok(())
+    }
+    pub(crate) fn sync_dir(&self, dir: &Path) -> Result<()> {
+        let mut s = self.s.lock().unwrap();
+        s.step()?;
+        let s = &mut *s;
+        s.durable_names.retain(|p, _| p.parent() != Some(dir));
+        for (p, id) in s.names.iter().filter(|(p, _)| p.parent() == Some(dir)) {
+            s.durable_names.insert(p.clone(), *id);
+        }
+        Ok(())
+    }
+    /* list() -- the files in the directory */
+    pub(crate) fn list(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        let s = self.s.lock().unwrap();
+        s.check()?;
+        Ok(s.names.keys().filter(|p| p.parent() == Some(dir)).cloned().collect())
+    }
+}
+
+impl fs_s {
+    fn check(&self) -> Result<()> {
+        if self.crashed {
+            return Err(Error::other("crashfs: the system has crashed"));
+        }
+        Ok(())
+    }
+    /* step() -- pass an I/O point, the crash happens at it if it is the one */
+    fn step(&mut self) -> Result<()> {
+        self.check()?;
+        self.ops += 1;
+        if self.ops >= self.crash_at {
+            self.crashed = true;
+            self.check()?;
+        }
+        Ok(())
+    }
+}
+
+impl fd_s {
+    pub(crate) fn len(&self) -> Result<u64> {
+        let s = self.fs.s.lock().unwrap();
+        s.check()?;
+        Ok(s.files[self.id].data.len() as u64)
+    }
+    pub(crate) fn set_len(&self, len: u64) -> Result<()> {
+        let mut s = self.fs.s.lock().unwrap();
+        s.step()?;
+        let f = &mut s.files[self.id];
+        f.data.resize(len as usize, 0);
+        f.unsynced.push(io_t::SetLen(len as usize));
+        Ok(())
+    }
+    pub(crate) fn sync_data(&self) -> Result<()> {
+        let mut s = self.fs.s.lock().unwrap();
+        s.step()?;
+        let s = &mut *s;
+        let f = &mut s.files[self.id];
+        for io in f.unsynced.drain(..) {
+            match io {
+                io_t::Write(pos, w) => write_into(&mut f.durable, pos, &w),
+                io_t::SetLen(len) => f.durable.resize(len, 0),
+            }
+        }
+        for (p, id) in s.names.iter().filter(|(_, id)| **id == self.id) {
+            s.durable_names.insert(p.clone(), *id);
+        }
+        Ok(())
+    }
+    /* read_at() -- fill buf from offset, as read_exact_at() does */
+    pub(crate) fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let s = self.fs.s.lock().unwrap();
+        s.check()?;
+        let data = &s.files[self.id].data;
+        let pos = offset as usize;
+        if pos + buf.len() > data.len() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "failed to fill whole buffer"));
+        }
+        buf.copy_from_slice(&data[pos..pos + buf.len()]);
+        Ok(())
+    }
+    pub(crate) fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        let mut s = self.fs.s.lock().unwrap();
+        s.step()?;
+        let f = &mut s.files[self.id];
+        write_into(&mut f.data, offset as usize, buf);
+        f.unsynced.push(io_t::Write(offset as usize, buf.to_vec()));
+        Ok(())
+    }
+    /* read_zero() -- fill buf from offset, the part beyond the end of the file is zero, as in a private map */
+    pub(crate) fn read_zero(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let s = self.fs.s.lock().unwrap();
+        s.check()?;
+        let data = &s.files[self.id].data;
+        let pos = (offset as usize).min(data.len());
+        let end = (pos + buf.len()).min(data.len());
+        buf[..end - pos].copy_from_slice(&data[pos..end]);
+        Ok(())
+    }
+}
+
+fn write_into(b: &mut Vec<u8>, pos: usize, w: &[u8]) {
+    if b.len() < pos + w.len() {
+        b.resize(pos + w.len(), 0);
+    }
+    b[pos..pos + w.len()].copy_from_slice(w);
+}
+
+fn not_found(path: &Path) -> Error {
+    Error::new(ErrorKind::NotFound, format!("crashfs: {} is not found", path.display()))
+}
+
+/* next_rand() -- xorshift64* */
+pub(crate) fn next_rand(x: &mut u64) -> u64 {
+    *x ^= *x >> 12;
+    *x ^= *x << 25;
+    *x ^= *x >> 27;
+    x.wrapping_mul(0x2545f4914f6cdd1d)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::kv::storage::crashfs::crashfs_s;
+    use crate::kv::storage::mmap::CFile;
+
+    #[test]
+    fn test_reboot() {
+        let dir = PathBuf::from(format!("/lycee-crashfs-test-{}", std::process::id()));
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        let fs = crashfs_s::mount(&dir, 7);
+        let f = CFile::open(&a).unwrap();
+        f.write_at(&[1u8; 4096], 0).unwrap();
+        f.sync_data().unwrap();
+        f.write_at(&[2u8; 4096], 0).unwrap();
+        CFile::open(&b).unwrap();
+        assert_eq!(vec![a.clone(), b.clone()], CFile::list(&dir).unwrap());
+
+        /* the I/O point of the crash fails, and so does everything after it */
+        fs.crash_at(2);
+        f.write_at(&[3u8; 4096], 0).unwrap();
+        assert!(f.sync_data().is_err() && f.write_at(&[4u8; 1], 0).is_err());
+        assert!(fs.crashed());
+
+        /* a synced file keeps its name and what is synced, a write after it may be lost or torn at a sector */
+        fs.reboot();
+        assert_eq!(vec![a.clone()], CFile::list(&dir).unwrap());
+        let f = CFile::open(&a).unwrap();
+        let mut buf = vec![0u8; 4096];
+        f.read_at(&mut buf, 0).unwrap();
+        for s in buf.chunks(512) {
+            assert!(s.iter().all(|c| *c == s[0]) && (1..=3).contains(&s[0]));
+        }
+        assert!(buf.windows(2).all(|w| w[0] >= w[1]));
+
+        /* a rename is durable once the directory is synced */
+        CFile::rename(&a, &b).unwrap();
+        CFile::sync_dir(&dir).unwrap();
+        CFile::remove(&b).unwrap();
+        fs.reboot();
+        assert_eq!(vec![b], CFile::list(&dir).unwrap());
+        fs.unmount();
+    }
+}
@@ -1,5 +1,3 @@
-use std::mem;
-
 const CRC64_TAB: [u64; 256] = [
     0x0000000000000000, 0x7ad870c830358979, 0xf5b0e190606b12f2,
     0x8f689158505e9b8b, 0xc038e5739841b68f, 0xbae095bba8743ff6,
@@ -89,14 +87,50 @@ const CRC64_TAB: [u64; 256] = [
     0x29b7d047efec8728
 ];
 
+/*
+ * the checksum starts from all ones and is inverted at the end, so that a
+ * buffer all zero, as a page or a header never written, does not match
+ * a checksum of zero
+ */
+const CRC64_INIT: u64 = !0;
+const CRC64_XOROUT: u64 = !0;
+
 pub fn kv_crc64(buffer: &[u8]) -> u64 {
-    let mut crc: u64 = 0;
+    crc64_update(CRC64_INIT, buffer) ^ CRC64_XOROUT
+}
+
+fn crc64_update(mut crc: u64, buffer: &[u8]) -> u64 {
     for x in buffer {
-        crc = CRC64_TAB[((crc ^ x.clone() as u64) & 0xff) as usize] ^ (crc >> 8);
+        crc = CRC64_TAB[((crc ^ *x as u64) & 0xff) as usize] ^ (crc >> 8);
     }
-    return crc;
+    crc
+}
+
+/*
+ * kv_crc64_skip() -- the crc64 of buffer, with the 8 bytes at off, where it
+ *                    is stamped, taken as zero. A buffer all zero never
+ *                    matches, one which has never been written is not
+ *                    taken for an intact one.
+ */
+pub fn kv_crc64_skip(buffer: &[u8], off: usize) -> u64 {
+    let crc = crc64_update(CRC64_INIT, &buffer[..off]);
+    let crc = crc64_update(crc, &[0u8; 8]);
+    crc64_update(crc, &buffer[off + 8..]) ^ CRC64_XOROUT
+}
+
+/* kv_crc64_seal() -- stamp the checksum of buffer at off, little endian */
+pub fn kv_crc64_seal(buffer: &mut [u8], off: usize) {
+    let crc = kv_crc64_skip(buffer, off);
+    buffer[off..off + 8].copy_from_slice(&crc.to_le_bytes());
+}
+
+/* kv_crc64_check() -- the checksum stamped at off matches the rest of buffer */
+pub fn kv_crc64_check(buffer: &[u8], off: usize) -> bool {
+    let mut w = [0u8; 8];
+    w.copy_from_slice(&buffer[off..off + 8]);
+    kv_crc64_skip(buffer, off) == u64::from_le_bytes(w)
 }
 
-pub fn as_ne_bytes<T: Sized>(u: &T) -> &'static [u8] {
-    unsafe { &*(u as *const T as *const &[u8]) }
+pub fn as_ne_bytes<T: Sized>(u: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(u as *const T as *const u8, std::mem::size_of::<T>()) }
 }
@@ -0,0 +1,207 @@
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Mutex;
+
+use crate::kv::storage::crc64::{kv_crc64_check, kv_crc64_seal};
+use crate::kv::storage::inner::{DOUBLE_WRITE_LEN, DOUBLE_WRITE_POS, FILE_HEADER_CRC, PAGE_BITMAP_CRC};
+use crate::kv::storage::mmap::CFile;
+use crate::kv::storage::page::page_intact;
+
+/*
+ * The double-write area keeps a copy of the last batch of writes to the
+ * file, so that a write torn by a crash can be repaired, the checksums only
+ * find it. A batch is written to the area and synced before it is written
+ * in place, and it is synced in place before the area takes the next one.
+ * The area starts with a directory of the batch, all little endian:
+ *
+ *     [crc64: u64][n: u32][0: u32] n * [pos: u64][len: u32][kind: u32]
+ *
+ * and the copies of the extents follow it in the same order. On open,
+ * restore() writes back the copy of every extent whose checksum does not
+ * match in place while the one of the copy does. An extent which has not
+ * been written in place at all matches, it is left to the log, see wal.rs.
+ */
+pub(crate) const DWB_PAGE: u32 = 1;
+pub(crate) const DWB_BITMAP: u32 = 2;
+pub(crate) const DWB_HEADER: u32 = 3;
+
+const DIR_LEN: usize = 4096;
+const DIR_HEADER_LEN: usize = 16;
+const ENTRY_LEN: usize = 16;
+const MAX_ENTRIES: usize = (DIR_LEN - DIR_HEADER_LEN) / ENTRY_LEN;
+
+/* dwb_s -- the double-write area of a file, the writes through it go one batch at a time */
+pub(crate) struct dwb_s {
+    b: Mutex<dwb_buf_s>,
+}
+
+struct dwb_buf_s {
+    /* the directory and the copies of the batch being written */
+    buf: Vec<u8>,
+    /* the last batch is written in place, but may not be durable yet */
+    unsynced: bool,
+}
+
+impl dwb_s {
+    pub(crate) fn new() -> dwb_s {
+        dwb_s { b: Mutex::new(dwb_buf_s { buf: Vec::new(), unsynced: false }) }
+    }
+
+    /*
+     * write() -- write the extents of w, (kind, pos, bytes), to the file
+     *            through the area, in as many batches as it takes. The last
+     *            batch is synced in place by the next one, or by the caller.
+     */
+    pub(crate) fn write(&self, file: &CFile, mut w: &[(u32, usize, &[u8])]) -> Result<()> {
+        let mut b = self.b.lock().unwrap();
+        while !w.is_empty() {
+            let mut n = 0;
+            let mut len = DIR_LEN;
+            while n < w.len() && n < MAX_ENTRIES && len + w[n].2.len() <= DOUBLE_WRITE_LEN {
+                len += w[n].2.len();
+                n += 1;
+            }
+            if n == 0 {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      format!("{} bytes do not fit in the double-write area", w[0].2.len())));
+            }
+            /* the copies of the last batch are needed until it is durable in place */
+            if b.unsynced {
+                file.sync_data()?;
+                b.unsynced = false;
+            }
+            let buf = &mut b.buf;
+            buf.clear();
+            buf.resize(DIR_LEN, 0);
+            buf[8..12].copy_from_slice(&(n as u32).to_le_bytes());
+            for (i, (kind, pos, bytes)) in w[..n].iter().enumerate() {
+                let e = DIR_HEADER_LEN + i * ENTRY_LEN;
+                buf[e..e + 8].copy_from_slice(&(*pos as u64).to_le_bytes());
+                buf[e + 8..e + 12].copy_from_slice(&(bytes.len() as u32).to_le_bytes());
+                buf[e + 12..e + 16].copy_from_slice(&kind.to_le_bytes());
+            }
+            kv_crc64_seal(&mut buf[..DIR_LEN], 0);
+            for (_, _, bytes) in w[..n].iter() {
+                buf.extend_from_slice(bytes);
+            }
+            file.write_at(buf, DOUBLE_WRITE_POS as u64)?;
+            file.sync_data()?;
+            for (_, pos, bytes) in w[..n].iter() {
+                file.write_at(bytes, *pos as u64)?;
+            }
+            b.unsynced = true;
+            w = &w[n..];
+        }
+        Ok(())
+    }
+
+    /* restore() -- repair the extents of the last batch which are torn in place, the number of them is returned */
+    pub(crate) fn restore(file: &CFile) -> Result<usize> {
+        let flen = file.len()? as usize;
+        if flen < DOUBLE_WRITE_POS + DIR_LEN {
+            return Ok(0);
+        }
+        let mut dir = vec![0u8; DIR_LEN];
+        file.read_at(&mut dir, DOUBLE_WRITE_POS as u64)?;
+        let n = get_u32(&dir[8..]) as usize;
+        /* the area is torn itself, then nothing was written in place */
+        if !kv_crc64_check(&dir, 0) || n > MAX_ENTRIES {
+            return Ok(0);
+        }
+        let mut off = DOUBLE_WRITE_POS + DIR_LEN;
+        let mut restored = 0;
+        for i in 0..n {
+            let e = &dir[DIR_HEADER_LEN + i * ENTRY_LEN..];
+            let (pos, len, kind) = (get_u64(e) as usize, get_u32(&e[8..]) as usize, get_u32(&e[12..]));
+            let mut copy = vec![0u8; len];
+            file.read_at(&mut copy, off as u64)?;
+            off += len;
+            /* one beyond the end of the file is cut off after it is written, it is not needed any more */
+            if pos + len > flen {
+                continue;
+            }
+            let mut b = vec![0u8; len];
+            file.read_at(&mut b, pos as u64)?;
+            if !intact(kind, &b) && intact(kind, &copy) {
+                file.write_at(&copy, pos as u64)?;
+                restored += 1;
+            }
+        }
+        if restored > 0 {
+            file.sync_data()?;
+        }
+        Ok(restored)
+    }
+}
+
+/* intact() -- the checksum of an extent of the kind matches */
+fn intact(kind: u32, b: &[u8]) -> bool {
+    match kind {
+        DWB_PAGE => page_intact(b),
+        DWB_BITMAP => kv_crc64_check(b, PAGE_BITMAP_CRC),
+        DWB_HEADER => kv_crc64_check(b, FILE_HEADER_CRC),
+        _ => false,
+    }
+}
+
+fn get_u32(b: &[u8]) -> u32 {
+    u32::from_le_bytes(b[..4].try_into().unwrap())
+}
+
+pub(crate) fn get_u64(b: &[u8]) -> u64 {
+    u64::from_le_bytes(b[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::kv::storage::dwb::{dwb_s, DWB_PAGE};
+    use crate::kv::storage::inner::{DOUBLE_WRITE_POS, gpid_t, PAGE_SIZE};
+    use crate::kv::storage::kvdb::kvdb_s;
+    use crate::kv::storage::mmap::CFile;
+    use crate::kv::storage::page::{page_intact, page_s, PAGE_LEAF, seal_page};
+
+    #[test]
+    fn test_restore() {
+        let path = std::env::temp_dir().join(format!("lycee-dwb-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let file = CFile::open(&path).unwrap();
+        file.set_len(kvdb_s::get_page_pos(8, PAGE_SIZE) as u64).unwrap();
+        let pages: Vec<Vec<u8>> = (0..4u8).map(|i| {
+            let mut b = vec![0u8; PAGE_SIZE];
+            let p = page_s::from_bytes_mut(&mut b);
+            p.init(PAGE_LEAF);
+            assert!(p.insert_at(0, &[i; 10], &[i; 1000]));
+            seal_page(&mut b);
+            b
+        }).collect();
+        let dwb = dwb_s::new();
+        let w: Vec<(u32, usize, &[u8])> = (1..).zip(pages.iter())
+            .map(|(gpid, b)| (DWB_PAGE, kvdb_s::get_page_pos(gpid, PAGE_SIZE), &b[..]))
+            .collect();
+        dwb.write(&file, &w).unwrap();
+        let read = |gpid: gpid_t| {
+            let mut b = vec![0u8; PAGE_SIZE];
+            file.read_at(&mut b, kvdb_s::get_page_pos(gpid, PAGE_SIZE) as u64).unwrap();
+            b
+        };
+        assert_eq!(0, dwb_s::restore(&file).unwrap());
+
+        /* a page torn in place is restored, and so is one which is not written at all */
+        let torn = |gpid: gpid_t| file.write_at(&[7u8; 1024], (kvdb_s::get_page_pos(gpid, PAGE_SIZE) + 2048) as u64).unwrap();
+        torn(2);
+        file.write_at(&vec![0u8; PAGE_SIZE], kvdb_s::get_page_pos(3, PAGE_SIZE) as u64).unwrap();
+        assert!(!page_intact(&read(2)) && !page_intact(&read(3)));
+        assert_eq!(2, dwb_s::restore(&file).unwrap());
+        assert_eq!((&pages[1], &pages[2]), (&read(2), &read(3)));
+
+        /* nothing is restored from a torn area */
+        torn(2);
+        file.write_at(&[7u8; 8], DOUBLE_WRITE_POS as u64 + 100).unwrap();
+        assert_eq!(0, dwb_s::restore(&file).unwrap());
+        assert!(!page_intact(&read(2)));
+        drop(file);
+        fs::remove_file(&path).unwrap();
+    }
+}
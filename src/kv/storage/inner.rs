@@ -1,25 +1,44 @@
-use std::cell::RefCell;
+use std::error;
+use std::fmt;
+use std::io::{Error, ErrorKind};
 use std::mem;
 use std::ptr;
 use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+use std::time::Instant;
 
 use crate::{catch_backtrace, catch_symbol};
+use crate::kv::storage::crc64::{as_ne_bytes, kv_crc64_skip};
+use crate::kv::storage::kvdb::kvdb_s;
+use crate::kv::storage::page::record_s;
 
+/* the default page size, the one of a database is set when it is created */
 pub const PAGE_SIZE: usize = 4096;
+pub const MIN_PAGE_SIZE: usize = 512;
+/* the offsets in a page are u16, and so are the lengths of records, whose top bit is a flag */
+pub const MAX_PAGE_SIZE: usize = 32 * 1024;
 pub const FILE_META_LEN: usize = 2 * 1024 * 1024;
-pub const BUSY_PAGE_NUM_POS: usize = 1 * 1024 * 1024;
+pub const BUSY_PAGE_NUM_POS: usize = 1024 * 1024;
+/* the double-write area, between the header and the busy page numbers, see dwb.rs */
+pub const DOUBLE_WRITE_POS: usize = 256 * 1024;
+pub const DOUBLE_WRITE_LEN: usize = BUSY_PAGE_NUM_POS - DOUBLE_WRITE_POS;
 //bytes
 pub const PAGE_BITMAP_LEN: usize = 64 * 1024;
-pub const PAGE_BITMAP_PAGES: usize = PAGE_BITMAP_LEN / PAGE_SIZE;
 pub const PAGE_NUM_PER_CK: usize = PAGE_BITMAP_LEN * 8;
 //bytes
 const PAGE_BITMAP_WLEN: usize = 64 * 1024 / 8;
+/* the pages of a chunk which can be allocated, the last word of the bitmap holds its checksum instead of their bits */
+pub const PAGE_NUM_USABLE_PER_CK: usize = PAGE_NUM_PER_CK - 64;
 pub const MAX_CHUNK_NUM: usize = 256 * 1024;
-const CHUNK_DATA_LEN: usize = PAGE_BITMAP_LEN * 8 * PAGE_SIZE;
-const DATA_AREA_LEN: usize = MAX_CHUNK_NUM * CHUNK_DATA_LEN;
+/* the dirty pages a checkpoint can record, the log is replayed in full for the pages of a larger table */
+pub const DPT_MAX: usize = 4096;
 
-const RECORD_NUM_PG: usize = (PAGE_SIZE / mem::size_of::<record_s>()) - 1;
 
+/* bitmap_pages() -- the pages taken by the bitmap at the head of a chunk */
+pub fn bitmap_pages(page_size: usize) -> usize {
+    PAGE_BITMAP_LEN.div_ceil(page_size)
+}
 
 // TODO: to dump all items in the call stack
 pub fn kvdb_assert(cond: bool) {
@@ -33,61 +52,282 @@ fn _pl() {
 }
 
 // global page id
-pub type gpid_t = usize;
+pub type gpid_t = u64;
 
 pub const GPID_NIL: gpid_t = gpid_t::MAX;
 
-#[derive(Debug, PartialEq)]
-struct record_s {
-    k: usize,
-    v: usize,
-}
+/*
+ * The header, the busy page numbers and the bitmaps are mapped from the file
+ * as they are, so they are laid out with repr(C) and fields of the same
+ * width on every host. The file is little-endian like the pages, so their
+ * numbers are kept little-endian in memory too, in le32_t and le64_t, and
+ * they are read and written with get() and set().
+ */
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct le32_t(u32);
 
-const PAGE_LEAF: usize = 1 << 0;
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct le64_t(u64);
 
-#[derive(Debug, PartialEq)]
-struct page_header_s {
-    record_num: i32,
-    flags: u32,
-    next: gpid_t,
+impl le32_t {
+    pub(crate) const fn get(self) -> u32 {
+        u32::from_le(self.0)
+    }
+    pub(crate) fn set(&mut self, v: u32) {
+        self.0 = v.to_le();
+    }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct page_s {
-    h: page_header_s,
-    rec: [record_s; RECORD_NUM_PG],
+impl le64_t {
+    pub(crate) const fn new(v: u64) -> le64_t {
+        le64_t(v.to_le())
+    }
+    pub(crate) const fn get(self) -> u64 {
+        u64::from_le(self.0)
+    }
+    pub(crate) fn set(&mut self, v: u64) {
+        self.0 = v.to_le();
+    }
 }
 
+/* the first bytes of the file */
+pub const FILE_MAGIC: [u8; 8] = *b"kv@enmo\0";
+/*
+ * the layout of the file, a file of another version is not opened. 2: the
+ * checksums start from all ones, and the numbers are little-endian on every
+ * host, page ids included.
+ */
+pub const FORMAT_VERSION: u32 = 2;
+/* the features a file may use, none yet, a file with one not known here is not opened */
+pub const FEATURES: u64 = 0;
+
+#[repr(C)]
 pub struct file_header_s {
-    pub(crate) magic: &'static str,
-    pub(crate) file_size: u64,
-    pub(crate) record_num: usize,
-    pub(crate) total_pages: usize,
-    pub(crate) spare_pages: usize,
-    pub(crate) level: u32,
-    reserve: u32,
-    pub(crate) root_gpid: gpid_t,
+    pub(crate) magic: [u8; 8],
+    pub(crate) version: le32_t,
+    pub(crate) page_size: le32_t,
+    pub(crate) features: le64_t,
+    pub(crate) file_size: le64_t,
+    pub(crate) record_num: le64_t,
+    pub(crate) total_pages: le64_t,
+    /* zero, the free pages are counted from the bitmaps, see stats() */
+    pub(crate) reserved: le64_t,
+    pub(crate) root_gpid: le64_t,
+    pub(crate) level: le32_t,
+    /* zero, it keeps the fields after it on 8 bytes without the padding of the compiler */
+    pub(crate) pad: le32_t,
+    /*
+     * the last checkpoint: the end of the log when it was taken, and where
+     * the replay starts, the least lsn of the changes which may not be in
+     * the file. The pages which were dirty then are in dpt, with the lsn
+     * of their first change not written, dpt_num is DPT_NONE if there were
+     * too many of them.
+     */
+    pub(crate) ckpt_lsn: le64_t,
+    pub(crate) redo_lsn: le64_t,
+    pub(crate) dpt_num: le64_t,
+    pub(crate) dpt: [dpt_entry_s; DPT_MAX],
+    /* the crc64 of the header, stamped by seal() right before it is written */
+    pub(crate) crc: le64_t,
+}
+
+/* the offset of the checksum in the bytes of the header */
+pub const FILE_HEADER_CRC: usize = mem::offset_of!(file_header_s, crc);
+
+impl file_header_s {
+    pub(crate) fn seal(&mut self) {
+        self.crc.set(kv_crc64_skip(as_ne_bytes(self), FILE_HEADER_CRC));
+    }
+    pub(crate) fn is_intact(&self) -> bool {
+        self.crc.get() == kv_crc64_skip(as_ne_bytes(self), FILE_HEADER_CRC)
+    }
+    /* is_zero() -- the header has never been written */
+    pub(crate) fn is_zero(&self) -> bool {
+        as_ne_bytes(self).iter().all(|b| *b == 0)
+    }
+    /* check_format() -- the file should be a database of the format and the features known here */
+    pub(crate) fn check_format(&self) -> Result<(), Error> {
+        if self.magic != FILE_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, format!("the file is not a database, it starts with {:02x?}", self.magic)));
+        }
+        if self.version.get() != FORMAT_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("the format version of the file is {}, not {}", self.version.get(), FORMAT_VERSION)));
+        }
+        if self.features.get() & !FEATURES != 0 {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("the file uses the features {:#x}, which are not known", self.features.get() & !FEATURES)));
+        }
+        Ok(())
+    }
 }
 
+pub const DPT_NONE: u64 = u64::MAX;
+
+/* dpt_entry_s -- a page of the dirty page table of a checkpoint */
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct dpt_entry_s {
+    pub(crate) gpid: le64_t,
+    pub(crate) rec_lsn: le64_t,
+}
+
+#[repr(C)]
 pub struct page_bitmap_s {
-    pub(crate) w: [usize; PAGE_BITMAP_WLEN],
+    pub(crate) w: [le64_t; PAGE_BITMAP_WLEN - 1],
+    /* the crc64 of the bitmap, stamped by seal() right before it is written */
+    pub(crate) crc: le64_t,
+}
+
+/* the offset of the checksum in the bytes of a bitmap */
+pub const PAGE_BITMAP_CRC: usize = mem::offset_of!(page_bitmap_s, crc);
+
+impl page_bitmap_s {
+    pub(crate) fn seal(&mut self) {
+        self.crc.set(kv_crc64_skip(as_ne_bytes(self), PAGE_BITMAP_CRC));
+    }
+    pub(crate) fn is_intact(&self) -> bool {
+        self.crc.get() == kv_crc64_skip(as_ne_bytes(self), PAGE_BITMAP_CRC)
+    }
+    /* is_zero() -- the bitmap has never been written */
+    pub(crate) fn is_zero(&self) -> bool {
+        as_ne_bytes(self).iter().all(|b| *b == 0)
+    }
+    /* is_busy() -- the page lpid of the chunk is busy */
+    pub(crate) fn is_busy(&self, lpid: usize) -> bool {
+        self.w[lpid >> 6].get() & (1 << (lpid & 63)) != 0
+    }
+    pub(crate) fn set_busy(&mut self, lpid: usize) {
+        let w = &mut self.w[lpid >> 6];
+        w.set(w.get() | 1 << (lpid & 63));
+    }
+    pub(crate) fn set_free(&mut self, lpid: usize) {
+        let w = &mut self.w[lpid >> 6];
+        w.set(w.get() & !(1 << (lpid & 63)));
+    }
+}
+
+/*
+ * corrupt_s -- the error of a page whose checksum does not match, it is
+ *              the kind InvalidData. gpid is the page, or the first page of
+ *              a bitmap, GPID_NIL is the header of the file.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct corrupt_s {
+    pub gpid: gpid_t,
+}
+
+impl corrupt_s {
+    /* of() -- the corruption e reports, if it is one */
+    pub fn of(e: &Error) -> Option<corrupt_s> {
+        e.get_ref().and_then(|e| e.downcast_ref::<corrupt_s>()).copied()
+    }
+}
+
+impl fmt::Display for corrupt_s {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.gpid == GPID_NIL {
+            write!(f, "the checksum of the header does not match")
+        } else {
+            write!(f, "the checksum of page {} does not match", self.gpid)
+        }
+    }
 }
 
+impl error::Error for corrupt_s {}
 
+/* corrupt() -- the error of the page gpid which failed its checksum */
+pub(crate) fn corrupt(gpid: gpid_t) -> Error {
+    Error::new(ErrorKind::InvalidData, corrupt_s { gpid })
+}
+
+
+/* busy_page_num_s -- the busy pages of each chunk, with the ones of its bitmap, they fit in the meta area */
+#[repr(C)]
 pub struct busy_page_num_s {
-    pub(crate) n: [usize; MAX_CHUNK_NUM],
+    pub(crate) n: [le32_t; MAX_CHUNK_NUM],
 }
 
+const _: () = assert!(mem::size_of::<page_bitmap_s>() == PAGE_BITMAP_LEN);
+/* the busy page numbers end where the first chunk starts */
+const _: () = assert!(BUSY_PAGE_NUM_POS + mem::size_of::<busy_page_num_s>() <= FILE_META_LEN);
+
+/*
+ * latch_s -- the reader-writer latch of a page. It is held for as long as a
+ *            page is being read or modified, which is short, so a waiter
+ *            spins and yields. A writer waiting for the readers to leave
+ *            keeps new readers out.
+ */
+#[derive(Debug)]
+pub(crate) struct latch_s(AtomicU32);
+
+const LATCH_EXCL: u32 = 1 << 31;
+const LATCH_WAIT: u32 = 1 << 30;
+
+impl latch_s {
+    pub(crate) const fn new() -> latch_s {
+        latch_s(AtomicU32::new(0))
+    }
+    pub(crate) fn try_lock_shared(&self) -> bool {
+        let s = self.0.load(Ordering::Relaxed);
+        s & (LATCH_EXCL | LATCH_WAIT) == 0
+            && self.0.compare_exchange_weak(s, s + 1, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+    pub(crate) fn lock_shared(&self) {
+        while !self.try_lock_shared() {
+            thread::yield_now();
+        }
+    }
+    pub(crate) fn unlock_shared(&self) {
+        self.0.fetch_sub(1, Ordering::Release);
+    }
+    pub(crate) fn lock_excl(&self) {
+        loop {
+            let s = self.0.load(Ordering::Relaxed);
+            if s & !LATCH_WAIT == 0 {
+                if self.0.compare_exchange_weak(s, LATCH_EXCL, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                    return;
+                }
+            } else if s & LATCH_WAIT == 0 {
+                self.0.fetch_or(LATCH_WAIT, Ordering::Relaxed);
+            }
+            thread::yield_now();
+        }
+    }
+    pub(crate) fn unlock_excl(&self) {
+        self.0.fetch_and(!LATCH_EXCL, Ordering::Release);
+    }
+}
+
+impl PartialEq for latch_s {
+    fn eq(&self, other: &latch_s) -> bool {
+        self.0.load(Ordering::Relaxed) == other.0.load(Ordering::Relaxed)
+    }
+}
+
+/* pg_s -- a frame of the buffer pool, buf holds the page gpid while it is mapped */
 #[derive(Debug, PartialEq)]
 pub struct pg_s {
-    flags: u32,
-    reserv: u32,
-    gpid: gpid_t,
-    buf: Option<page_s>,
-    hash: Node,
-    link: Node,
+    pub(crate) flags: u32,
+    /* the number of users which pinned the page */
+    pub(crate) pin: u32,
+    pub(crate) gpid: gpid_t,
+    pub(crate) buf: Option<Box<[u8]>>,
+    /* when the page became dirty */
+    pub(crate) dirtied: Option<Instant>,
+    /* the lsn of the last change, it is set under the exclusive latch */
+    pub(crate) lsn: u64,
+    /* the start of the group of the first change not written to the file yet, None if there is none */
+    pub(crate) rec_lsn: Option<u64>,
+    /* guards buf, the other fields are guarded by the shard of the cache */
+    pub(crate) latch: latch_s,
+    pub(crate) hash: Node,
+    pub(crate) link: Node,
 }
 
+/* Node -- the link of an intrusive circular list, a list head links to itself when it is empty */
 #[derive(Debug, PartialEq)]
 pub struct Node {
     next: Option<NonNull<Node>>,
@@ -98,61 +338,153 @@ impl Node {
     pub(crate) const fn new() -> Node {
         Node { next: None, prev: None }
     }
+    /* init() -- make an empty list, the node should not move after that */
+    pub(crate) fn init(&mut self) {
+        let n = NonNull::from(&mut *self);
+        self.next = Some(n);
+        self.prev = Some(n);
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.next.is_none_or(|n| ptr::eq(n.as_ptr(), self))
+    }
+    pub(crate) fn next(&self) -> NonNull<Node> {
+        self.next.unwrap()
+    }
+    pub(crate) fn prev(&self) -> NonNull<Node> {
+        self.prev.unwrap()
+    }
+    /* add() -- put n right after the head self */
+    pub(crate) unsafe fn add(&mut self, n: &mut Node) {
+        let mut next = self.next();
+        n.next = Some(next);
+        n.prev = Some(NonNull::from(&mut *self));
+        next.as_mut().prev = Some(NonNull::from(&mut *n));
+        self.next = Some(NonNull::from(&mut *n));
+    }
+    /* del() -- take the node out of its list */
+    pub(crate) unsafe fn del(&mut self) {
+        if let (Some(mut prev), Some(mut next)) = (self.prev, self.next) {
+            prev.as_mut().next = Some(next);
+            next.as_mut().prev = Some(prev);
+        }
+        self.next = None;
+        self.prev = None;
+    }
 }
 
-const _PG: &pg_s = &pg_s::new();
 
 impl pg_s {
-    const fn new() -> pg_s {
-        const r: record_s = record_s { k: 0, v: 0 };
+    pub(crate) const fn new() -> pg_s {
         pg_s {
             flags: 0,
-            reserv: 0,
+            pin: 0,
             gpid: 0,
             buf: None,
+            dirtied: None,
+            lsn: 0,
+            rec_lsn: None,
+            latch: latch_s::new(),
             hash: Node::new(),
             link: Node::new(),
         }
     }
-    pub fn from_hash(hash: &Node) -> &mut pg_s {
-        unsafe {
-            let PG_HASH_OFFSET = (&_PG.hash as *const Node as usize) - (_PG as *const pg_s as usize);// 16
-            println!("PG_HASH_OFFSET={}", PG_HASH_OFFSET);
-            &mut *((hash as *const Node as usize - PG_HASH_OFFSET) as *mut pg_s)
-        }
+    /* from_hash() -- the frame whose hash node is hash */
+    pub(crate) fn from_hash(hash: NonNull<Node>) -> NonNull<pg_s> {
+        unsafe { NonNull::new_unchecked(hash.as_ptr().byte_sub(mem::offset_of!(pg_s, hash)) as *mut pg_s) }
     }
-    pub fn from_link(link: &Node) -> &mut pg_s {
-        unsafe {
-            let PG_LINK_OFFSET = (&_PG.link as *const Node as usize) - (_PG as *const pg_s as usize);// 32
-            println!("PG_LINK_OFFSET={}", PG_LINK_OFFSET);
-            &mut *((link as *const Node as usize - PG_LINK_OFFSET) as *mut pg_s)
-        }
+    /* from_link() -- the frame whose list node is link */
+    pub(crate) fn from_link(link: NonNull<Node>) -> NonNull<pg_s> {
+        unsafe { NonNull::new_unchecked(link.as_ptr().byte_sub(mem::offset_of!(pg_s, link)) as *mut pg_s) }
     }
 }
 
-pub struct cursor_s {
-    gpid: gpid_t,
-    pg: Box<pg_s>,
-    p: Box<page_s>,
-    pos: i64,
-    start_key: usize,
-    end_key: usize,
+/*
+ * cursor_s -- a range scan over the leaves, from both ends. The records of
+ *             the current leaf are copied out, so that the page is only
+ *             latched while it is being read.
+ */
+pub struct cursor_s<'a> {
+    pub(crate) d: &'a kvdb_s,
+    /* the records of the leaf of the front end, front[pos] is the next one to return */
+    pub(crate) front: Option<Vec<record_s>>,
+    pub(crate) pos: usize,
+    /* the records of the leaf of the back end, back[bpos - 1] is the next one to return */
+    pub(crate) back: Option<Vec<record_s>>,
+    pub(crate) bpos: usize,
+    pub(crate) start_key: Vec<u8>,
+    pub(crate) end_key: Option<Vec<u8>>,
+    /*
+     * the keys between lo and hi are not returned yet, a bound is inclusive
+     * if its *_incl is set, and there is no upper bound if hi is None.
+     */
+    pub(crate) lo: Vec<u8>,
+    pub(crate) lo_incl: bool,
+    pub(crate) hi: Option<Vec<u8>>,
+    pub(crate) hi_incl: bool,
+    pub(crate) done: bool,
+    pub(crate) err: Option<Error>,
+}
+
+impl<'a> cursor_s<'a> {
+    /* the error which stopped the iteration, if any */
+    pub fn err(&self) -> Option<&Error> {
+        self.err.as_ref()
+    }
+    pub(crate) fn below_lo(&self, k: &[u8]) -> bool {
+        k < &self.lo[..] || (k == &self.lo[..] && !self.lo_incl)
+    }
+    pub(crate) fn above_hi(&self, k: &[u8]) -> bool {
+        match self.hi {
+            Some(ref hi) => k > &hi[..] || (k == &hi[..] && !self.hi_incl),
+            None => false,
+        }
+    }
+    /* nothing is left between the bounds */
+    pub(crate) fn is_empty_range(&self) -> bool {
+        match self.hi {
+            Some(ref hi) => self.lo > *hi || (self.lo == *hi && !(self.lo_incl && self.hi_incl)),
+            None => false,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::kv::storage::inner::pg_s;
+    use std::mem;
+    use std::ptr::NonNull;
+
+    use crate::kv::storage::crc64::as_ne_bytes;
+    use crate::kv::storage::inner::{le32_t, le64_t, page_bitmap_s, pg_s};
+
+    #[test]
+    fn test_le() {
+        let mut w = le64_t::new(0x0102030405060708);
+        assert_eq!(&[8, 7, 6, 5, 4, 3, 2, 1], as_ne_bytes(&w));
+        w.set(w.get() + 1);
+        assert_eq!(0x0102030405060709, w.get());
+        let mut n = le32_t::default();
+        n.set(0x01020304);
+        assert_eq!(&[4, 3, 2, 1], as_ne_bytes(&n));
+
+        /* the bit of a page is the one the log replay sets in the bytes of the bitmap */
+        let mut pb: Box<page_bitmap_s> = Box::new(unsafe { mem::zeroed() });
+        pb.set_busy(75);
+        assert!(pb.is_busy(75) && !pb.is_busy(74));
+        assert_eq!(1 << 3, as_ne_bytes(&*pb)[75 >> 3]);
+        pb.set_free(75);
+        assert!(pb.is_zero());
+    }
 
     #[test]
     fn to_pg() {
         let pg = &mut pg_s::new();
         pg.flags = 101;
-        let pg2 = pg_s::from_hash(&pg.hash);
-        assert_eq!(101, pg2.flags);
-        assert_eq!(pg, pg2);
+        let pg2 = pg_s::from_hash(NonNull::from(&pg.hash));
+        assert_eq!(101, unsafe { pg2.as_ref() }.flags);
+        assert_eq!(NonNull::from(&*pg), pg2);
         pg.flags = 202;
-        let pg3 = pg_s::from_link(&pg.link);
-        assert_eq!(202, pg3.flags);
-        assert_eq!(pg, pg3);
+        let pg3 = pg_s::from_link(NonNull::from(&pg.link));
+        assert_eq!(202, unsafe { pg3.as_ref() }.flags);
+        assert_eq!(NonNull::from(&*pg), pg3);
     }
 }
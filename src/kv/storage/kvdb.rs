@@ -1,180 +1,1754 @@
-use std::fs::File;
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::ops::{Deref, DerefMut};
 use std::path::Path;
+use std::ptr::NonNull;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use crate::kv::storage::allocator::{allocator_s, ckid_t, lpid_t};
-use crate::kv::storage::cache::cache_s;
-use crate::kv::storage::inner::{busy_page_num_s, cursor_s, file_header_s, GPID_NIL, gpid_t, kvdb_assert, PAGE_BITMAP_PAGES, PAGE_NUM_PER_CK, PAGE_SIZE};
+use crate::kv::storage::allocator::allocator_s;
+use crate::kv::storage::cache::{DEFAULT_CACHE_SIZE, flush_opts_s, MIN_MAPPED_PG, pool_s, SHARD_NUM};
+use crate::kv::storage::dwb::{dwb_s, DWB_HEADER};
+use crate::kv::storage::inner::{corrupt, cursor_s, DOUBLE_WRITE_POS, DPT_MAX, DPT_NONE, dpt_entry_s, FEATURES, FILE_MAGIC, FILE_META_LEN, file_header_s, FORMAT_VERSION, GPID_NIL, gpid_t, kvdb_assert, le64_t, MAX_PAGE_SIZE, MIN_PAGE_SIZE, PAGE_SIZE, pg_s};
 use crate::kv::storage::mmap::{CFile, MapT};
+use crate::kv::storage::page::{max_key_len, max_record_size, PAGE_HEADER_LEN, PAGE_LEAF, page_s, record_s, record_size};
+use crate::kv::storage::wal::{log_page, mtr_keep, mtr_lsn, mtr_save, mtr_take, sync_policy_t, wal_s, wal_stats_s};
 
-const FILE_HEADER_LEN: u64 = PAGE_SIZE as u64;
+/* the header with its dirty page table, in whole pages */
+const FILE_HEADER_LEN: u64 = (mem::size_of::<file_header_s>().div_ceil(PAGE_SIZE) * PAGE_SIZE) as u64;
+const _: () = assert!(FILE_HEADER_LEN <= DOUBLE_WRITE_POS as u64);
+/* the least size of a segment of the log */
+const MIN_SEGMENT_SIZE: u64 = 64 << 10;
 
-const OK: i8 = 0;
-const PAGE_DELETED: i8 = 1;
-const REC_NOT_FOUND: i8 = 2;
-const PAGE_SPLITTED: i8 = 3;
-const REC_REPLACED: i8 = 4;
-const REC_INSERTED: i8 = 5;
-const FOUND_EXACT: i8 = 6;
-const FOUND_GREATER: i8 = 7;
+pub(crate) const OK: i8 = 0;
+pub(crate) const PAGE_DELETED: i8 = 1;
+pub(crate) const REC_NOT_FOUND: i8 = 2;
+pub(crate) const PAGE_SPLITTED: i8 = 3;
+pub(crate) const REC_REPLACED: i8 = 4;
+pub(crate) const REC_INSERTED: i8 = 5;
+pub(crate) const FOUND_EXACT: i8 = 6;
+pub(crate) const FOUND_GREATER: i8 = 7;
 
+/*
+ * pg_t -- a page pinned in the cache and latched, shared for reading or
+ *         exclusive for modifying. It is released by put_page() or when it
+ *         is dropped. The page is marked dirty once it is modified, and a
+ *         modified page goes to the running mini-transaction instead, which
+ *         releases it once it is logged, see wal.rs.
+ */
+pub(crate) struct pg_t {
+    pg: NonNull<pg_s>,
+    excl: bool,
+    dirty: bool,
+    /* the start and end lsn of the group which logged the change */
+    lsn: Option<(u64, u64)>,
+    ch: Arc<pool_s>,
+}
+
+impl Deref for pg_t {
+    type Target = page_s;
+    fn deref(&self) -> &page_s {
+        page_s::from_bytes(unsafe { self.pg.as_ref() }.buf.as_ref().unwrap())
+    }
+}
+
+impl DerefMut for pg_t {
+    fn deref_mut(&mut self) -> &mut page_s {
+        assert!(self.excl, "the page {} is changed under a shared latch", self.gpid());
+        if !self.dirty {
+            mtr_save(self.gpid(), self.as_bytes());
+        }
+        self.dirty = true;
+        page_s::from_bytes_mut(unsafe { self.pg.as_mut() }.buf.as_mut().unwrap())
+    }
+}
+
+impl pg_t {
+    pub(crate) fn gpid(&self) -> gpid_t {
+        unsafe { self.pg.as_ref() }.gpid
+    }
+    pub(crate) fn set_lsn(&mut self, start: u64, lsn: u64) {
+        self.lsn = Some((start, lsn));
+    }
+    /* restore() -- put the image b taken before the page was modified back, it is released as it was then */
+    pub(crate) fn restore(&mut self, b: &[u8]) {
+        unsafe { self.pg.as_mut() }.buf.as_mut().unwrap().copy_from_slice(b);
+        self.dirty = false;
+    }
+}
+
+impl Drop for pg_t {
+    fn drop(&mut self) {
+        let mut gate = None;
+        if self.excl && self.dirty && self.lsn.is_none() {
+            if mtr_keep(|| pg_t { pg: self.pg, excl: true, dirty: true, lsn: None, ch: self.ch.clone() }) {
+                return;
+            }
+            let wal = self.ch.wal.as_ref().unwrap();
+            gate = Some(wal.gate.read().unwrap());
+            self.lsn = Some(log_page(wal, self.gpid(), self.as_bytes()));
+        }
+        let pg = unsafe { &mut *self.pg.as_ptr() };
+        let mut start = 0;
+        if let Some((s, lsn)) = self.lsn {
+            pg.lsn = lsn;
+            start = s;
+        }
+        if self.excl {
+            pg.latch.unlock_excl();
+        } else {
+            pg.latch.unlock_shared();
+        }
+        let wake = self.ch.shard(pg.gpid).c.lock().unwrap().unpin(self.pg, self.dirty, start);
+        drop(gate);
+        if wake {
+            self.ch.wake();
+        }
+    }
+}
+
+/* the pages of one level of the tree */
+#[derive(Debug, Default)]
+pub(crate) struct level_stat_s {
+    pub(crate) pages: usize,
+    pub(crate) records: usize,
+    pub(crate) used: usize,
+    /* the bytes saved by storing the prefix of the keys once per page */
+    pub(crate) saved: usize,
+}
+
+/* options_s -- how a database is opened */
+#[derive(Debug, Clone)]
+pub struct options_s {
+    /* the bytes of the pages kept in the cache */
+    pub cache_size: usize,
+    /* the page size of a new database, an existing one should have been created with the same size */
+    pub page_size: usize,
+    /* how often dirty pages are written out in the background, never if it is zero */
+    pub flush_interval: Duration,
+    /* a page dirty for longer than this is written out in the background */
+    pub dirty_expire: Duration,
+    /* the ratio of dirty pages in the cache, above which the oldest ones are written out */
+    pub dirty_ratio: f64,
+    /* when the writes are durable in the log, put_with() and del_with() may choose for themselves */
+    pub wal_sync: sync_policy_t,
+    /* the size a segment of the log grows to before the next one is started */
+    pub wal_segment_size: u64,
+    /* a checkpoint is taken by a write once the log has grown this much, or this long after the last one */
+    pub checkpoint_size: u64,
+    pub checkpoint_interval: Duration,
+    /* the file grows to a multiple of this many bytes when it is full, by the page if it is zero */
+    pub grow_size: u64,
+}
+
+impl Default for options_s {
+    fn default() -> options_s {
+        options_s {
+            cache_size: DEFAULT_CACHE_SIZE,
+            page_size: PAGE_SIZE,
+            flush_interval: Duration::from_millis(100),
+            dirty_expire: Duration::from_secs(1),
+            dirty_ratio: 0.25,
+            wal_sync: sync_policy_t::Every(Duration::from_millis(100)),
+            wal_segment_size: 16 << 20,
+            checkpoint_size: 64 << 20,
+            checkpoint_interval: Duration::from_secs(60),
+            grow_size: 4 << 20,
+        }
+    }
+}
+
+/*
+ * kvdb_s -- an open database, it can be shared by threads. The pages of the
+ *           tree are latched on their own, see bpt.rs. The locks of the
+ *           allocator and the header are taken in this order, and only for
+ *           short.
+ */
 pub struct kvdb_s {
-    pub(crate) h: MapT<file_header_s>,
-    pub alc: Option<allocator_s>,
-    ch: cache_s,
+    pub(crate) h: Mutex<MapT<file_header_s>>,
+    pub(crate) alc: Mutex<allocator_s>,
+    /*
+     * root_gpid and level of the header change under it. It is held shared
+     * while the root is being latched, and alone by a writer which may
+     * split or collapse the root.
+     */
+    pub(crate) tree: RwLock<()>,
+    pub(crate) ch: Arc<pool_s>,
+    pub(crate) wal: Arc<wal_s>,
+    /* the pages, the bitmaps and the header are written through it */
+    pub(crate) dwb: Arc<dwb_s>,
+    flusher: Option<JoinHandle<()>>,
+    syncer: Option<JoinHandle<()>>,
+    pub(crate) wal_sync: sync_policy_t,
+    /* the last checkpoint, it is held while one is being taken */
+    ckpt: Mutex<ckpt_s>,
+    ckpt_size: u64,
+    ckpt_interval: Duration,
     pub file: CFile,
+    pub(crate) pg_size: usize,
+}
+
+/* ckpt_s -- the end of the log at the last checkpoint, and when it was taken */
+struct ckpt_s {
+    lsn: u64,
+    at: Instant,
+}
+
+impl Drop for kvdb_s {
+    fn drop(&mut self) {
+        if let Some(flusher) = self.flusher.take() {
+            self.ch.stop_flusher(flusher);
+        }
+        if let Some(syncer) = self.syncer.take() {
+            self.wal.stop_syncer(syncer);
+        }
+        /* everything is in the data file after a clean close, the log is not needed any more */
+        if let Err(e) = self.sync().and_then(|_| self.wal.remove()) {
+            eprintln!("failed to write back the cache: {}", e);
+        }
+    }
 }
 
 impl kvdb_s {
     pub fn open<P: AsRef<Path>>(name: P) -> Result<kvdb_s> {
-        let file = CFile::open(name)?;
-        let new = file.metadata()?.len() < FILE_HEADER_LEN;
-        let mut h = file.map_mut::<file_header_s>(0)?;
-        let hd: &mut file_header_s = &mut *h;
-        /* if the database is created right before, we should initialize the header of the file */
+        kvdb_s::open_with(name, &options_s::default())
+    }
+    pub fn open_with<P: AsRef<Path>>(name: P, opts: &options_s) -> Result<kvdb_s> {
+        let ps = opts.page_size;
+        if !ps.is_power_of_two() || !(MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&ps) {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("page size {} is not a power of 2 in [{}, {}]", ps, MIN_PAGE_SIZE, MAX_PAGE_SIZE)));
+        }
+        if opts.cache_size / ps < MIN_MAPPED_PG {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("cache of {} bytes is less than {} pages", opts.cache_size, MIN_MAPPED_PG)));
+        }
+        if !(opts.dirty_ratio > 0.0 && opts.dirty_ratio <= 1.0) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("dirty ratio {} is not in (0, 1]", opts.dirty_ratio)));
+        }
+        if opts.wal_sync == sync_policy_t::Every(Duration::from_secs(0)) {
+            return Err(Error::new(ErrorKind::InvalidInput, "the log should be synced every non-zero interval"));
+        }
+        if opts.wal_segment_size < MIN_SEGMENT_SIZE || opts.checkpoint_size == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("segments of the log of {} bytes, or checkpoints every {} bytes of it, are too small",
+                                          opts.wal_segment_size, opts.checkpoint_size)));
+        }
+        let file = CFile::open(&name)?;
+        let mut new = file.len()? < FILE_HEADER_LEN;
         if new {
-            hd.magic = "kv@enmo";
-            hd.record_num = 0;
-            hd.root_gpid = GPID_NIL;
-            hd.level = 0;
-            hd.total_pages = 0;
-            hd.spare_pages = 0;
-        }
-        hd.file_size = file.metadata()?.len();
-        let mut db = kvdb_s {
+            file.set_len(FILE_HEADER_LEN)?;
+        } else {
+            /* the writes torn by a crash are repaired before anything is read */
+            dwb_s::restore(&file)?;
+        }
+        let dwb = Arc::new(dwb_s::new());
+        /* the header only goes to the file once the log is durable, see wal.rs */
+        let mut h = file.map_copy::<file_header_s>(0)?;
+        let hd: &mut file_header_s = &mut h;
+        /*
+         * a crash while the database is created may leave the file grown, but
+         * no header in it. Once its first chunk is opened, the file reaches
+         * beyond the meta area, and a header all zero is a corrupt one.
+         */
+        if !new && file.len()? <= FILE_META_LEN as u64 && hd.is_zero() {
+            new = true;
+        }
+        /* if the database is created right before, we should initialize the header of the file */
+        let lsn = if new {
+            hd.magic = FILE_MAGIC;
+            hd.version.set(FORMAT_VERSION);
+            hd.features.set(FEATURES);
+            hd.record_num.set(0);
+            hd.root_gpid.set(GPID_NIL);
+            hd.level.set(0);
+            hd.total_pages.set(0);
+            hd.reserved.set(0);
+            hd.page_size.set(ps as u32);
+            hd.ckpt_lsn.set(0);
+            hd.redo_lsn.set(0);
+            hd.dpt_num.set(0);
+            hd.seal();
+            dwb.write(&file, &[(DWB_HEADER, 0, h.as_bytes())])?;
+            file.sync_data()?;
+            0
+        } else {
+            if !hd.is_intact() {
+                /* a file which is not a database, or whose header is of another version, is not taken for a corrupt one, one zeroed is */
+                if !hd.is_zero() && (hd.magic != FILE_MAGIC || hd.version.get() != FORMAT_VERSION) {
+                    hd.check_format()?;
+                }
+                return Err(corrupt(GPID_NIL));
+            }
+            hd.check_format()?;
+            if hd.page_size.get() as usize != ps {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      format!("page size of the database is {}, not {}", hd.page_size.get(), ps)));
+            }
+            let (n, end) = wal_s::replay(&name, &file, &mut h)?;
+            /* the replayed log is in the file once it is synced, the header says the log starts after it */
+            if n > 0 {
+                file.sync_data()?;
+            }
+            if n > 0 || h.redo_lsn.get() != end {
+                h.ckpt_lsn.set(end);
+                h.redo_lsn.set(end);
+                h.dpt_num.set(0);
+                h.seal();
+                dwb.write(&file, &[(DWB_HEADER, 0, h.as_bytes())])?;
+                file.sync_data()?;
+            }
+            end
+        };
+        /* a log left next to a new database is not its own */
+        let wal = Arc::new(wal_s::open(&name, opts.wal_segment_size, lsn, new)?);
+        let hd: &mut file_header_s = &mut h;
+        hd.file_size.set(file.len()?);
+        let alc = allocator_s::new(&file, hd, opts.grow_size)?;
+        let ch = Arc::new(pool_s::new(opts.cache_size / ps, ps, SHARD_NUM, opts.dirty_ratio, Some(wal.clone()), Some(dwb.clone())));
+        let flusher = if opts.flush_interval > Duration::from_secs(0) {
+            let fo = flush_opts_s { interval: opts.flush_interval, expire: opts.dirty_expire };
+            Some(pool_s::start_flusher(ch.clone(), file.try_clone()?, fo))
+        } else {
+            None
+        };
+        let syncer = match opts.wal_sync {
+            sync_policy_t::Every(interval) => Some(wal_s::start_syncer(wal.clone(), interval)),
+            _ => None,
+        };
+        let db = kvdb_s {
             file,
-            h,
-            alc: None,
-            ch: cache_s::new(),
-        };
-        db.init_allocator();
-        return Ok(db);
-    }
-    pub fn get(&mut self, k: u64) -> Result<u64> {
-        return unimplemented!();
-        self.dump_page()?;
-        // struct record_s rec;
-        //
-        // kvdb_dump_page(d, d->h->root_gpid);
-        // ret = bpt_search(d, d->h->root_gpid, k, &rec, NULL);
-        // if (ret==FOUND_EXACT) {
-        // *v = rec.v;
-        // return 0;
-        // }
-        // return -1;
-    }
-    pub fn put(&mut self, k: u64, v: u64) -> Result<()> {
-        return unimplemented!();
-        // if self.h.level == 0 {}
-        // int kvdb_put(kvdb_t d, uint64_t k, uint64_t v)
-        // {
-        // 	int tries = 0;
-        // 	struct record_s rec;
-        // 	int ret;
-        //
-        // 	if (d->h->level==0) {
-        // 		bpt_make_root(d, 1);
-        // 	}
-        // 	rec.k = k;
-        // 	rec.v = v;
-        // 	do {
-        // 		ret = bpt_insert(d, NULL, NULL, -1, d->h->root_gpid, &rec);
-        // 		tries ++;
-        // 		kvdb_assert(tries<=2);
-        // 	} while (ret==PAGE_SPLITED);
-        //
-        // 	if (ret!=REC_REPLACED) {
-        // 		d->h->record_num ++;
-        // 	}
-        //
-        // 	return 0;
-        // }
-    }
-    pub fn del(&mut self, k: u64) -> Result<()> {
+            h: Mutex::new(h),
+            alc: Mutex::new(alc),
+            tree: RwLock::new(()),
+            ch,
+            wal,
+            dwb,
+            flusher,
+            syncer,
+            wal_sync: opts.wal_sync,
+            ckpt: Mutex::new(ckpt_s { lsn, at: Instant::now() }),
+            ckpt_size: opts.checkpoint_size,
+            ckpt_interval: opts.checkpoint_interval,
+            pg_size: ps,
+        };
+        db.init_allocator()?;
+        Ok(db)
+    }
+    /*
+     * sync() -- make everything modified before the call durable, the dirty
+     *           pages in the cache, and the header and bitmaps of the file,
+     *           with a checkpoint after them.
+     */
+    pub fn sync(&self) -> Result<()> {
+        let mut c = self.ckpt.lock().unwrap();
+        self.checkpoint(&mut c, true)
+    }
+    /*
+     * checkpoint() -- write the header and the bitmaps of the file, with the
+     *                 dirty page table of the cache, and give up the log
+     *                 before the first change which may not be in the file.
+     *                 The pages dirty since before the last checkpoint are
+     *                 written out first, or all of them if full. The table
+     *                 is taken while no group is between the log and the
+     *                 cache, the header while the root is not changing, and
+     *                 all of them are written after the log of their changes.
+     */
+    fn checkpoint(&self, c: &mut ckpt_s, full: bool) -> Result<()> {
+        if full {
+            self.ch.sync(&self.file)?;
+        } else {
+            self.ch.sync_older(&self.file, c.lsn)?;
+        }
+        let (lsn, dpt) = {
+            let _g = self.wal.gate.write().unwrap();
+            (self.wal.lsn(), self.ch.dirty_table())
+        };
+        let redo = dpt.iter().map(|e| e.1).min().map_or(lsn, |l| l.min(lsn));
+        /* the bitmaps hold the changes logged before the table, and maybe a few after, but none not logged yet */
+        self.alc.lock().unwrap().write(&self.file, &self.wal, &self.dwb)?;
+        let h = {
+            let _t = self.tree.read().unwrap();
+            let mut h = self.hd();
+            h.ckpt_lsn.set(lsn);
+            h.redo_lsn.set(redo);
+            if dpt.len() <= DPT_MAX {
+                h.dpt_num.set(dpt.len() as u64);
+                for (i, &(gpid, rec_lsn)) in dpt.iter().enumerate() {
+                    h.dpt[i] = dpt_entry_s { gpid: le64_t::new(gpid), rec_lsn: le64_t::new(rec_lsn) };
+                }
+            } else {
+                h.dpt_num.set(DPT_NONE);
+            }
+            h.seal();
+            h.as_bytes().to_vec()
+        };
+        self.wal.flush(self.wal.lsn())?;
+        /* the pages written out are durable before the header says so */
+        self.file.sync_data()?;
+        self.dwb.write(&self.file, &[(DWB_HEADER, 0, &h)])?;
+        self.file.sync_data()?;
+        self.wal.recycle(redo)?;
+        c.lsn = lsn;
+        c.at = Instant::now();
         Ok(())
     }
-    pub fn iter(&self, start_key: u64, end_key: u64) -> Result<Box<cursor_s>> {
-        unimplemented!()
+    /* maybe_checkpoint() -- take a checkpoint if the log has grown enough since the last one, or it is time */
+    fn maybe_checkpoint(&self) -> Result<()> {
+        let mut c = match self.ckpt.try_lock() {
+            Ok(c) => c,
+            Err(_) => return Ok(()),
+        };
+        if self.wal.lsn() - c.lsn >= self.ckpt_size || c.at.elapsed() >= self.ckpt_interval {
+            return self.checkpoint(&mut c, false);
+        }
+        Ok(())
+    }
+    /* hd() -- the header of the file, it should not be held across the calls which allocate pages */
+    pub(crate) fn hd(&self) -> MutexGuard<'_, MapT<file_header_s>> {
+        self.h.lock().unwrap()
+    }
+    /* root() -- the level of the tree and its root, they stay while the tree lock is held */
+    pub(crate) fn root(&self) -> (u32, gpid_t) {
+        let h = self.hd();
+        (h.level.get(), h.root_gpid.get() as gpid_t)
+    }
+    pub fn get(&self, k: &[u8]) -> Result<Vec<u8>> {
+        let mut rec = record_s::default();
+        if self.bpt_search(k, &mut rec)? == FOUND_EXACT {
+            return Ok(rec.v);
+        }
+        Err(not_found(k))
+    }
+    pub fn put(&self, k: &[u8], v: &[u8]) -> Result<()> {
+        self.put_with(k, v, self.wal_sync)
+    }
+    /* put_with() -- put k and v, and wait for them to be durable as policy says */
+    pub fn put_with(&self, k: &[u8], v: &[u8], policy: sync_policy_t) -> Result<()> {
+        /* the overflow pages of the value are logged with the record, or a crash between would leak them */
+        self.mtr(|| self.bpt_insert(&self.new_record(k, v)?))?;
+        self.commit(policy)?;
+        self.maybe_checkpoint()
+    }
+    /* commit() -- wait for the last operation of the thread to be durable, if policy says so */
+    pub(crate) fn commit(&self, policy: sync_policy_t) -> Result<()> {
+        match policy {
+            sync_policy_t::Always => self.wal.flush(mtr_lsn()),
+            _ => Ok(()),
+        }
+    }
+    /* wal_stats() -- the counters of the log, of the syncs and of the writers waiting for them */
+    pub fn wal_stats(&self) -> wal_stats_s {
+        self.wal.stats()
+    }
+    /* new_record() -- the leaf record of k, a value which does not fit in a leaf goes to overflow pages */
+    pub(crate) fn new_record(&self, k: &[u8], v: &[u8]) -> Result<record_s> {
+        if k.len() > max_key_len(self.pg_size) {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("key is too large: {} > {}", k.len(), max_key_len(self.pg_size))));
+        }
+        if record_size(k.len(), v.len()) > max_record_size(self.pg_size) {
+            return self.ovf_write(k, v);
+        }
+        Ok(record_s { k: k.to_vec(), v: v.to_vec(), ovf: false })
+    }
+    pub fn del(&self, k: &[u8]) -> Result<()> {
+        self.del_with(k, self.wal_sync)
+    }
+    /* del_with() -- delete k, and wait for it to be durable as policy says */
+    pub fn del_with(&self, k: &[u8], policy: sync_policy_t) -> Result<()> {
+        if self.mtr(|| self.bpt_delete(k))? == REC_NOT_FOUND {
+            return Err(not_found(k));
+        }
+        self.commit(policy)?;
+        self.maybe_checkpoint()
+    }
+    /*
+     * iter() -- scan the records whose keys are in [start_key, end_key] in
+     *           order, or in reverse order from the back end. There is no
+     *           upper bound if end_key is None.
+     */
+    pub fn iter(&self, start_key: &[u8], end_key: Option<&[u8]>) -> Result<Box<cursor_s<'_>>> {
+        let mut c = Box::new(cursor_s {
+            d: self,
+            front: None,
+            pos: 0,
+            back: None,
+            bpos: 0,
+            start_key: start_key.to_vec(),
+            end_key: end_key.map(|k| k.to_vec()),
+            lo: start_key.to_vec(),
+            lo_incl: true,
+            hi: end_key.map(|k| k.to_vec()),
+            hi_incl: true,
+            done: false,
+            err: None,
+        });
+        c.done = self.root().0 == 0 || c.is_empty_range();
+        Ok(c)
     }
     pub fn dump(&self) -> Result<()> {
-        println!("kvdb header:");
-        return unimplemented!();
-    }
-    pub fn dump_page(&self) -> Result<()> {
-        println!("kvdb header:");
-        return unimplemented!();
-        // pg_t pg;
-        // 	struct page_s *p;
-        //
-        // 	pg = get_page(d, gpid);
-        // 	p = get_page_buf(d, pg);
-        // 	_kvdb_dump_page(gpid, p);
-        // 	put_page(d, pg);
-    }
-    fn make_root(&mut self, leaf: i32) -> Result<()> {
-        // struct page_s *p;
-        // pg_t pg;
-        // gpid_t gpid;
-        //
+        let _t = self.tree.read().unwrap();
+        {
+            let h = self.hd();
+            println!("kvdb header:");
+            println!("    file_size:   {}", h.file_size.get());
+            println!("    record_num:  {}", h.record_num.get());
+            println!("    total_pages: {}", h.total_pages.get());
+            println!("    level:       {}", h.level.get());
+            println!("    root_gpid:   {}", h.root_gpid.get());
+        }
+        let (cached, pinned, dirty) = self.ch.counts();
+        println!("cache: pages = {}, pinned = {}, dirty = {}", cached, pinned, dirty);
+        let (level, root) = self.root();
+        for (i, s) in self.level_stats()?.iter().enumerate() {
+            println!("level {}: pages = {}, records = {}, fanout = {:.1}, fill = {:.1}%, prefix saved = {} bytes",
+                     level as usize - i, s.pages, s.records, s.records as f64 / s.pages as f64,
+                     100.0 * s.used as f64 / (s.pages * (self.pg_size - PAGE_HEADER_LEN)) as f64, s.saved);
+        }
+        if level > 0 {
+            self.dump_page(root)?;
+        }
+        Ok(())
+    }
+    /* level_stats() -- walk the tree level by level from the root, it should not be modified meanwhile */
+    pub(crate) fn level_stats(&self) -> Result<Vec<level_stat_s>> {
+        let mut stats = Vec::new();
+        let (level, root) = self.root();
+        let mut gpids = if level > 0 { vec![root] } else { Vec::new() };
+        while !gpids.is_empty() {
+            let mut s = level_stat_s::default();
+            let mut children = Vec::new();
+            for gpid in gpids {
+                let p = self.get_page(gpid)?;
+                let n = p.record_num();
+                let shared = if p.is_leaf() { n } else { n.saturating_sub(1) };
+                s.pages += 1;
+                s.records += n;
+                s.used += p.used_space();
+                s.saved += p.prefix().len() * shared.saturating_sub(1);
+                if !p.is_leaf() {
+                    children.extend((0..n).map(|i| p.child(i)));
+                }
+                self.put_page(p);
+            }
+            stats.push(s);
+            gpids = children;
+        }
+        Ok(stats)
+    }
+    pub fn dump_page(&self, gpid: gpid_t) -> Result<()> {
+        let p = self.get_page(gpid)?;
+        println!("page {}: record_num = {}, flags = {:#x}, next = {}, free = {}",
+                 gpid, p.record_num(), p.flags(), p.next() as isize, p.free_space());
+        for i in 0..p.record_num() {
+            if p.is_leaf() && p.is_overflow(i) {
+                let (first, len) = p.record(i).ovf_ptr();
+                println!("    [{:>3}] k = {}, overflow = {}, len = {}", i, String::from_utf8_lossy(&p.key(i)), first, len);
+            } else if p.is_leaf() {
+                println!("    [{:>3}] k = {}, v = {}", i, String::from_utf8_lossy(&p.key(i)), String::from_utf8_lossy(p.val(i)));
+            } else {
+                println!("    [{:>3}] k = {}, child = {}", i, String::from_utf8_lossy(&p.key(i)), p.child(i));
+            }
+        }
+        self.put_page(p);
+        Ok(())
+    }
+    pub(crate) fn make_root(&self, leaf: bool) -> Result<()> {
         let gpid = self.alloc_page()?;
         kvdb_assert(gpid != GPID_NIL);
-        self.h.root_gpid = gpid;
-        self.h.level += 1;
-        let pg = self.get_page(gpid);
-        let p = self.get_page_buf(pg);
+        self.set_root(gpid, self.root().0 + 1)?;
+        let mut p = self.new_page(gpid)?;
+        p.init(if leaf { PAGE_LEAF } else { 0 });
+        self.put_page(p);
         Ok(())
-        // p->h.record_num = 0;
-        // p->h.flags = (leaf ? PAGE_LEAF : 0);
-        // p->h.next = GPID_NIL;
-        // put_page(d, pg);
-    }
-    fn alloc_page(&mut self) -> Result<gpid_t> {
-        let alc = self.alc.as_mut().unwrap();
-        let mut ck = alc.curr_ck;
-        let mut gpid: gpid_t = 0;
-        kvdb_assert(ck != ckid_t::MAX);
-        /*
-         * If there is not any free page in the chunk, then we find the next one
-         * and turn to it
-         */
-        if alc.bpn.n[ck] >= PAGE_NUM_PER_CK {
-            self.close_curr_ck()?;
-            ck = self.find_ck(ck);
-            /* TODO: reach the maximum length of the file, need to deal with it */
-            kvdb_assert(ck != ckid_t::MAX);
-            self.open_ck(ck);
-        }
-        let mut lpid_iter = PAGE_BITMAP_PAGES..PAGE_NUM_PER_CK;
-        /* Find a free page in the chunk */
-        for lpid in lpid_iter {
-            gpid = kvdb_s::get_gpid(ck, lpid);
-            if !self.pb_isset(gpid) {
+    }
+    /*
+     * collapse_root() -- an internal root with only one child is replaced by
+     *                    the child, and an empty leaf root leaves the tree
+     *                    empty. The tree lock should be held alone.
+     */
+    pub(crate) fn collapse_root(&self) -> Result<()> {
+        loop {
+            let (level, gpid) = self.root();
+            if level == 0 {
+                break;
+            }
+            let p = self.get_page_mut(gpid)?;
+            let n = p.record_num();
+            let leaf = p.is_leaf();
+            let child = if leaf { GPID_NIL } else { p.child(0) };
+            self.put_page(p);
+            if (leaf && n > 0) || (!leaf && n > 1) {
                 break;
             }
+            self.set_root(child, level - 1)?;
+            self.free_page(gpid)?;
         }
-        kvdb_assert(lpid_iter.next() != None);
-        self.pb_set(gpid as lpid_t);
-        alc.bpn.n[ck] += 1;
-        // let pos = kvdb_s::get_page_pos(gpid);
-        // if self.h.file_size < (pos + PAGE_SIZE) as u64 {
-        //     self.file_allocate(pos, PAGE_SIZE);
-        // }
-        return Ok(gpid);
+        Ok(())
+    }
+    /* set_root() -- change the root of the tree, the tree lock should be held alone */
+    pub(crate) fn set_root(&self, root: gpid_t, level: u32) -> Result<()> {
+        let old = {
+            let mut h = self.hd();
+            let old = (h.root_gpid.get(), h.level.get());
+            h.root_gpid.set(root);
+            h.level.set(level);
+            old
+        };
+        self.mtr_root(root, level, old)
     }
-    fn get_page(&self, p0: usize) -> usize {
-        unimplemented!()
+    /*
+     * get_page() -- pin the page gpid in the cache and latch it shared for
+     *               reading, it is released by put_page().
+     */
+    pub(crate) fn get_page(&self, gpid: gpid_t) -> Result<pg_t> {
+        self.latch_page(gpid, false, false)
     }
-    fn get_page_buf(&self, p0: usize) -> usize {
-        unimplemented!()
+    /* get_page_mut() -- pin the page gpid and latch it exclusive for modifying */
+    pub(crate) fn get_page_mut(&self, gpid: gpid_t) -> Result<pg_t> {
+        self.latch_page(gpid, true, false)
+    }
+    /* new_page() -- pin the page gpid just allocated and latch it exclusive, it is not read, init() it */
+    pub(crate) fn new_page(&self, gpid: gpid_t) -> Result<pg_t> {
+        self.latch_page(gpid, true, true)
+    }
+    /*
+     * the latch is taken after the lock of the shard is dropped, the pin keeps
+     * the frame. A page modified by the running operation is still latched.
+     */
+    fn latch_page(&self, gpid: gpid_t, excl: bool, new: bool) -> Result<pg_t> {
+        kvdb_assert(gpid != GPID_NIL);
+        if let Some(p) = mtr_take(gpid) {
+            return Ok(p);
+        }
+        let pg = self.ch.shard(gpid).get(&self.file, gpid, new)?;
+        let latch = &unsafe { pg.as_ref() }.latch;
+        if excl {
+            latch.lock_excl();
+        } else {
+            latch.lock_shared();
+        }
+        Ok(pg_t { pg, excl, dirty: false, lsn: None, ch: self.ch.clone() })
+    }
+    pub(crate) fn put_page(&self, pg: pg_t) {
+        drop(pg);
+    }
+    /*
+     * put_page_alone() -- log the modified page pg alone and release it, even
+     *                     in an operation. Only a new page nobody can reach
+     *                     yet needs not be held until the operation is logged.
+     */
+    pub(crate) fn put_page_alone(&self, mut pg: pg_t) {
+        let _gate = self.wal.gate.read().unwrap();
+        let (start, lsn) = log_page(&self.wal, pg.gpid(), pg.as_bytes());
+        pg.set_lsn(start, lsn);
+        drop(pg);
     }
 }
 
+fn not_found(k: &[u8]) -> Error {
+    Error::new(ErrorKind::NotFound, format!("key {:?} not found", String::from_utf8_lossy(k)))
+}
+
+
+impl<'a> cursor_s<'a> {
+    /* seek() -- move the front end to the first key not less than k */
+    pub fn seek(&mut self, k: &[u8]) {
+        if k > &self.start_key[..] {
+            self.lo = k.to_vec();
+        } else {
+            self.lo = self.start_key.clone();
+        }
+        self.lo_incl = true;
+        self.front = None;
+        self.done = self.d.root().0 == 0 || self.is_empty_range();
+    }
+    /* seek_for_prev() -- move the back end to the last key not greater than k */
+    pub fn seek_for_prev(&mut self, k: &[u8]) {
+        self.hi = match self.end_key {
+            Some(ref end) if k > &end[..] => Some(end.clone()),
+            _ => Some(k.to_vec()),
+        };
+        self.hi_incl = true;
+        self.back = None;
+        self.done = self.d.root().0 == 0 || self.is_empty_range();
+    }
+    /*
+     * load() -- copy the records of a leaf with their values, the overflow
+     *           ones are read while the leaf is still latched, so that they
+     *           are not freed meanwhile.
+     */
+    fn load(&self, p: &pg_t) -> Result<Vec<record_s>> {
+        p.records().into_iter().map(|rec| self.d.rec_resolve(rec)).collect()
+    }
+    /*
+     * read_front() -- the next record from the front end. The tree may have
+     *                 changed since the last leaf was copied, so the next one
+     *                 is looked up from the root again with lo.
+     */
+    fn read_front(&mut self) -> Result<Option<record_s>> {
+        if self.front.as_ref().is_none_or(|rec| self.pos >= rec.len()) {
+            loop {
+                let leaf = match self.d.bpt_leaf(Some(&self.lo), false, false)? {
+                    Some(leaf) => leaf,
+                    None => return Ok(None),
+                };
+                let p = leaf.p;
+                if p.record_num() > 0 && !self.below_lo(&p.key(p.record_num() - 1)) {
+                    let rec = self.load(&p)?;
+                    self.pos = rec.partition_point(|r| self.below_lo(&r.k));
+                    self.front = Some(rec);
+                    break;
+                }
+                /* the leaf holds nothing above lo, the leaves after it start from its fence */
+                match leaf.hi_fence {
+                    Some(hi) => {
+                        self.lo = hi;
+                        self.lo_incl = true;
+                    }
+                    None => return Ok(None),
+                }
+            }
+        }
+        let rec = self.front.as_ref().unwrap()[self.pos].clone();
+        if self.above_hi(&rec.k) {
+            return Ok(None);
+        }
+        self.pos += 1;
+        self.lo = rec.k.clone();
+        self.lo_incl = false;
+        self.done = self.is_empty_range();
+        Ok(Some(rec))
+    }
+    /*
+     * read_back() -- the next record from the back end. If the leaf of hi
+     *                has nothing up to hi, the keys left are below the least
+     *                key of the leaf, which becomes the new bound.
+     */
+    fn read_back(&mut self) -> Result<Option<record_s>> {
+        while self.back.is_none() || self.bpos == 0 {
+            let leaf = match self.hi {
+                Some(ref hi) => self.d.bpt_leaf(Some(hi), !self.hi_incl, false)?,
+                None => self.d.bpt_leaf(None, false, false)?,
+            };
+            let leaf = match leaf {
+                Some(leaf) => leaf,
+                None => return Ok(None),
+            };
+            let rec = self.load(&leaf.p)?;
+            self.bpos = rec.partition_point(|r| !self.above_hi(&r.k));
+            self.back = Some(rec);
+            if self.bpos == 0 {
+                match leaf.fence {
+                    Some(fence) => {
+                        self.hi = Some(fence);
+                        self.hi_incl = false;
+                    }
+                    None => return Ok(None),
+                }
+            }
+        }
+        let rec = self.back.as_ref().unwrap()[self.bpos - 1].clone();
+        if self.below_lo(&rec.k) {
+            return Ok(None);
+        }
+        self.bpos -= 1;
+        self.hi = Some(rec.k.clone());
+        self.hi_incl = false;
+        self.done = self.is_empty_range();
+        Ok(Some(rec))
+    }
+    fn finish(&mut self, ret: Result<Option<record_s>>) -> Option<(Vec<u8>, Vec<u8>)> {
+        match ret {
+            Ok(Some(rec)) => Some((rec.k, rec.v)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                self.err = Some(e);
+                None
+            }
+        }
+    }
+}
 
-impl Iterator for cursor_s {
-    type Item = (u64, u64);
+impl<'a> Iterator for cursor_s<'a> {
+    type Item = (Vec<u8>, Vec<u8>);
 
     fn next(&mut self) -> Option<Self::Item> {
-        unimplemented!()
+        if self.done {
+            return None;
+        }
+        let ret = self.read_front();
+        self.finish(ret)
+    }
+}
+
+impl<'a> DoubleEndedIterator for cursor_s<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let ret = self.read_back();
+        self.finish(ret)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::io::{Error, ErrorKind, Write};
+    use std::mem;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::kv::storage::crashfs::{crashfs_s, next_rand};
+    use crate::kv::storage::crc64::kv_crc64_seal;
+    use crate::kv::storage::inner::{corrupt_s, DOUBLE_WRITE_POS, FILE_HEADER_CRC, file_header_s, FILE_MAGIC, FORMAT_VERSION, GPID_NIL, PAGE_SIZE};
+    use crate::kv::storage::kvdb::{kvdb_s, options_s};
+    use crate::kv::storage::mmap::CFile;
+    use crate::kv::storage::wal::sync_policy_t;
+
+    fn db_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("lycee-{}-{}.db", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        for seg in wal_files(&path) {
+            let _ = fs::remove_file(seg);
+        }
+        path
+    }
+
+    /* the segments of the log of the database at path, by their names */
+    fn wal_files(path: &Path) -> Vec<PathBuf> {
+        let prefix = format!("{}-wal.", path.file_name().unwrap().to_string_lossy());
+        let mut segs: Vec<PathBuf> = fs::read_dir(path.parent().unwrap()).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.file_name().unwrap().to_string_lossy().starts_with(&prefix))
+            .collect();
+        segs.sort();
+        segs
+    }
+
+    /* spread the keys over the key space so that splits happen everywhere, the lengths of them vary too */
+    fn key(i: u64) -> Vec<u8> {
+        format!("{:x}.{}", i.wrapping_mul(0x9e3779b97f4a7c15) >> (i % 32), i).into_bytes()
+    }
+
+    fn key_num(k: &[u8]) -> u64 {
+        let k = String::from_utf8_lossy(k);
+        k[k.find('.').unwrap() + 1..].parse().unwrap()
+    }
+
+    fn val(i: u64) -> Vec<u8> {
+        format!("{}-{}", i, "v".repeat((i % 97) as usize)).into_bytes()
+    }
+
+    #[test]
+    fn test_put_get() {
+        let path = db_path("put_get");
+        let db = kvdb_s::open(&path).unwrap();
+        assert!(db.get(b"1").is_err());
+        let n = 5000;
+        for i in 0..n {
+            db.put(&key(i), &val(i)).unwrap();
+        }
+        assert_eq!(n as usize, db.hd().record_num.get() as usize);
+        assert!(db.hd().level.get() >= 2);
+        for i in 0..n {
+            assert_eq!(val(i), db.get(&key(i)).unwrap());
+        }
+        assert!(db.get(&key(n)).is_err());
+        /* replacing a value does not change the number of records */
+        db.put(&key(7), b"77").unwrap();
+        assert_eq!(b"77".to_vec(), db.get(&key(7)).unwrap());
+        assert_eq!(n as usize, db.hd().record_num.get() as usize);
+        /* growing values split the pages on replacement */
+        for i in 0..n {
+            db.put(&key(i), &val(i).repeat(3)).unwrap();
+        }
+        assert_eq!(n as usize, db.hd().record_num.get() as usize);
+        for i in 0..n {
+            assert_eq!(val(i).repeat(3), db.get(&key(i)).unwrap());
+        }
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_put_limits() {
+        let path = db_path("put_limits");
+        let db = kvdb_s::open(&path).unwrap();
+        let big = vec![b'k'; 4096];
+        assert_eq!(ErrorKind::InvalidInput, db.put(&big, b"v").unwrap_err().kind());
+        /* keys are ordered byte by byte, the empty key is the smallest one */
+        for k in [&b"b"[..], b"a\x00", b"ab", b"", b"a", b"\xff"].iter() {
+            db.put(k, k).unwrap();
+        }
+        let keys: Vec<Vec<u8>> = db.iter(b"", None).unwrap().map(|(k, _)| k).collect();
+        let expected: Vec<&[u8]> = vec![b"", b"a", b"a\x00", b"ab", b"b", b"\xff"];
+        assert_eq!(expected, keys.iter().map(|k| &k[..]).collect::<Vec<&[u8]>>());
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_del() {
+        let path = db_path("del");
+        let db = kvdb_s::open(&path).unwrap();
+        assert_eq!(ErrorKind::NotFound, db.del(b"1").unwrap_err().kind());
+        let n = 5000;
+        for i in 0..n {
+            db.put(&key(i), &val(i)).unwrap();
+        }
+        for i in (0..n).filter(|i| i % 3 != 0) {
+            db.del(&key(i)).unwrap();
+        }
+        assert_eq!(ErrorKind::NotFound, db.del(&key(1)).unwrap_err().kind());
+        for i in 0..n {
+            assert_eq!(i % 3 == 0, db.get(&key(i)).is_ok());
+        }
+        assert_eq!(n.div_ceil(3) as usize, db.hd().record_num.get() as usize);
+        for i in (0..n).filter(|i| i % 3 == 0) {
+            db.del(&key(i)).unwrap();
+        }
+        assert_eq!(0, db.hd().record_num.get() as usize);
+        assert_eq!(0, db.hd().level.get());
+        assert_eq!(0, db.hd().total_pages.get());
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_del_reuse_pages() {
+        let path = db_path("del_reuse_pages");
+        let db = kvdb_s::open(&path).unwrap();
+        let mut file_size = 0;
+        for round in 0..4 {
+            for i in 0..3000 {
+                db.put(&key(i), &val(round)).unwrap();
+            }
+            if round == 0 {
+                file_size = db.hd().file_size.get();
+            }
+            for i in 0..3000 {
+                db.del(&key(i)).unwrap();
+            }
+        }
+        assert_eq!(file_size, db.hd().file_size.get());
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_iter() {
+        let path = db_path("iter");
+        let db = kvdb_s::open(&path).unwrap();
+        assert_eq!(0, db.iter(b"", None).unwrap().count());
+        let n = 3000;
+        for i in 0..n {
+            db.put(&key(i), &val(i)).unwrap();
+        }
+        let mut keys: Vec<Vec<u8>> = (0..n).map(key).collect();
+        keys.sort();
+        let all: Vec<(Vec<u8>, Vec<u8>)> = db.iter(b"", None).unwrap().collect();
+        assert_eq!(n as usize, all.len());
+        for (i, (k, v)) in all.iter().enumerate() {
+            assert_eq!(keys[i], *k);
+            assert_eq!(db.get(k).unwrap(), *v);
+        }
+        /* both ends of the range are inclusive */
+        let range: Vec<Vec<u8>> = db.iter(&keys[100], Some(&keys[1900])).unwrap().map(|(k, _)| k).collect();
+        assert_eq!(&keys[100..=1900], &range[..]);
+        let mut after = keys[100].clone();
+        after.push(0);
+        let range: Vec<Vec<u8>> = db.iter(&after, Some(&keys[1900][..keys[1900].len() - 1])).unwrap().map(|(k, _)| k).collect();
+        assert_eq!(&keys[101..1900], &range[..]);
+        assert_eq!(0, db.iter(&keys[5], Some(&keys[4])).unwrap().count());
+        for k in &keys[..2000] {
+            db.del(k).unwrap();
+        }
+        let rest: Vec<Vec<u8>> = db.iter(b"", None).unwrap().map(|(k, _)| k).collect();
+        assert_eq!(&keys[2000..], &rest[..]);
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_iter_rev() {
+        let path = db_path("iter_rev");
+        let db = kvdb_s::open(&path).unwrap();
+        assert_eq!(None, db.iter(b"", None).unwrap().next_back());
+        let n = 3000;
+        for i in 0..n {
+            db.put(&key(i), &val(i)).unwrap();
+        }
+        for i in 0..n / 2 {
+            db.del(&key(i * 2)).unwrap();
+        }
+        let fwd: Vec<Vec<u8>> = db.iter(b"", None).unwrap().map(|(k, _)| k).collect();
+        let mut rev: Vec<Vec<u8>> = db.iter(b"", None).unwrap().rev().map(|(k, _)| k).collect();
+        rev.reverse();
+        assert_eq!(fwd, rev);
+
+        /* both ends meet in the middle without returning a record twice */
+        let mut c = db.iter(&fwd[10], Some(&fwd[1000])).unwrap();
+        let mut got = Vec::new();
+        let mut tail = Vec::new();
+        loop {
+            match (c.next(), c.next_back()) {
+                (Some(a), Some(b)) => {
+                    got.push(a.0);
+                    tail.push(b.0);
+                }
+                (Some(a), None) => got.push(a.0),
+                (None, Some(b)) => tail.push(b.0),
+                (None, None) => break,
+            }
+        }
+        tail.reverse();
+        got.extend(tail);
+        assert_eq!(&fwd[10..=1000], &got[..]);
+
+        /* the latest 5 keys before a key */
+        let mut c = db.iter(b"", None).unwrap();
+        c.seek_for_prev(&fwd[700][..fwd[700].len() - 1]);
+        let last: Vec<Vec<u8>> = c.by_ref().rev().take(5).map(|(k, _)| k).collect();
+        assert_eq!(vec![fwd[699].clone(), fwd[698].clone(), fwd[697].clone(), fwd[696].clone(), fwd[695].clone()], last);
+        c.seek(&fwd[100]);
+        assert_eq!(Some(fwd[100].clone()), c.next().map(|(k, _)| k));
+        let mut after = fwd[100].clone();
+        after.push(0);
+        c.seek(&after);
+        assert_eq!(Some(fwd[101].clone()), c.next().map(|(k, _)| k));
+        drop(c);
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    /* a JSON document of about len bytes */
+    fn blob(i: u64, len: usize) -> Vec<u8> {
+        let mut b = format!("{{\"id\": {}, \"items\": [", i).into_bytes();
+        let mut n = 0;
+        while b.len() < len {
+            b.extend_from_slice(format!("{{\"n\": {}, \"s\": \"{}\"}}, ", n, i * n).as_bytes());
+            n += 1;
+        }
+        b.extend_from_slice(b"null]}");
+        b
+    }
+
+    #[test]
+    fn test_overflow() {
+        let path = db_path("overflow");
+        let db = kvdb_s::open(&path).unwrap();
+        let n = 40;
+        let size = |i: u64| (10 + (i as usize * 37) % 190) << 10;
+        for i in 0..n {
+            db.put(&key(i), &blob(i, size(i))).unwrap();
+            db.put(&key(i + n), &val(i)).unwrap();
+        }
+        for i in 0..n {
+            assert_eq!(blob(i, size(i)), db.get(&key(i)).unwrap());
+            assert_eq!(val(i), db.get(&key(i + n)).unwrap());
+        }
+        let mut c = db.iter(b"", None).unwrap();
+        assert_eq!(2 * n as usize, c.by_ref().filter(|(k, v)| db.get(k).unwrap() == *v).count());
+        assert!(c.err().is_none());
+        drop(c);
+
+        /* replacing and deleting a large value give its overflow pages back */
+        let pages = db.hd().total_pages.get() as usize;
+        db.put(&key(0), &blob(0, 200 << 10)).unwrap();
+        assert_eq!(pages + db.ovf_pages(blob(0, 200 << 10).len()) - db.ovf_pages(blob(0, size(0)).len()), db.hd().total_pages.get() as usize);
+        db.put(&key(0), b"small").unwrap();
+        assert_eq!(b"small".to_vec(), db.get(&key(0)).unwrap());
+        db.put(&key(n), &blob(n, 50 << 10)).unwrap();
+        assert_eq!(blob(n, 50 << 10), db.get(&key(n)).unwrap());
+        for i in 0..2 * n {
+            db.del(&key(i)).unwrap();
+        }
+        assert_eq!(0, db.hd().total_pages.get());
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_prefix_keys() {
+        let path = db_path("prefix_keys");
+        let db = kvdb_s::open(&path).unwrap();
+        let k = |t: u64, r: u64| format!("t{:010}_r{:012}", t, r).into_bytes();
+        for t in 0..4 {
+            for r in 0..5000u64 {
+                db.put(&k(t, r * 7919 % 5000), &r.to_le_bytes()).unwrap();
+            }
+        }
+        for t in 0..4 {
+            for r in (0..5000u64).step_by(7) {
+                assert_eq!(r.to_le_bytes().to_vec(), db.get(&k(t, r * 7919 % 5000)).unwrap());
+            }
+        }
+        assert_eq!(20000, db.iter(b"", None).unwrap().count());
+        /* most of each key is stored once per page, and separators are short */
+        let stats = db.level_stats().unwrap();
+        assert_eq!(2, db.hd().level.get());
+        assert!(stats.iter().all(|s| s.saved > 0));
+        assert!(stats[1].records * 24 > stats[1].used);
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let path = db_path("bulk_load");
+        let db = kvdb_s::open(&path).unwrap();
+        let n = 20000;
+        let mut keys: Vec<Vec<u8>> = (0..n).map(key).collect();
+        keys.sort();
+        let items = keys.iter().enumerate().map(|(i, k)| {
+            let v = if i % 1000 == 0 { blob(i as u64, 20 << 10) } else { val(i as u64) };
+            (k.clone(), v)
+        });
+        assert_eq!(n as usize, db.bulk_load(items, 0.7).unwrap());
+        assert_eq!(n as usize, db.hd().record_num.get() as usize);
+        assert_eq!(blob(1000, 20 << 10), db.get(&keys[1000]).unwrap());
+        assert_eq!(val(1), db.get(&keys[1]).unwrap());
+        assert!(keys.iter().eq(db.iter(b"", None).unwrap().map(|(k, _)| k).collect::<Vec<_>>().iter()));
+        assert!(keys.iter().rev().eq(db.iter(b"", None).unwrap().rev().map(|(k, _)| k).collect::<Vec<_>>().iter()));
+        let stats = db.level_stats().unwrap();
+        assert_eq!(1, stats[0].pages);
+        assert!(stats.last().unwrap().used < stats.last().unwrap().pages * 3000);
+        assert_eq!(ErrorKind::InvalidInput, db.bulk_load(vec![(b"a".to_vec(), b"a".to_vec())], 1.0).unwrap_err().kind());
+
+        /* the tree built works as any other one */
+        for i in 0..n {
+            db.put(&key(i), &val(i + 1)).unwrap();
+        }
+        for i in (0..n).filter(|i| i % 2 == 0) {
+            db.del(&key(i)).unwrap();
+        }
+        for i in 0..n {
+            assert_eq!(i % 2 == 1, db.get(&key(i)).map(|v| assert_eq!(val(i + 1), v)).is_ok());
+        }
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bulk_load_unsorted() {
+        let path = db_path("bulk_load_unsorted");
+        let db = kvdb_s::open(&path).unwrap();
+        let items = (0..5000u64).map(|i| ((if i == 4000 { 10 } else { i }).to_be_bytes().to_vec(), blob(i, 1000)));
+        assert_eq!(ErrorKind::InvalidInput, db.bulk_load(items, 1.0).unwrap_err().kind());
+        let dup = vec![(b"a".to_vec(), vec![0; 10000]), (b"a".to_vec(), Vec::new())];
+        assert_eq!(ErrorKind::InvalidInput, db.bulk_load(dup, 1.0).unwrap_err().kind());
+        assert_eq!(ErrorKind::InvalidInput, db.bulk_load(Vec::new(), 0.0).unwrap_err().kind());
+        assert_eq!(0, db.hd().total_pages.get());
+        assert_eq!(0, db.hd().level.get());
+        assert_eq!(0, db.bulk_load(Vec::new(), 0.5).unwrap());
+        assert_eq!(0, db.hd().level.get());
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_options() {
+        let path = db_path("options");
+        for &ps in [1024, 16384].iter() {
+            let opts = options_s { cache_size: 64 * ps, page_size: ps, ..Default::default() };
+            {
+                let db = kvdb_s::open_with(&path, &opts).unwrap();
+                for i in 0..3000 {
+                    db.put(&key(i), &val(i)).unwrap();
+                }
+                db.put(b"big", &blob(0, 100 << 10)).unwrap();
+                for i in (0..3000).step_by(2) {
+                    db.del(&key(i)).unwrap();
+                }
+            }
+            let other = options_s { page_size: if ps == 1024 { 2048 } else { 4096 }, ..opts.clone() };
+            assert_eq!(ErrorKind::InvalidInput, kvdb_s::open_with(&path, &other).err().unwrap().kind());
+            let db = kvdb_s::open_with(&path, &opts).unwrap();
+            assert_eq!(1501, db.iter(b"", None).unwrap().count());
+            for i in 0..3000 {
+                assert_eq!(i % 2 == 1, db.get(&key(i)).map(|v| assert_eq!(val(i), v)).is_ok());
+            }
+            assert_eq!(blob(0, 100 << 10), db.get(b"big").unwrap());
+            drop(db);
+            fs::remove_file(&path).unwrap();
+        }
+        for opts in [options_s { page_size: 3000, ..Default::default() },
+            options_s { page_size: 64 << 10, ..Default::default() },
+            options_s { cache_size: 4096, ..Default::default() }].iter() {
+            assert_eq!(ErrorKind::InvalidInput, kvdb_s::open_with(&path, opts).err().unwrap().kind());
+        }
+    }
+
+    #[test]
+    fn test_reopen() {
+        let path = db_path("reopen");
+        {
+            let db = kvdb_s::open(&path).unwrap();
+            for i in 0..1000 {
+                db.put(&key(i), &val(i)).unwrap();
+            }
+        }
+        let db = kvdb_s::open(&path).unwrap();
+        assert_eq!(1000, db.hd().record_num.get() as usize);
+        for i in 0..1000 {
+            assert_eq!(val(i), db.get(&key(i)).unwrap());
+        }
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rollback() {
+        let path = db_path("rollback");
+        let db = kvdb_s::open(&path).unwrap();
+        for i in 0..1000 {
+            db.put(&key(i), &val(i)).unwrap();
+        }
+        let root = db.root();
+        let mut lost = GPID_NIL;
+        /* nothing of a failed operation is left, not even in the log */
+        let e = db.mtr(|| {
+            db.bpt_insert(&db.new_record(&key(1000), &val(1000))?)?;
+            db.bpt_delete(&key(1))?;
+            lost = db.alloc_page()?;
+            db.set_root(lost, root.0 + 1)?;
+            Err::<(), _>(Error::other("stop"))
+        }).err().unwrap();
+        assert_eq!("stop", e.to_string());
+        assert_eq!(root, db.root());
+        assert!(db.get(&key(1000)).is_err());
+        assert_eq!(val(1), db.get(&key(1)).unwrap());
+        assert_eq!(lost, db.mtr(|| db.alloc_page()).unwrap());
+        db.mtr(|| db.free_page(lost)).unwrap();
+        let v = db.verify().unwrap();
+        assert!(v.problems.is_empty() && v.record_num == 1000);
+        drop(db);
+
+        let db = kvdb_s::open(&path).unwrap();
+        assert_eq!(root, db.root());
+        assert!(db.get(&key(1000)).is_err());
+        for i in 0..1000 {
+            assert_eq!(val(i), db.get(&key(i)).unwrap());
+        }
+        assert!(db.verify().unwrap().problems.is_empty());
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_threads() {
+        fn shared<T: Send + Sync>(_: &T) {}
+        let path = db_path("threads");
+        let db = Arc::new(kvdb_s::open(&path).unwrap());
+        shared(&*db);
+        let n = 2000;
+        for i in 0..n {
+            db.put(&key(i * 4), &val(i * 4)).unwrap();
+        }
+
+        /* writers on their own keys, while readers see the old ones all the time */
+        let mut threads = Vec::new();
+        for t in 1..4 {
+            let db = db.clone();
+            threads.push(thread::spawn(move || {
+                for i in 0..n {
+                    db.put(&key(i * 4 + t), &val(i * 4 + t)).unwrap();
+                    if i % 2 == 0 {
+                        db.del(&key(i * 4 + t)).unwrap();
+                    }
+                }
+            }));
+        }
+        for _ in 0..2 {
+            let db = db.clone();
+            threads.push(thread::spawn(move || {
+                for i in 0..n {
+                    assert_eq!(val(i * 4), db.get(&key(i * 4)).unwrap());
+                }
+                let old = db.iter(&[], None).unwrap().filter(|(k, _)| key_num(k).is_multiple_of(4)).count();
+                assert_eq!(n as usize, old);
+            }));
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!((n + 3 * n / 2) as usize, db.hd().record_num.get() as usize);
+        for (k, v) in db.iter(&[], None).unwrap() {
+            let i = key_num(&k);
+            assert_eq!(val(i), v);
+            assert!(i.is_multiple_of(4) || i / 4 % 2 == 1);
+        }
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    /* xorshift, so that a failed run can be told by its seed */
+    fn rand(seed: &mut u64) -> u64 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        *seed
+    }
+
+    #[test]
+    fn test_stress() {
+        let path = db_path("stress");
+        /* small pages, so that splits and merges happen all the time */
+        let opts = options_s { page_size: 512, ..Default::default() };
+        let db = Arc::new(kvdb_s::open_with(&path, &opts).unwrap());
+        let threads: Vec<_> = (0..8u64).map(|t| {
+            let db = db.clone();
+            thread::spawn(move || {
+                let mut seed = 0x2545f4914f6cdd1d ^ t;
+                let mut m = BTreeMap::new();
+                /* the keys of all the threads are mixed in the tree, the last digit tells the owner */
+                let k = |i: u64| format!("{:04x}{}", i, t).into_bytes();
+                for n in 0..8000 {
+                    let i = rand(&mut seed) % 2000;
+                    match rand(&mut seed) % 10 {
+                        0..=3 => {
+                            let v = val(n).repeat(1 + (n % 3) as usize);
+                            db.put(&k(i), &v).unwrap();
+                            m.insert(k(i), v);
+                        }
+                        4..=5 => assert_eq!(m.remove(&k(i)).is_some(), db.del(&k(i)).is_ok(), "seed {}", t),
+                        6..=8 => assert_eq!(m.get(&k(i)).cloned(), db.get(&k(i)).ok(), "seed {}", t),
+                        _ => {
+                            let (lo, hi) = (k(i), k(i + 50));
+                            let mine = |kv: &(Vec<u8>, Vec<u8>)| kv.0.ends_with(t.to_string().as_bytes());
+                            let want: Vec<_> = m.range(lo.clone()..=hi.clone()).map(|(k, v)| (k.clone(), v.clone())).collect();
+                            let c = db.iter(&lo, Some(&hi)).unwrap();
+                            if i.is_multiple_of(2) {
+                                assert_eq!(want, c.filter(mine).collect::<Vec<_>>(), "seed {}", t);
+                            } else {
+                                assert_eq!(want.into_iter().rev().collect::<Vec<_>>(), c.rev().filter(mine).collect::<Vec<_>>(), "seed {}", t);
+                            }
+                        }
+                    }
+                }
+                m
+            })
+        }).collect();
+        let mut all = BTreeMap::new();
+        for t in threads {
+            all.extend(t.join().unwrap());
+        }
+        assert_eq!(all.len(), db.hd().record_num.get() as usize);
+        assert!(db.iter(&[], None).unwrap().eq(all.into_iter()));
+        for s in db.level_stats().unwrap() {
+            assert!(s.records >= s.pages);
+        }
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sync() {
+        let path = db_path("sync");
+        let opts = options_s { flush_interval: Duration::from_millis(5), dirty_expire: Duration::from_millis(20), ..Default::default() };
+        let db = kvdb_s::open_with(&path, &opts).unwrap();
+        for i in 0..2000 {
+            db.put(&key(i), &val(i)).unwrap();
+        }
+        db.sync().unwrap();
+        assert_eq!(0, db.ch.counts().2);
+
+        /* the flusher writes expired pages without a sync */
+        for i in 2000..3000 {
+            db.put(&key(i), &val(i)).unwrap();
+        }
+        let t0 = std::time::Instant::now();
+        while db.ch.counts().2 > 0 {
+            assert!(t0.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        for i in 0..3000 {
+            assert_eq!(val(i), db.get(&key(i)).unwrap());
+        }
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_crash() {
+        let path = db_path("crash");
+        /* a small cache, so that pages are written out of the log order all the time */
+        let opts = options_s { cache_size: 64 * 4096, flush_interval: Duration::from_secs(0), ..Default::default() };
+        let db = kvdb_s::open_with(&path, &opts).unwrap();
+        for i in 0..3000 {
+            db.put(&key(i), &val(i)).unwrap();
+        }
+        db.put(b"big", &blob(1, 50 << 10)).unwrap();
+        db.sync().unwrap();
+        for i in (0..3000).step_by(2) {
+            db.del(&key(i)).unwrap();
+        }
+        for i in 3000..4000 {
+            db.put(&key(i), &val(i)).unwrap();
+        }
+        db.del(b"big").unwrap();
+        db.wal.flush(db.wal.lsn()).unwrap();
+        /* the changes after the last flush may be lost, but not half of one */
+        for i in 4000..4100 {
+            db.put(&key(i), &val(i)).unwrap();
+        }
+        mem::forget(db);
+        /* a torn group at the end of the log is ignored */
+        let last = wal_files(&path).pop().unwrap();
+        fs::OpenOptions::new().append(true).open(last).unwrap().write_all(&[7u8; 100]).unwrap();
+
+        let db = kvdb_s::open_with(&path, &opts).unwrap();
+        /* the log goes on in a fresh segment, the torn one is a spare now */
+        assert!(wal_files(&path).pop().unwrap().to_string_lossy().ends_with(&format!("{:016x}", db.wal.lsn())));
+        for i in 0..4000 {
+            assert_eq!(i % 2 == 1 || i >= 3000, db.get(&key(i)).map(|v| assert_eq!(val(i), v)).is_ok());
+        }
+        let mut n = 2500;
+        for i in 4000..4100 {
+            if let Ok(v) = db.get(&key(i)) {
+                assert_eq!(val(i), v);
+                n += 1;
+            }
+        }
+        assert!(db.get(b"big").is_err());
+        assert_eq!(n, db.hd().record_num.get() as usize);
+        assert_eq!(n, db.iter(b"", None).unwrap().count());
+        /* the recovered database goes on as usual */
+        for i in 5000..6000 {
+            db.put(&key(i), &val(i)).unwrap();
+        }
+        drop(db);
+        assert!(wal_files(&path).is_empty());
+        let db = kvdb_s::open_with(&path, &opts).unwrap();
+        assert_eq!(n + 1000, db.iter(b"", None).unwrap().count());
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_checksum() {
+        let path = db_path("checksum");
+        let flip = |pos: usize| {
+            let f = CFile::open(&path).unwrap();
+            let mut b = [0u8; 1];
+            f.read_at(&mut b, pos as u64).unwrap();
+            f.write_at(&[b[0] ^ 0x10], pos as u64).unwrap();
+        };
+        let corrupt = |e: std::io::Error| {
+            assert_eq!(ErrorKind::InvalidData, e.kind());
+            corrupt_s::of(&e).unwrap().gpid
+        };
+        let db = kvdb_s::open(&path).unwrap();
+        for i in 0..3000 {
+            db.put(&key(i), &val(i)).unwrap();
+        }
+        let (level, root) = db.root();
+        assert!(level > 0);
+
+        /* a page is checked when it is read into the cache, it is not kept if it does not match */
+        let pos = kvdb_s::get_page_pos(root, PAGE_SIZE);
+        drop(db);
+        flip(pos + 100);
+        let db = kvdb_s::open(&path).unwrap();
+        assert_eq!(root, corrupt(db.get(&key(1)).unwrap_err()));
+        assert_eq!(root, corrupt(db.get(&key(2)).unwrap_err()));
+        flip(pos + 100);
+        assert_eq!(val(1), db.get(&key(1)).unwrap());
+        drop(db);
+
+        /* so are the bitmap of a chunk, named by its first page, and the header */
+        flip(kvdb_s::get_page_pos(0, PAGE_SIZE) + 10);
+        assert_eq!(0, corrupt(kvdb_s::open(&path).err().unwrap()));
+        flip(kvdb_s::get_page_pos(0, PAGE_SIZE) + 10);
+        flip(100);
+        flip(DOUBLE_WRITE_POS + 100);
+        assert_eq!(GPID_NIL, corrupt(kvdb_s::open(&path).err().unwrap()));
+        flip(DOUBLE_WRITE_POS + 100);
+
+        /* a live page, bitmap or header zeroed is not taken for one never written */
+        let zero = |pos: usize, len: usize| {
+            let f = CFile::open(&path).unwrap();
+            let mut b = vec![0u8; len];
+            f.read_at(&mut b, pos as u64).unwrap();
+            f.write_at(&vec![0u8; len], pos as u64).unwrap();
+            b
+        };
+        let undo = |pos: usize, b: Vec<u8>| CFile::open(&path).unwrap().write_at(&b, pos as u64).unwrap();
+        let b = zero(pos, PAGE_SIZE);
+        let db = kvdb_s::open(&path).unwrap();
+        assert_eq!(root, corrupt(db.get(&key(1)).unwrap_err()));
+        drop(db);
+        undo(pos, b);
+        let b = zero(kvdb_s::get_page_pos(0, PAGE_SIZE), PAGE_SIZE);
+        assert_eq!(0, corrupt(kvdb_s::open(&path).err().unwrap()));
+        undo(kvdb_s::get_page_pos(0, PAGE_SIZE), b);
+        let (b, d) = (zero(0, FILE_HEADER_CRC + 8), zero(DOUBLE_WRITE_POS, 8));
+        assert_eq!(GPID_NIL, corrupt(kvdb_s::open(&path).err().unwrap()));
+        undo(0, b);
+        undo(DOUBLE_WRITE_POS, d);
+
+        /* the header is the last write when the database is closed, its copy is still in the double-write area */
+        let db = kvdb_s::open(&path).unwrap();
+        assert_eq!(3000, db.iter(b"", None).unwrap().count());
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_format() {
+        let path = db_path("format");
+        /* change the bytes of the header at pos, and seal it again, so that only the format is wrong */
+        let patch = |pos: usize, b: &[u8]| -> Vec<u8> {
+            let f = CFile::open(&path).unwrap();
+            let mut h = vec![0u8; FILE_HEADER_CRC + 8];
+            f.read_at(&mut h, 0).unwrap();
+            let old = h[pos..pos + b.len()].to_vec();
+            h[pos..pos + b.len()].copy_from_slice(b);
+            kv_crc64_seal(&mut h, FILE_HEADER_CRC);
+            f.write_at(&h, 0).unwrap();
+            old
+        };
+        let fail = |what: &str| {
+            let e = kvdb_s::open(&path).err().unwrap();
+            assert_eq!(ErrorKind::InvalidData, e.kind());
+            assert!(corrupt_s::of(&e).is_none());
+            assert!(e.to_string().contains(what), "{}", e);
+        };
+        let db = kvdb_s::open(&path).unwrap();
+        db.put(&key(1), &val(1)).unwrap();
+        drop(db);
+
+        /* the fields are where the format says, in little-endian */
+        let f = CFile::open(&path).unwrap();
+        let mut h = [0u8; 16];
+        f.read_at(&mut h, 0).unwrap();
+        assert_eq!(&FILE_MAGIC, &h[..8]);
+        assert_eq!(FORMAT_VERSION.to_le_bytes(), h[8..12]);
+        assert_eq!((PAGE_SIZE as u32).to_le_bytes(), h[12..16]);
+
+        let old = patch(mem::offset_of!(file_header_s, magic), b"kv@other");
+        fail("not a database");
+        patch(mem::offset_of!(file_header_s, magic), &old);
+        let old = patch(mem::offset_of!(file_header_s, version), &(FORMAT_VERSION + 1).to_le_bytes());
+        fail(&format!("version of the file is {}", FORMAT_VERSION + 1));
+        patch(mem::offset_of!(file_header_s, version), &old);
+        let old = patch(mem::offset_of!(file_header_s, features), &(1u64 << 40).to_le_bytes());
+        fail("features 0x10000000000");
+        patch(mem::offset_of!(file_header_s, features), &old);
+        let db = kvdb_s::open(&path).unwrap();
+        assert_eq!(val(1), db.get(&key(1)).unwrap());
+        drop(db);
+        fs::remove_file(&path).unwrap();
+
+        /* nor is a file which is not a database taken for a corrupt one */
+        fs::write(&path, vec![b'x'; 4 << 20]).unwrap();
+        fail("not a database");
+        fs::remove_file(&path).unwrap();
+    }
+
+    /* the records a crash_workload() has written, and the change it was making when it failed, if it did */
+    type acked_t = (BTreeMap<Vec<u8>, Vec<u8>>, Option<(Vec<u8>, Option<Vec<u8>>)>);
+
+    /* n random puts, deletes and syncs of a database from seed, until one of them fails */
+    fn crash_workload(path: &PathBuf, opts: &options_s, seed: u64, n: usize) -> acked_t {
+        let mut acked = BTreeMap::new();
+        let db = match kvdb_s::open_with(path, opts) {
+            Ok(db) => db,
+            Err(_) => return (acked, None),
+        };
+        let mut r = seed;
+        for _ in 0..n {
+            let x = next_rand(&mut r);
+            let k = key(x % 300);
+            if x.is_multiple_of(5) && acked.contains_key(&k) {
+                if db.del(&k).is_err() {
+                    return (acked, Some((k, None)));
+                }
+                acked.remove(&k);
+            } else if x.is_multiple_of(61) {
+                if db.sync().is_err() {
+                    break;
+                }
+            } else {
+                let v = if x.is_multiple_of(3) { blob(x % 1000, 1500) } else { val(x % 1000) };
+                if db.put(&k, &v).is_err() {
+                    return (acked, Some((k, Some(v))));
+                }
+                acked.insert(k, v);
+            }
+        }
+        (acked, None)
+    }
+
+    /* the database has every record acknowledged by a crash_workload(), and the change it failed in at most */
+    fn crash_check(db: &kvdb_s, acked: &acked_t) {
+        let mut want = acked.0.clone();
+        let got: BTreeMap<Vec<u8>, Vec<u8>> = db.iter(b"", None).unwrap().collect();
+        if let Some((k, v)) = &acked.1 {
+            if got.get(k) != want.get(k) {
+                match v {
+                    Some(v) => want.insert(k.clone(), v.clone()),
+                    None => want.remove(k),
+                };
+            }
+        }
+        assert_eq!(want, got);
+        let v = db.verify().unwrap();
+        assert!(v.is_ok() && v.records == want.len(), "{}", v);
+        for (k, v) in want.iter() {
+            assert_eq!(v, &db.get(k).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_crash_points() {
+        let dir = std::env::temp_dir().join(format!("lycee-crashfs-{}", std::process::id()));
+        let path = dir.join("crash.db");
+        /* no thread of its own, the I/O points of a workload are the same every time it runs */
+        let opts = options_s {
+            cache_size: 32 * 512,
+            page_size: 512,
+            flush_interval: Duration::from_secs(0),
+            wal_sync: sync_policy_t::Always,
+            wal_segment_size: 64 << 10,
+            checkpoint_size: 16 << 10,
+            checkpoint_interval: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let (seed, n) = (0x5eed, 60);
+        let fs = crashfs_s::mount(&dir, 0);
+        let all = crash_workload(&path, &opts, seed, n);
+        let points = fs.ops();
+        assert!(all.1.is_none() && points > n as u64);
+
+        /* a crash at every I/O point of it, the closing of the database included */
+        for k in 1..=points {
+            let fs = crashfs_s::mount(&dir, k);
+            fs.crash_at(k);
+            let acked = crash_workload(&path, &opts, seed, n);
+            assert!(fs.crashed());
+            fs.reboot();
+            let db = kvdb_s::open_with(&path, &opts).unwrap_or_else(|e| panic!("crash at {}: {}", k, e));
+            crash_check(&db, &acked);
+            /* and it goes on as usual */
+            db.put(b"after", b"crash").unwrap();
+            drop(db);
+            let db = kvdb_s::open_with(&path, &opts).unwrap();
+            assert_eq!(b"crash".to_vec(), db.get(b"after").unwrap());
+        }
+        fs.unmount();
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let path = db_path("checkpoint");
+        let opts = options_s {
+            cache_size: 256 * 4096,
+            flush_interval: Duration::from_secs(0),
+            wal_sync: sync_policy_t::Never,
+            wal_segment_size: 256 << 10,
+            checkpoint_size: 1 << 20,
+            ..Default::default()
+        };
+        let db = kvdb_s::open_with(&path, &opts).unwrap();
+        for i in 0..6000 {
+            db.put(&key(i), &val(i)).unwrap();
+        }
+        for i in (0..6000).step_by(3) {
+            db.del(&key(i)).unwrap();
+        }
+        /* the log before the last checkpoint is given up, what is left is about two checkpoints of it */
+        let st = db.wal_stats();
+        assert!(st.recycled > 0 && st.segments > st.recycled);
+        let left: u64 = wal_files(&path).iter().map(|p| fs::metadata(p).unwrap().len()).sum();
+        assert!(db.wal.lsn() > 16 << 20 && left < 8 << 20, "{} bytes of the log are left", left);
+        let h = db.hd();
+        assert!(h.redo_lsn.get() > 0 && h.redo_lsn.get() <= h.ckpt_lsn.get());
+        drop(h);
+
+        /* the replay starts from the checkpoint */
+        db.wal.flush(db.wal.lsn()).unwrap();
+        mem::forget(db);
+        let db = kvdb_s::open_with(&path, &opts).unwrap();
+        for i in 0..6000 {
+            assert_eq!(i % 3 != 0, db.get(&key(i)).map(|v| assert_eq!(val(i), v)).is_ok());
+        }
+        assert_eq!(4000, db.hd().record_num.get() as usize);
+        assert_eq!(4000, db.iter(b"", None).unwrap().count());
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let path = db_path("group_commit");
+        let opts = options_s { wal_sync: sync_policy_t::Always, flush_interval: Duration::from_secs(0), ..Default::default() };
+        let db = Arc::new(kvdb_s::open_with(&path, &opts).unwrap());
+        let mut threads = Vec::new();
+        for t in 0..4 {
+            let db = db.clone();
+            threads.push(thread::spawn(move || {
+                for i in 0..300 {
+                    db.put(&key(i * 4 + t), &val(i * 4 + t)).unwrap();
+                }
+            }));
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+        let st = db.wal_stats();
+        assert!(st.groups >= 1200);
+        assert!(st.syncs > 0 && st.syncs <= st.waits && st.waits <= 1200);
+        assert!(st.max_batch >= 1 && st.max_wait <= st.wait_time);
+        /* every put returned after its sync, nothing is lost in a crash */
+        let db = Arc::try_unwrap(db).ok().unwrap();
+        mem::forget(db);
+        let db = kvdb_s::open_with(&path, &opts).unwrap();
+        for i in 0..1200 {
+            assert_eq!(val(i), db.get(&key(i)).unwrap());
+        }
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sync_policy() {
+        let path = db_path("sync_policy");
+        let opts = options_s { wal_sync: sync_policy_t::Never, flush_interval: Duration::from_secs(0), ..Default::default() };
+        let db = kvdb_s::open_with(&path, &opts).unwrap();
+        for i in 0..100 {
+            db.put(&key(i), &val(i)).unwrap();
+        }
+        assert_eq!(0, db.wal_stats().syncs);
+        /* a write may ask for more than the database does */
+        db.put_with(&key(100), &val(100), sync_policy_t::Always).unwrap();
+        db.del_with(&key(0), sync_policy_t::Always).unwrap();
+        assert_eq!(2, db.wal_stats().syncs);
+        drop(db);
+
+        let opts = options_s { wal_sync: sync_policy_t::Every(Duration::from_millis(5)), ..opts };
+        let db = kvdb_s::open_with(&path, &opts).unwrap();
+        assert_eq!(100, db.hd().record_num.get() as usize);
+        db.put(&key(0), &val(0)).unwrap();
+        let t0 = std::time::Instant::now();
+        while db.wal_stats().syncs == 0 {
+            assert!(t0.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(5));
+        }
+        drop(db);
+        let opts = options_s { wal_sync: sync_policy_t::Every(Duration::from_secs(0)), ..opts };
+        assert_eq!(ErrorKind::InvalidInput, kvdb_s::open_with(&path, &opts).err().unwrap().kind());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,43 +1,221 @@
-use std::{io, ptr};
+use std::io;
 use std::default::Default;
 use std::fmt::Debug;
-use std::fs::{File, Metadata, OpenOptions};
-use std::io::{Result, Seek};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::Result;
 use std::marker::PhantomData;
+use std::os::unix::fs::FileExt;
 use std::ops::{Deref, DerefMut};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use mmapio::{AsMutT, AsRefT, MmapMut, MmapOptions};
 
-pub struct CFile(File);
+#[cfg(test)]
+use crate::kv::storage::crashfs::{crashfs_s, fd_s};
+
+/*
+ * CFile -- a file of a database or of its log. In the tests, the files
+ * under a mounted crashfs_s go to it instead of the disk, see crashfs.rs,
+ * and so do the file operations below.
+ */
+pub struct CFile(fd_t);
 
+enum fd_t {
+    Os(File),
+    #[cfg(test)]
+    Sim(fd_s),
+}
 
 impl CFile {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<CFile> {
-        Ok(CFile(OpenOptions::new()
+        #[cfg(test)]
+        {
+            if let Some(fs) = crashfs_s::lookup(path.as_ref()) {
+                return Ok(CFile(fd_t::Sim(fs.open(path.as_ref())?)));
+            }
+        }
+        Ok(CFile(fd_t::Os(OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(path)?))
+            .truncate(false)
+            .open(path)?)))
+    }
+    pub fn len(&self) -> Result<u64> {
+        match &self.0 {
+            fd_t::Os(f) => Ok(f.metadata()?.len()),
+            #[cfg(test)]
+            fd_t::Sim(f) => f.len(),
+        }
+    }
+    pub fn set_len(&self, size: u64) -> Result<()> {
+        match &self.0 {
+            fd_t::Os(f) => f.set_len(size),
+            #[cfg(test)]
+            fd_t::Sim(f) => f.set_len(size),
+        }
+    }
+    /* allocate() -- grow the file to size with its blocks reserved, it is only set_len() where fallocate() cannot */
+    pub fn allocate(&self, size: u64) -> Result<()> {
+        match &self.0 {
+            fd_t::Os(f) => {
+                let len = f.metadata()?.len();
+                if size <= len {
+                    return Ok(());
+                }
+                #[cfg(target_os = "linux")]
+                {
+                    use std::os::unix::io::AsRawFd;
+                    let r = unsafe { libc::fallocate(f.as_raw_fd(), 0, len as libc::off_t, (size - len) as libc::off_t) };
+                    if r == 0 {
+                        return Ok(());
+                    }
+                    let e = io::Error::last_os_error();
+                    match e.raw_os_error() {
+                        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) | Some(libc::EINVAL) => {}
+                        _ => return Err(e),
+                    }
+                }
+                f.set_len(size)
+            }
+            #[cfg(test)]
+            fd_t::Sim(f) if size <= f.len()? => Ok(()),
+            #[cfg(test)]
+            fd_t::Sim(f) => f.set_len(size),
+        }
+    }
+    /* punch_hole() -- give the blocks of [pos, pos + len) back, false if the file system cannot */
+    pub fn punch_hole(&self, pos: u64, len: u64) -> Result<bool> {
+        match &self.0 {
+            #[cfg(target_os = "linux")]
+            fd_t::Os(f) => {
+                use std::os::unix::io::AsRawFd;
+                let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+                if unsafe { libc::fallocate(f.as_raw_fd(), mode, pos as libc::off_t, len as libc::off_t) } == 0 {
+                    return Ok(true);
+                }
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(false),
+                    _ => Err(e),
+                }
+            }
+            #[cfg(not(target_os = "linux"))]
+            fd_t::Os(_) => Ok(false),
+            #[cfg(test)]
+            fd_t::Sim(_) => Ok(false),
+        }
+    }
+    /* disk_usage() -- the bytes of the blocks the file takes on the disk */
+    pub fn disk_usage(&self) -> Result<u64> {
+        match &self.0 {
+            fd_t::Os(f) => {
+                use std::os::unix::fs::MetadataExt;
+                Ok(f.metadata()?.blocks() * 512)
+            }
+            #[cfg(test)]
+            fd_t::Sim(f) => f.len(),
+        }
+    }
+    pub fn try_clone(&self) -> Result<CFile> {
+        match &self.0 {
+            fd_t::Os(f) => Ok(CFile(fd_t::Os(f.try_clone()?))),
+            #[cfg(test)]
+            fd_t::Sim(f) => Ok(CFile(fd_t::Sim(f.clone()))),
+        }
+    }
+    pub fn sync_data(&self) -> Result<()> {
+        match &self.0 {
+            fd_t::Os(f) => f.sync_data(),
+            #[cfg(test)]
+            fd_t::Sim(f) => f.sync_data(),
+        }
+    }
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        match &self.0 {
+            fd_t::Os(f) => f.read_exact_at(buf, offset),
+            #[cfg(test)]
+            fd_t::Sim(f) => f.read_at(buf, offset),
+        }
     }
-    pub fn metadata(&self) -> Result<Metadata> {
-        self.0.metadata()
+    pub fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        match &self.0 {
+            fd_t::Os(f) => f.write_all_at(buf, offset),
+            #[cfg(test)]
+            fd_t::Sim(f) => f.write_at(buf, offset),
+        }
     }
     pub fn map_mut<T>(&self, offset: u64) -> Result<MapT<T>> {
-        unsafe {
-            MapT::<T>::new(&self.0, offset)
+        match &self.0 {
+            fd_t::Os(f) => unsafe { MapT::<T>::new(f, offset) },
+            /* a shared map would write behind the back of the file system */
+            #[cfg(test)]
+            fd_t::Sim(_) => Err(io::Error::other("crashfs: shared maps are not supported")),
+        }
+    }
+    /* map_copy() -- a private map, the changes to it stay in memory until they are written by write_at() */
+    pub fn map_copy<T>(&self, offset: u64) -> Result<MapT<T>> {
+        match &self.0 {
+            fd_t::Os(f) => unsafe {
+                Ok(MapT(MmapOptions::new()
+                            .len(std::mem::size_of::<T>())
+                            .offset(offset)
+                            .map_copy(f)?,
+                        PhantomData::<T>,
+                ))
+            },
+            /* a copy of the file as it is now, which is what a private map is allowed to be */
+            #[cfg(test)]
+            fd_t::Sim(f) => {
+                let mut m = MmapMut::map_anon(std::mem::size_of::<T>())?;
+                f.read_zero(&mut m, offset)?;
+                Ok(MapT(m, PhantomData::<T>))
+            }
         }
     }
-}
-
 
-fn copy_nonoverlapping<S, D>(src: *const S, dst: *mut D) {
-    unsafe { ptr::copy_nonoverlapping(src as *const u8, dst as *mut u8, std::mem::size_of::<S>()); }
+    /* remove() -- remove the file at path */
+    pub fn remove<P: AsRef<Path>>(path: P) -> Result<()> {
+        #[cfg(test)]
+        {
+            if let Some(fs) = crashfs_s::lookup(path.as_ref()) {
+                return fs.remove(path.as_ref());
+            }
+        }
+        fs::remove_file(path)
+    }
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
+        #[cfg(test)]
+        {
+            if let Some(fs) = crashfs_s::lookup(from.as_ref()) {
+                return fs.rename(from.as_ref(), to.as_ref());
+            }
+        }
+        fs::rename(from, to)
+    }
+    /* sync_dir() -- make the entries of the directory durable, the files created, renamed or removed in it */
+    pub fn sync_dir<P: AsRef<Path>>(dir: P) -> Result<()> {
+        #[cfg(test)]
+        {
+            if let Some(fs) = crashfs_s::lookup(dir.as_ref()) {
+                return fs.sync_dir(dir.as_ref());
+            }
+        }
+        File::open(dir)?.sync_all()
+    }
+    /* list() -- the paths of the entries in the directory */
+    pub fn list<P: AsRef<Path>>(dir: P) -> Result<Vec<PathBuf>> {
+        #[cfg(test)]
+        {
+            if let Some(fs) = crashfs_s::lookup(dir.as_ref()) {
+                return fs.list(dir.as_ref());
+            }
+        }
+        fs::read_dir(dir)?.map(|e| Ok(e?.path())).collect()
+    }
 }
 
-fn transmute_ref<'a, P, T>(p: *const P) -> &'a T {
-    unsafe { &*(p as *const T) }
-}
 
 #[derive(Debug)]
 pub struct MapT<T>(MmapMut, PhantomData<T>);
@@ -50,7 +228,7 @@ impl<T> Default for MapT<T> {
 
 impl<T> Drop for MapT<T> {
     fn drop(&mut self) {
-        self.flush();
+        let _ = self.flush();
     }
 }
 
@@ -74,7 +252,7 @@ impl<T> MapT<T> {
     ///
     /// # Example
     ///
-    /// ```
+    /// ```ignore
     /// use std::fs::OpenOptions;
     /// use std::io::Write;
     /// use std::path::PathBuf;
@@ -99,6 +277,11 @@ impl<T> MapT<T> {
         self.0.flush()
     }
 
+    /* the bytes of the map */
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
     /// Asynchronously flushes outstanding memory map modifications to disk.
     ///
     /// This method initiates flushing modified pages to durable storage, but it will not wait for
@@ -118,7 +301,7 @@ impl<T> MapT<T> {
     /// in the specified range are flushed; other outstanding changes to the memory map may be
     /// flushed as well.
     pub fn flush_range(&self, offset: usize, len: usize) -> Result<()> {
-        self.flush_range(offset, len)
+        self.0.flush_range(offset, len)
     }
 
     /// Asynchronously flushes outstanding memory map modifications in the range to disk.
@@ -131,7 +314,7 @@ impl<T> MapT<T> {
     /// flushed are those in the specified range; other outstanding changes to the memory map may
     /// be flushed as well.
     pub fn flush_async_range(&self, offset: usize, len: usize) -> Result<()> {
-        self.flush_async_range(offset, len)
+        self.0.flush_async_range(offset, len)
     }
 }
 
@@ -150,12 +333,8 @@ impl<T> DerefMut for MapT<T> {
 
 #[cfg(test)]
 mod tests {
-    use std::fs::OpenOptions;
-    use std::io::Write;
-    use std::mem;
-    use std::sync::Once;
-
-    use mmapio::AsMutT;
+    use std::fs;
+    use std::path::PathBuf;
 
     use crate::kv::storage::mmap::{CFile, MapT};
 
@@ -165,25 +344,50 @@ mod tests {
         n: [u8; 128],
     }
 
+    fn map_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("lycee-mmap-{}-{}.map", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
     #[test]
     fn test_write() {
-        let mut f = CFile::open("test.mmap")
-            .expect("Unable to open file");
+        let path = map_path("write");
+        let f = CFile::open(&path).unwrap();
+        f.set_len(4096).unwrap();
         let mut src = A { n: [0; 128] };
         src.n[0..4].copy_from_slice(&[2, 3, 4, 8]);
-        let mut mmap: MapT<A> = f.map_mut(0)
-                                 .expect("write");
+        let mut mmap: MapT<A> = f.map_mut(0).unwrap();
         mmap.set(&src);
-        mmap.flush();
+        mmap.flush().unwrap();
+        let mut b = [0u8; 4];
+        f.read_at(&mut b, 0).unwrap();
+        assert_eq!([2, 3, 4, 8], b);
+        drop(mmap);
+        drop(f);
+        fs::remove_file(&path).unwrap();
     }
 
     #[test]
     fn test_read() {
-        let f = CFile::open("test.mmap")
-            .expect("Unable to open file");
-        let mut a: &mut A = &mut f.map_mut(0)
-                                  .expect("read");
-        a.n[0] = 12;
-        println!("size={}\nsrc={:?}", mem::size_of::<A>(), a);
+        let path = map_path("read");
+        let f = CFile::open(&path).unwrap();
+        f.set_len(4096).unwrap();
+        f.write_at(&[7u8; 128], 0).unwrap();
+        /* a private copy is not written back, a shared map is */
+        let mut c: MapT<A> = f.map_copy(0).unwrap();
+        assert_eq!([7u8; 128], c.n);
+        c.n[0] = 12;
+        drop(c);
+        let mut m: MapT<A> = f.map_mut(0).unwrap();
+        assert_eq!(7, m.n[0]);
+        m.n[1] = 12;
+        m.flush().unwrap();
+        let mut b = [0u8; 2];
+        f.read_at(&mut b, 0).unwrap();
+        assert_eq!([7, 12], b);
+        drop(m);
+        drop(f);
+        fs::remove_file(&path).unwrap();
     }
 }
@@ -1,19 +1,41 @@
+/* the engine is written in the style of C, its types are named so too */
+#![allow(non_camel_case_types, non_upper_case_globals)]
+
 use std::error::Error;
 
 use modify::Modify;
 
+#[allow(dead_code)]
 mod modify;
 mod kvdb;
+mod bpt;
+mod page;
+mod overflow;
+mod bulk;
+mod verify;
+mod vacuum;
+mod stats;
 pub mod inner;
 mod allocator;
 #[macro_use]
 mod cache;
 mod crc64;
 mod mmap;
+#[cfg(test)]
+mod crashfs;
+mod wal;
+mod dwb;
 mod cmd;
 
+pub use cmd::exec;
+pub use inner::corrupt_s;
+pub use kvdb::{kvdb_s, options_s};
+
+/* the interface to the server, which is not wired to it yet */
+
 /// Storage represents the internal-facing server part of TinyKV, it handles sending and receiving from other
 /// TinyKV nodes. As part of that responsibility, it also reads and writes data to disk (or semi-permanent memory).
+#[allow(dead_code)]
 trait Storage {
     fn start(&self) -> Result<(), Box<dyn Error>>;
     fn stop(&self) -> Result<(), Box<dyn Error>>;
@@ -21,12 +43,14 @@ trait Storage {
     fn reader(&self) -> Result<Box<dyn StorageReader>, Box<dyn Error>>;
 }
 
+#[allow(dead_code)]
 trait StorageReader {
     fn get_cf(&self, cf: String, key: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>>;
     fn iter_cf(&self, cf: String) -> dyn Iterator<Item=dyn DBItem>;
     fn close(&self);
 }
 
+#[allow(dead_code)]
 trait DBItem {
     /// Key returns the key.
     fn key(&self) -> Vec<u8>;
@@ -46,8 +70,8 @@ trait DBItem {
 
 #[cfg(test)]
 mod tests {
-    use crate::kv::storage::cache::cache_s;
-    use crate::kv::storage::inner::Node;
+    use crate::kv::storage::cache::{cache_s, DEFAULT_CACHE_SIZE};
+    use crate::kv::storage::inner::PAGE_SIZE;
 
     #[test]
     fn is_it_work() {
@@ -56,7 +80,7 @@ mod tests {
 
     #[test]
     fn test_cache_s_new() {
-        let cache = cache_s::new();
+        let cache = cache_s::with_capacity(DEFAULT_CACHE_SIZE / PAGE_SIZE, PAGE_SIZE);
         for (i, emem) in cache.hash.iter().enumerate() {
             println!("cache.hash {}:{:?}", i, emem);
         }
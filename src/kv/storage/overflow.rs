@@ -0,0 +1,82 @@
+use std::io::{Error, ErrorKind, Result};
+
+use crate::kv::storage::inner::GPID_NIL;
+use crate::kv::storage::kvdb::kvdb_s;
+use crate::kv::storage::page::{PAGE_HEADER_LEN, PAGE_OVERFLOW, record_s};
+
+impl kvdb_s {
+    /* the bytes of a value kept in one overflow page */
+    fn ovf_data_len(&self) -> usize {
+        self.pg_size - PAGE_HEADER_LEN
+    }
+    /* ovf_pages() -- the number of overflow pages a value of len bytes takes */
+    pub(crate) fn ovf_pages(&self, len: usize) -> usize {
+        len.div_ceil(self.ovf_data_len())
+    }
+
+    /*
+     * ovf_write() -- store v in a new chain of overflow pages, and return the
+     *                record of k which points to it. The chain is written from
+     *                its tail, so that each page knows its next one. The pages
+     *                are not held by the running operation, a value may take
+     *                more of them than the cache has, but their allocation is
+     *                logged with it.
+     */
+    pub(crate) fn ovf_write(&self, k: &[u8], v: &[u8]) -> Result<record_s> {
+        let mut next = GPID_NIL;
+        for chunk in v.chunks(self.ovf_data_len()).rev() {
+            let gpid = self.alloc_page()?;
+            let mut p = self.new_page(gpid)?;
+            p.init(PAGE_OVERFLOW);
+            p.set_next(next);
+            p.data_mut()[..chunk.len()].copy_from_slice(chunk);
+            self.put_page_alone(p);
+            next = gpid;
+        }
+        Ok(record_s::overflow(k.to_vec(), next, v.len()))
+    }
+
+    /* ovf_read() -- collect the value of an overflow record */
+    pub(crate) fn ovf_read(&self, rec: &record_s) -> Result<Vec<u8>> {
+        let (mut gpid, len) = rec.ovf_ptr();
+        let mut v = Vec::with_capacity(len);
+        while v.len() < len {
+            if gpid == GPID_NIL {
+                return Err(Error::new(ErrorKind::InvalidData,
+                                      format!("overflow chain is too short: {} < {}", v.len(), len)));
+            }
+            let p = self.get_page(gpid)?;
+            let n = self.ovf_data_len().min(len - v.len());
+            v.extend_from_slice(&p.data()[..n]);
+            gpid = p.next();
+            self.put_page(p);
+        }
+        Ok(v)
+    }
+
+    /* ovf_free() -- give the pages of an overflow record back to the allocator */
+    pub(crate) fn ovf_free(&self, rec: &record_s) -> Result<()> {
+        let (mut gpid, _) = rec.ovf_ptr();
+        while gpid != GPID_NIL {
+            let p = self.get_page(gpid)?;
+            let next = p.next();
+            self.put_page(p);
+            self.free_page(gpid)?;
+            gpid = next;
+        }
+        Ok(())
+    }
+
+    /*
+     * rec_resolve() -- a leaf record with its value in place, wherever it is
+     *                  kept. The leaf should be latched, or the overflow pages
+     *                  could be freed under the reader.
+     */
+    pub(crate) fn rec_resolve(&self, rec: record_s) -> Result<record_s> {
+        if rec.ovf {
+            let v = self.ovf_read(&rec)?;
+            return Ok(record_s { k: rec.k, v, ovf: false });
+        }
+        Ok(rec)
+    }
+}
@@ -0,0 +1,577 @@
+use std::cmp::Ordering;
+
+use crate::kv::storage::crc64::{kv_crc64_check, kv_crc64_seal};
+use crate::kv::storage::inner::{GPID_NIL, gpid_t, kvdb_assert};
+
+/*
+ * A page is a slotted page, all the integers in it are little endian:
+ *
+ *     +--------+-------------------+------------+----------------+
+ *     | header | slot directory -> | free space | <- record cells |
+ *     +--------+-------------------+------------+----------------+
+ *
+ * The slot directory keeps the offsets of the cells in key order, and the
+ * cells are allocated from the end of the page towards the directory. The
+ * prefix shared by all the keys of the page is kept once at the very end of
+ * it, and the cells only hold the rest of the keys. The
+ * space of a removed cell is only counted in frag, until the page is
+ * compacted. A cell is laid out as:
+ *
+ *     [key len: u16][value len: u16][key][value]
+ *
+ * In an internal page, the value of a record is the gpid of the child, and
+ * the key of the first record is never used, it is left empty and does not
+ * count for the prefix. A value too
+ * large to stay in a leaf is kept in a chain of overflow pages, the top bit
+ * of the value length is set and the value of the cell is an overflow
+ * pointer, [first gpid: u64][value len: u64]. The data of an overflow page
+ * follows the page header, and the next page of the chain is in next. The
+ * leaves are linked by next too, forward only. The crc64 of the whole page
+ * is in the header, it is only stamped when the page is written to the
+ * file, and checked when it is read back, see seal_page().
+ */
+const PH_FLAGS: usize = 0;
+const PH_RECORD_NUM: usize = 2;
+const PH_CELL_LO: usize = 4;
+const PH_FRAG: usize = 6;
+const PH_NEXT: usize = 8;
+const PH_PREFIX_LEN: usize = 16;
+const PH_CRC: usize = 18;
+pub const PAGE_HEADER_LEN: usize = 26;
+
+const SLOT_LEN: usize = 2;
+const CELL_HEADER_LEN: usize = 4;
+const CHILD_LEN: usize = 8;
+pub const OVF_PTR_LEN: usize = 16;
+const VLEN_OVF: usize = 1 << 15;
+const VLEN_MASK: usize = VLEN_OVF - 1;
+
+pub const PAGE_LEAF: u16 = 1 << 0;
+pub const PAGE_OVERFLOW: u16 = 1 << 1;
+
+/* seal_page() -- stamp the checksum of a page which is about to be written */
+pub fn seal_page(b: &mut [u8]) {
+    kv_crc64_seal(b, PH_CRC)
+}
+
+/* page_intact() -- the checksum of a page read from the file matches, a page never written is all zero and does not */
+pub fn page_intact(b: &[u8]) -> bool {
+    kv_crc64_check(b, PH_CRC)
+}
+
+/* the space a record takes in a page, slot included */
+pub fn record_size(klen: usize, vlen: usize) -> usize {
+    SLOT_LEN + CELL_HEADER_LEN + klen + vlen
+}
+
+/* max_record_size() -- a record never takes more than a quarter of a page, so that a split always works */
+pub fn max_record_size(page_size: usize) -> usize {
+    (page_size - PAGE_HEADER_LEN) / 4
+}
+
+/* max_key_len() -- a key should fit in a record of an internal page, or with an overflow pointer */
+pub fn max_key_len(page_size: usize) -> usize {
+    max_record_size(page_size) - record_size(0, CHILD_LEN.max(OVF_PTR_LEN))
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct record_s {
+    pub(crate) k: Vec<u8>,
+    pub(crate) v: Vec<u8>,
+    /* v is an overflow pointer */
+    pub(crate) ovf: bool,
+}
+
+impl record_s {
+    /* child() -- a record of an internal page which points to gpid */
+    pub fn child(k: Vec<u8>, gpid: gpid_t) -> record_s {
+        record_s { k, v: gpid.to_le_bytes().to_vec(), ovf: false }
+    }
+    /* the child gpid of a record of an internal page */
+    pub fn gpid(&self) -> gpid_t {
+        let mut w = [0u8; 8];
+        w.copy_from_slice(&self.v);
+        u64::from_le_bytes(w) as gpid_t
+    }
+    /* overflow() -- a record whose value is kept in the chain starting at gpid */
+    pub fn overflow(k: Vec<u8>, gpid: gpid_t, len: usize) -> record_s {
+        let mut v = gpid.to_le_bytes().to_vec();
+        v.extend_from_slice(&(len as u64).to_le_bytes());
+        record_s { k, v, ovf: true }
+    }
+    /* the first page and the length of an overflow value */
+    pub fn ovf_ptr(&self) -> (gpid_t, usize) {
+        kvdb_assert(self.ovf && self.v.len() == OVF_PTR_LEN);
+        let mut w = [0u8; 8];
+        w.copy_from_slice(&self.v[..8]);
+        let gpid = u64::from_le_bytes(w) as gpid_t;
+        w.copy_from_slice(&self.v[8..]);
+        (gpid, u64::from_le_bytes(w) as usize)
+    }
+    pub fn size(&self) -> usize {
+        record_size(self.k.len(), self.v.len())
+    }
+}
+
+fn common_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+/* prefix_len() -- the length of the prefix shared by the sorted keys of a page */
+pub fn prefix_len(recs: &[record_s], leaf: bool) -> usize {
+    let keys = if leaf { recs } else { &recs[1.min(recs.len())..] };
+    match (keys.first(), keys.last()) {
+        (Some(first), Some(last)) => common_len(&first.k, &last.k),
+        _ => 0,
+    }
+}
+
+/* packed_size() -- the space the records take in a page, with the prefix of the keys stored once */
+pub fn packed_size(recs: &[record_s], leaf: bool) -> usize {
+    let plen = prefix_len(recs, leaf);
+    let (unused, shared) = match recs.first() {
+        Some(first) if !leaf => (first.k.len(), recs.len() - 1),
+        _ => (0, recs.len()),
+    };
+    recs.iter().map(|r| r.size()).sum::<usize>() - unused - plen * shared + plen
+}
+
+/*
+ * separator() -- the key which separates recs[..m] from recs[m..] in the
+ *                parent. Between leaves it is the shortest key greater than
+ *                the last key on the left, which is not greater than the
+ *                first one on the right. The key of an internal record is
+ *                the lower bound of a subtree, it is kept as is.
+ */
+pub fn separator(recs: &[record_s], m: usize, leaf: bool) -> Vec<u8> {
+    if !leaf {
+        return recs[m].k.clone();
+    }
+    shortest_separator(&recs[m - 1].k, &recs[m].k)
+}
+
+/* shortest_separator() -- the shortest key s with a < s <= b */
+pub fn shortest_separator(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let n = common_len(a, b);
+    b[..(n + 1).min(b.len())].to_vec()
+}
+
+/*
+ * split_point() -- find m, so that recs[..m] and recs[m..] both fit in pages
+ *                  with capacity cap and are as even as possible. The
+ *                  separator of recs[m] should not be longer than max_sep.
+ */
+pub fn split_point(recs: &[record_s], cap: usize, max_sep: usize, leaf: bool) -> Option<usize> {
+    let mut best: Option<(usize, usize)> = None;
+    for m in 1..recs.len() {
+        let left = packed_size(&recs[..m], leaf);
+        let right = packed_size(&recs[m..], leaf);
+        if left > cap || right > cap || separator(recs, m, leaf).len() > max_sep {
+            continue;
+        }
+        let diff = left.abs_diff(right);
+        if best.is_none_or(|(_, d)| diff < d) {
+            best = Some((m, diff));
+        }
+    }
+    best.map(|(m, _)| m)
+}
+
+#[repr(transparent)]
+pub struct page_s {
+    b: [u8],
+}
+
+impl page_s {
+    pub fn from_bytes(b: &[u8]) -> &page_s {
+        unsafe { &*(b as *const [u8] as *const page_s) }
+    }
+    pub fn from_bytes_mut(b: &mut [u8]) -> &mut page_s {
+        unsafe { &mut *(b as *mut [u8] as *mut page_s) }
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.b
+    }
+    /* copy_from() -- make the page a copy of src, which is of the same size */
+    pub fn copy_from(&mut self, src: &page_s) {
+        self.b.copy_from_slice(&src.b);
+    }
+
+    fn get_u16(&self, off: usize) -> usize {
+        u16::from_le_bytes([self.b[off], self.b[off + 1]]) as usize
+    }
+    fn set_u16(&mut self, off: usize, v: usize) {
+        self.b[off..off + 2].copy_from_slice(&(v as u16).to_le_bytes());
+    }
+    fn get_u64(&self, off: usize) -> u64 {
+        let mut w = [0u8; 8];
+        w.copy_from_slice(&self.b[off..off + 8]);
+        u64::from_le_bytes(w)
+    }
+    fn set_u64(&mut self, off: usize, v: u64) {
+        self.b[off..off + 8].copy_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn init(&mut self, flags: u16) {
+        let len = self.b.len();
+        self.set_u16(PH_FLAGS, flags as usize);
+        self.set_u16(PH_RECORD_NUM, 0);
+        self.set_u16(PH_CELL_LO, len);
+        self.set_u16(PH_FRAG, 0);
+        self.set_u16(PH_PREFIX_LEN, 0);
+        self.set_next(GPID_NIL);
+    }
+    pub fn flags(&self) -> u16 {
+        self.get_u16(PH_FLAGS) as u16
+    }
+    pub fn is_leaf(&self) -> bool {
+        self.flags() & PAGE_LEAF != 0
+    }
+    pub fn record_num(&self) -> usize {
+        self.get_u16(PH_RECORD_NUM)
+    }
+    /* the prefix shared by the keys of the page */
+    pub fn prefix(&self) -> &[u8] {
+        &self.b[self.b.len() - self.get_u16(PH_PREFIX_LEN)..]
+    }
+    pub fn next(&self) -> gpid_t {
+        self.get_u64(PH_NEXT) as gpid_t
+    }
+    pub fn set_next(&mut self, gpid: gpid_t) {
+        self.set_u64(PH_NEXT, gpid)
+    }
+
+    /* the room for slots and cells */
+    pub fn capacity(&self) -> usize {
+        self.b.len() - PAGE_HEADER_LEN
+    }
+    pub fn free_space(&self) -> usize {
+        self.contiguous_space() + self.get_u16(PH_FRAG)
+    }
+    fn contiguous_space(&self) -> usize {
+        self.get_u16(PH_CELL_LO) - PAGE_HEADER_LEN - SLOT_LEN * self.record_num()
+    }
+    pub fn used_space(&self) -> usize {
+        self.capacity() - self.free_space()
+    }
+    /* a page is underflowed if less than a quarter of it is used */
+    pub fn is_underflow(&self) -> bool {
+        self.used_space() < self.capacity() / 4
+    }
+    /* fits() -- whether a record of size bytes surely goes in, even if the page gives up its prefix for it */
+    pub fn fits(&self, size: usize) -> bool {
+        self.free_space() >= size + self.prefix().len() * self.record_num()
+    }
+    /* can_remove() -- whether the page is not underflowed after losing size bytes */
+    pub fn can_remove(&self, size: usize) -> bool {
+        self.used_space() >= self.capacity() / 4 + size
+    }
+    /* record_space() -- the space record i takes, slot included */
+    pub fn record_space(&self, i: usize) -> usize {
+        SLOT_LEN + self.cell_size(self.cell(i))
+    }
+    /* the space taken by the largest record */
+    pub fn max_record_space(&self) -> usize {
+        (0..self.record_num()).map(|i| self.record_space(i)).max().unwrap_or(0)
+    }
+
+    fn cell(&self, i: usize) -> usize {
+        kvdb_assert(i < self.record_num());
+        self.get_u16(PAGE_HEADER_LEN + SLOT_LEN * i)
+    }
+    fn cell_size(&self, off: usize) -> usize {
+        CELL_HEADER_LEN + self.get_u16(off) + (self.get_u16(off + 2) & VLEN_MASK)
+    }
+    /* suffix() -- the part of key i after the prefix of the page */
+    pub fn suffix(&self, i: usize) -> &[u8] {
+        let off = self.cell(i);
+        let klen = self.get_u16(off);
+        &self.b[off + CELL_HEADER_LEN..off + CELL_HEADER_LEN + klen]
+    }
+    /* key i is never used if it is the first one of an internal page */
+    fn unused_key(&self, i: usize) -> bool {
+        i == 0 && !self.is_leaf()
+    }
+    pub fn key(&self, i: usize) -> Vec<u8> {
+        if self.unused_key(i) {
+            return Vec::new();
+        }
+        let mut k = self.prefix().to_vec();
+        k.extend_from_slice(self.suffix(i));
+        k
+    }
+    /* cmp_key() -- compare key i with k, without putting key i together */
+    pub fn cmp_key(&self, i: usize, k: &[u8]) -> Ordering {
+        let prefix = self.prefix();
+        let n = prefix.len().min(k.len());
+        match prefix[..n].cmp(&k[..n]) {
+            Ordering::Equal if n < prefix.len() => Ordering::Greater,
+            Ordering::Equal => self.suffix(i).cmp(&k[n..]),
+            ord => ord,
+        }
+    }
+    pub fn val(&self, i: usize) -> &[u8] {
+        let off = self.cell(i);
+        let klen = self.get_u16(off);
+        let vlen = self.get_u16(off + 2) & VLEN_MASK;
+        let start = off + CELL_HEADER_LEN + klen;
+        &self.b[start..start + vlen]
+    }
+    /* val_mut() -- the value of record i to be changed in place, its length stays */
+    fn val_mut(&mut self, i: usize) -> &mut [u8] {
+        let off = self.cell(i);
+        let klen = self.get_u16(off);
+        let vlen = self.get_u16(off + 2) & VLEN_MASK;
+        let start = off + CELL_HEADER_LEN + klen;
+        &mut self.b[start..start + vlen]
+    }
+    /* is_overflow() -- the value of record i is an overflow pointer */
+    pub fn is_overflow(&self, i: usize) -> bool {
+        self.get_u16(self.cell(i) + 2) & VLEN_OVF != 0
+    }
+    /* the data area of an overflow page */
+    pub fn data(&self) -> &[u8] {
+        &self.b[PAGE_HEADER_LEN..]
+    }
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.b[PAGE_HEADER_LEN..]
+    }
+    pub fn child(&self, i: usize) -> gpid_t {
+        kvdb_assert(!self.is_leaf());
+        let mut w = [0u8; 8];
+        w.copy_from_slice(self.val(i));
+        u64::from_le_bytes(w) as gpid_t
+    }
+    pub fn set_child(&mut self, i: usize, gpid: gpid_t) {
+        kvdb_assert(!self.is_leaf());
+        self.val_mut(i).copy_from_slice(&gpid.to_le_bytes());
+    }
+    /* set_ovf_gpid() -- the overflow chain of record i starts at gpid now */
+    pub fn set_ovf_gpid(&mut self, i: usize, gpid: gpid_t) {
+        kvdb_assert(self.is_overflow(i));
+        self.val_mut(i)[..8].copy_from_slice(&gpid.to_le_bytes());
+    }
+    pub fn record(&self, i: usize) -> record_s {
+        record_s { k: self.key(i), v: self.val(i).to_vec(), ovf: self.is_overflow(i) }
+    }
+    pub fn records(&self) -> Vec<record_s> {
+        (0..self.record_num()).map(|i| self.record(i)).collect()
+    }
+
+    /*
+     * search() -- binary search in a leaf page, returns the position of the
+     *             first record whose key is not less than k, and whether the
+     *             key at that position equals k.
+     */
+    pub fn search(&self, k: &[u8]) -> (usize, bool) {
+        let (mut lo, mut hi) = (0, self.record_num());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.cmp_key(mid, k) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Equal => return (mid, true),
+                Ordering::Greater => hi = mid,
+            }
+        }
+        (lo, false)
+    }
+    /*
+     * child_pos() -- in an internal page, the key of the first record is the
+     *                lower bound of the whole subtree and never compared, so
+     *                the child to descend is the last one whose key is not
+     *                greater than k.
+     */
+    pub fn child_pos(&self, k: &[u8]) -> usize {
+        let (mut lo, mut hi) = (1, self.record_num());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.cmp_key(mid, k) != Ordering::Greater {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo - 1
+    }
+
+    /*
+     * insert_at() -- insert a record before position pos, false if there is no
+     *                room for it. If the key does not share the prefix of the
+     *                page, the page is packed again with a shorter one.
+     */
+    pub fn insert_at(&mut self, pos: usize, k: &[u8], v: &[u8]) -> bool {
+        self.insert_cell(pos, k, v, 0)
+    }
+    pub fn insert_rec(&mut self, pos: usize, rec: &record_s) -> bool {
+        self.insert_cell(pos, &rec.k, &rec.v, if rec.ovf { VLEN_OVF } else { 0 })
+    }
+    fn insert_cell(&mut self, pos: usize, k: &[u8], v: &[u8], vflags: usize) -> bool {
+        let n = self.record_num();
+        kvdb_assert(pos <= n);
+        let plen = self.prefix().len();
+        if !self.unused_key(pos) && !k.starts_with(self.prefix()) {
+            return self.repack(pos, k, v, vflags);
+        }
+        let full = k;
+        let k = if self.unused_key(pos) { &[][..] } else { &k[plen..] };
+        let size = record_size(k.len(), v.len());
+        /* packing the page again squeezes out the holes left by removed cells */
+        if size > self.contiguous_space() {
+            return self.repack(pos, full, v, vflags);
+        }
+        let off = self.get_u16(PH_CELL_LO) - (size - SLOT_LEN);
+        self.set_u16(off, k.len());
+        self.set_u16(off + 2, v.len() | vflags);
+        self.b[off + CELL_HEADER_LEN..off + CELL_HEADER_LEN + k.len()].copy_from_slice(k);
+        self.b[off + CELL_HEADER_LEN + k.len()..off + size - SLOT_LEN].copy_from_slice(v);
+        self.set_u16(PH_CELL_LO, off);
+
+        let slots = PAGE_HEADER_LEN + SLOT_LEN * pos;
+        self.b.copy_within(slots..PAGE_HEADER_LEN + SLOT_LEN * n, slots + SLOT_LEN);
+        self.set_u16(slots, off);
+        self.set_u16(PH_RECORD_NUM, n + 1);
+        true
+    }
+    pub fn remove_at(&mut self, pos: usize) {
+        let n = self.record_num();
+        let off = self.cell(pos);
+        let size = self.cell_size(off);
+        if off == self.get_u16(PH_CELL_LO) {
+            self.set_u16(PH_CELL_LO, off + size);
+        } else {
+            self.set_u16(PH_FRAG, self.get_u16(PH_FRAG) + size);
+        }
+        let slots = PAGE_HEADER_LEN + SLOT_LEN * pos;
+        self.b.copy_within(slots + SLOT_LEN..PAGE_HEADER_LEN + SLOT_LEN * n, slots);
+        self.set_u16(PH_RECORD_NUM, n - 1);
+    }
+    /* repack() -- rebuild the page with a new record, the prefix may change */
+    fn repack(&mut self, pos: usize, k: &[u8], v: &[u8], vflags: usize) -> bool {
+        let mut recs = self.records();
+        recs.insert(pos, record_s { k: k.to_vec(), v: v.to_vec(), ovf: vflags & VLEN_OVF != 0 });
+        if packed_size(&recs, self.is_leaf()) > self.capacity() {
+            return false;
+        }
+        self.rebuild(&recs);
+        true
+    }
+    /* rebuild() -- replace all the records of the page, the header is kept */
+    pub fn rebuild(&mut self, recs: &[record_s]) {
+        let leaf = self.is_leaf();
+        kvdb_assert(packed_size(recs, leaf) <= self.capacity());
+        let len = self.b.len();
+        let plen = prefix_len(recs, leaf);
+        if plen > 0 {
+            self.b[len - plen..].copy_from_slice(&recs[recs.len() - 1].k[..plen]);
+        }
+        self.set_u16(PH_PREFIX_LEN, plen);
+        self.set_u16(PH_RECORD_NUM, 0);
+        self.set_u16(PH_CELL_LO, len - plen);
+        self.set_u16(PH_FRAG, 0);
+        for (i, rec) in recs.iter().enumerate() {
+            self.insert_rec(i, rec);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kv::storage::page::{packed_size, page_intact, page_s, PAGE_HEADER_LEN, PAGE_LEAF, record_s, record_size, seal_page, separator, split_point};
+
+    #[test]
+    fn test_insert_remove() {
+        let mut buf = vec![0u8; 256];
+        let p = page_s::from_bytes_mut(&mut buf);
+        p.init(PAGE_LEAF);
+        assert_eq!(256 - PAGE_HEADER_LEN, p.free_space());
+        for k in [&b"b"[..], b"d", b"a", b"c"].iter() {
+            let (pos, exact) = p.search(k);
+            assert!(!exact);
+            assert!(p.insert_at(pos, k, b"0123456789"));
+        }
+        assert_eq!(4, p.record_num());
+        assert_eq!(4 * record_size(1, 10), p.used_space());
+        assert_eq!((2, true), p.search(b"c"));
+        assert_eq!((4, false), p.search(b"e"));
+        assert_eq!(b"b".to_vec(), p.key(1));
+        assert_eq!(b"0123456789", p.val(1));
+
+        /* the hole of a removed cell is reused after compaction */
+        p.remove_at(1);
+        p.remove_at(1);
+        assert_eq!(2 * record_size(1, 10), p.used_space());
+        let big = vec![7u8; p.free_space() - record_size(1, 0)];
+        assert!(!p.insert_at(1, b"bb", &big));
+        assert!(p.insert_at(1, b"b", &big));
+        assert_eq!(0, p.free_space());
+        assert_eq!(&big[..], p.val(1));
+        assert_eq!(b"d".to_vec(), p.key(2));
+    }
+
+    #[test]
+    fn test_split_point() {
+        let recs: Vec<record_s> = (0..10u8)
+            .map(|i| record_s { k: vec![i], v: vec![0; if i < 5 { 1 } else { 10 }], ovf: false })
+            .collect();
+        let m = split_point(&recs, 1000, usize::MAX, true).unwrap();
+        assert_eq!(6, m);
+        assert_eq!(None, split_point(&recs, 40, usize::MAX, true));
+        assert_eq!(None, split_point(&recs, 1000, 0, true));
+    }
+
+    #[test]
+    fn test_prefix() {
+        let mut buf = vec![0u8; 512];
+        let p = page_s::from_bytes_mut(&mut buf);
+        p.init(PAGE_LEAF);
+        let recs: Vec<record_s> = (0..10)
+            .map(|i| record_s { k: format!("t0001_r{:04}", i * 2).into_bytes(), v: vec![i as u8], ovf: false })
+            .collect();
+        p.rebuild(&recs);
+        assert_eq!(b"t0001_r00", p.prefix());
+        assert_eq!(packed_size(&recs, true), p.used_space());
+        assert_eq!(recs, p.records());
+        assert_eq!((3, false), p.search(b"t0001_r0005"));
+        assert_eq!((10, false), p.search(b"t0001_r1"));
+        assert_eq!((0, false), p.search(b"t0001_r"));
+
+        /* a key sharing the prefix only takes its suffix, another one makes the prefix shorter */
+        assert!(p.insert_at(3, b"t0001_r0005", b"x"));
+        assert_eq!(b"t0001_r00", p.prefix());
+        assert!(p.insert_at(11, b"t0002_r0000", b"x"));
+        assert_eq!(b"t000", p.prefix());
+        assert_eq!(b"t0001_r0005".to_vec(), p.key(3));
+        assert_eq!(b"t0002_r0000".to_vec(), p.key(11));
+        assert_eq!((11, true), p.search(b"t0002_r0000"));
+    }
+
+    #[test]
+    fn test_separator() {
+        let recs: Vec<record_s> = [&b"abc"[..], b"abd", b"abdzz", b"b"].iter()
+            .map(|k| record_s { k: k.to_vec(), v: Vec::new(), ovf: false })
+            .collect();
+        assert_eq!(b"abd".to_vec(), separator(&recs, 1, true));
+        assert_eq!(b"abdz".to_vec(), separator(&recs, 2, true));
+        assert_eq!(b"b".to_vec(), separator(&recs, 3, true));
+        assert_eq!(b"abdzz".to_vec(), separator(&recs, 2, false));
+    }
+
+    #[test]
+    fn test_seal() {
+        let mut buf = vec![0u8; 512];
+        assert!(!page_intact(&buf));
+        let p = page_s::from_bytes_mut(&mut buf);
+        p.init(PAGE_LEAF);
+        assert!(p.insert_at(0, b"k", b"v"));
+        assert!(!page_intact(&buf));
+        seal_page(&mut buf);
+        assert!(page_intact(&buf));
+        /* a flipped bit anywhere, the checksum included, is found */
+        for off in [0, 30, 300, 511].iter() {
+            buf[*off] ^= 0x10;
+            assert!(!page_intact(&buf));
+            buf[*off] ^= 0x10;
+        }
+        assert!(page_intact(&buf));
+    }
+}
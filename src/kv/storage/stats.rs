@@ -0,0 +1,274 @@
+use std::fmt;
+use std::io::Result;
+
+use crate::kv::storage::allocator::{ck_pos, ckid_t};
+use crate::kv::storage::inner::{bitmap_pages, MAX_CHUNK_NUM, PAGE_BITMAP_LEN, PAGE_NUM_USABLE_PER_CK};
+use crate::kv::storage::kvdb::kvdb_s;
+use crate::kv::storage::page::PAGE_OVERFLOW;
+
+/* the leaves are counted by how full they are, in steps of a tenth */
+pub const FILL_STEPS: usize = 10;
+
+/*
+ * stats_s -- how full the file and the tree are. It is printed as "name
+ *            value" lines, as verify_s is, followed by one line for each
+ *            step of the leaf fill and for each chunk:
+ *
+ *                leaf_fill <from>-<to>% <leaves>
+ *                chunk <ck> used <pages> free <pages> holes <pages>
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct stats_s {
+    /* the file, and the pages of the header */
+    pub file_size: u64,
+    pub page_size: usize,
+    pub total_pages: usize,
+    /* the free pages the file holds already, they are taken before it grows */
+    pub spare_pages: usize,
+    /* the tree, the height is the number of its levels */
+    pub height: u32,
+    pub record_num: usize,
+    pub tree_pages: usize,
+    pub leaf_pages: usize,
+    /* the pages of the overflow values, by their lengths */
+    pub ovf_pages: usize,
+    /* leaf_fill[i] are the leaves from i to i + 1 tenths full, the full ones go to the last */
+    pub leaf_fill: [usize; FILL_STEPS],
+    /* the pages the cache found, and the ones it read from the file */
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub chunks: Vec<chunk_stat_s>,
+}
+
+/* chunk_stat_s -- the pages of a chunk, the ones of its bitmap are left out */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct chunk_stat_s {
+    pub ck: ckid_t,
+    pub used: usize,
+    pub free: usize,
+    /* the free pages below the last used one, which vacuum() can give back */
+    pub holes: usize,
+}
+
+impl stats_s {
+    /* cache_hit_ratio() -- the part of the pages found in the cache, 0 if none is looked for */
+    pub fn cache_hit_ratio(&self) -> f64 {
+        let n = self.cache_hits + self.cache_misses;
+        if n == 0 { 0.0 } else { self.cache_hits as f64 / n as f64 }
+    }
+    /* used_pages() -- the pages taken in all the chunks */
+    pub fn used_pages(&self) -> usize {
+        self.chunks.iter().map(|c| c.used).sum()
+    }
+}
+
+impl fmt::Display for stats_s {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "file_size {}", self.file_size)?;
+        writeln!(f, "page_size {}", self.page_size)?;
+        writeln!(f, "total_pages {}", self.total_pages)?;
+        writeln!(f, "spare_pages {}", self.spare_pages)?;
+        writeln!(f, "used_pages {}", self.used_pages())?;
+        writeln!(f, "height {}", self.height)?;
+        writeln!(f, "record_num {}", self.record_num)?;
+        writeln!(f, "tree_pages {}", self.tree_pages)?;
+        writeln!(f, "leaf_pages {}", self.leaf_pages)?;
+        writeln!(f, "ovf_pages {}", self.ovf_pages)?;
+        writeln!(f, "cache_hits {}", self.cache_hits)?;
+        writeln!(f, "cache_misses {}", self.cache_misses)?;
+        writeln!(f, "cache_hit_ratio {:.3}", self.cache_hit_ratio())?;
+        for (i, n) in self.leaf_fill.iter().enumerate() {
+            writeln!(f, "leaf_fill {}-{}% {}", i * 100 / FILL_STEPS, (i + 1) * 100 / FILL_STEPS, n)?;
+        }
+        for c in self.chunks.iter() {
+            writeln!(f, "chunk {} used {} free {} holes {}", c.ck, c.used, c.free, c.holes)?;
+        }
+        Ok(())
+    }
+}
+
+impl kvdb_s {
+    /*
+     * stats() -- walk the tree from the root for the pages and the fill of
+     *            the leaves, and the busy page numbers and the bitmaps for
+     *            the pages of each chunk.
+     *            The overflow pages are counted from the lengths of the
+     *            values, their chains are not followed. The operations go
+     *            on meanwhile, a page is only latched while it is read, so
+     *            the numbers of a tree being modified are close but not
+     *            exact. A page freed and taken for another kind since its
+     *            parent was read is left out.
+     */
+    pub fn stats(&self) -> Result<stats_s> {
+        let mut s = stats_s { page_size: self.pg_size, ..Default::default() };
+        {
+            let h = self.hd();
+            s.file_size = h.file_size.get();
+            s.total_pages = h.total_pages.get() as usize;
+            s.record_num = h.record_num.get() as usize;
+        }
+        let (mut level, root) = self.root();
+        s.height = level;
+        let mut gpids = if level > 0 { vec![root] } else { Vec::new() };
+        while !gpids.is_empty() {
+            let mut children = Vec::new();
+            for gpid in gpids {
+                /* a page cut off the file by vacuum() is not read */
+                if (kvdb_s::get_page_pos(gpid, self.pg_size) + self.pg_size) as u64 > self.hd().file_size.get() {
+                    continue;
+                }
+                let p = self.get_page(gpid)?;
+                if p.flags() & PAGE_OVERFLOW != 0 || p.is_leaf() != (level == 1) {
+                    self.put_page(p);
+                    continue;
+                }
+                s.tree_pages += 1;
+                if p.is_leaf() {
+                    s.leaf_pages += 1;
+                    let step = p.used_space() * FILL_STEPS / p.capacity();
+                    s.leaf_fill[step.min(FILL_STEPS - 1)] += 1;
+                    for i in (0..p.record_num()).filter(|i| p.is_overflow(*i)) {
+                        s.ovf_pages += self.ovf_pages(p.record(i).ovf_ptr().1);
+                    }
+                } else {
+                    children.extend((0..p.record_num()).map(|i| p.child(i)));
+                }
+                self.put_page(p);
+            }
+            gpids = children;
+            level -= 1;
+        }
+        let (hits, misses) = self.ch.hits();
+        s.cache_hits = hits;
+        s.cache_misses = misses;
+        self.chunk_stats(&mut s)?;
+        Ok(s)
+    }
+
+    /*
+     * chunk_stats() -- the pages of the chunks whose bitmaps are in the file,
+     *                  as verify_alc() walks them. The allocator is locked
+     *                  for one chunk at a time.
+     */
+    fn chunk_stats(&self, s: &mut stats_s) -> Result<()> {
+        let file_size = s.file_size;
+        let head = bitmap_pages(self.pg_size);
+        for ck in (0..MAX_CHUNK_NUM).take_while(|ck| ((ck_pos(*ck, self.pg_size) + PAGE_BITMAP_LEN) as u64) <= file_size) {
+            let alc = self.alc.lock().unwrap();
+            /* vacuum() may have cut the file since the walk started */
+            if ((ck_pos(ck, self.pg_size) + PAGE_BITMAP_LEN) as u64) > self.hd().file_size.get() {
+                break;
+            }
+            let end = alc.peek_pb(&self.file, ck, |pb| {
+                pb.w.iter().rposition(|w| w.get() != 0).map_or(0, |i| (i << 6) + 64 - pb.w[i].get().leading_zeros() as usize)
+            })?;
+            /* a chunk which is never opened has no busy pages, not even the ones of its bitmap */
+            let used = (alc.bpn.n[ck].get() as usize).saturating_sub(head);
+            let in_file = ((file_size as usize - ck_pos(ck, self.pg_size)) / self.pg_size).min(PAGE_NUM_USABLE_PER_CK);
+            s.spare_pages += in_file.saturating_sub(head + used);
+            s.chunks.push(chunk_stat_s {
+                ck,
+                used,
+                free: PAGE_NUM_USABLE_PER_CK.saturating_sub(head + used),
+                holes: end.saturating_sub(head + used),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use crate::kv::storage::allocator::ck_pos;
+    use crate::kv::storage::inner::bitmap_pages;
+    use crate::kv::storage::kvdb::{kvdb_s, options_s};
+    use crate::kv::storage::stats::FILL_STEPS;
+
+    #[test]
+    fn test_stats() {
+        let path = std::env::temp_dir().join(format!("lycee-stats-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let ps = 1024;
+        let opts = options_s { page_size: ps, cache_size: 64 * ps, ..Default::default() };
+        let db = kvdb_s::open_with(&path, &opts).unwrap();
+        let s = db.stats().unwrap();
+        assert_eq!((0, 0, 0, 0), (s.height, s.record_num, s.tree_pages, s.used_pages()));
+
+        for i in 0..3000u64 {
+            let v = if i % 100 == 0 { vec![i as u8; 5000] } else { i.to_string().into_bytes() };
+            db.put(&i.to_be_bytes(), &v).unwrap();
+        }
+        for i in (0..3000u64).filter(|i| i % 4 != 0) {
+            db.del(&i.to_be_bytes()).unwrap();
+        }
+        let s = db.stats().unwrap();
+        let v = db.verify().unwrap();
+        assert_eq!((v.level, v.record_num, v.tree_pages, v.leaf_pages, v.ovf_pages),
+                   (s.height, s.record_num, s.tree_pages, s.leaf_pages, s.ovf_pages));
+        assert!(s.height > 1 && s.ovf_pages == 30 * db.ovf_pages(5000));
+        assert_eq!(s.leaf_pages, s.leaf_fill.iter().sum::<usize>());
+        /* the deletes leave most of the leaves below half full, but not below a quarter */
+        assert_eq!(0, s.leaf_fill[..FILL_STEPS / 4].iter().sum::<usize>());
+        assert_eq!((s.total_pages, s.total_pages), (s.used_pages(), s.tree_pages + s.ovf_pages));
+        let c = &s.chunks[0];
+        assert!(c.holes > 0 && c.holes < c.free);
+        let head = bitmap_pages(ps);
+        assert_eq!((s.file_size as usize - ck_pos(0, ps)) / ps - head - c.used, s.spare_pages);
+        assert!(s.spare_pages >= c.holes);
+        assert!(s.cache_hits > 0 && s.cache_misses > 0 && s.cache_hit_ratio() > 0.5);
+
+        let out = s.to_string();
+        assert!(out.starts_with(&format!("file_size {}\n", s.file_size)));
+        assert!(out.contains(&format!("\nchunk 0 used {} free {} holes {}\n", c.used, c.free, c.holes)));
+        assert!(out.contains("\nleaf_fill 90-100% "));
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_stats_threads() {
+        let path = std::env::temp_dir().join(format!("lycee-stats-threads-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let ps = 1024;
+        let opts = options_s { page_size: ps, cache_size: 64 * ps, ..Default::default() };
+        let db = Arc::new(kvdb_s::open_with(&path, &opts).unwrap());
+        for i in 0..2000u64 {
+            db.put(&i.to_be_bytes(), i.to_string().as_bytes()).unwrap();
+        }
+        /* the writers split and merge the leaves while stats() walks them */
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (db, stop) = (db.clone(), stop.clone());
+            thread::spawn(move || {
+                let mut n = 0u64;
+                while !stop.load(Ordering::Relaxed) {
+                    let k = (n * 7919 % 4000).to_be_bytes();
+                    if n.is_multiple_of(3) {
+                        let _ = db.del(&k);
+                    } else {
+                        db.put(&k, &[n as u8; 100]).unwrap();
+                    }
+                    n += 1;
+                }
+            })
+        };
+        for _ in 0..20 {
+            let s = db.stats().unwrap();
+            assert!(s.height > 1 && s.leaf_pages > 0 && s.leaf_pages < s.tree_pages);
+            assert_eq!(s.leaf_pages, s.leaf_fill.iter().sum::<usize>());
+        }
+        stop.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
+        let s = db.stats().unwrap();
+        let v = db.verify().unwrap();
+        assert_eq!((v.level, v.record_num, v.tree_pages, v.leaf_pages),
+                   (s.height, s.record_num, s.tree_pages, s.leaf_pages));
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,410 @@
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+
+use crate::kv::storage::allocator::{ck_pos, ckid_t, lpid_t};
+use crate::kv::storage::inner::{bitmap_pages, BUSY_PAGE_NUM_POS, busy_page_num_s, GPID_NIL, gpid_t, kvdb_assert, MAX_CHUNK_NUM, PAGE_BITMAP_LEN, page_bitmap_s};
+use crate::kv::storage::kvdb::kvdb_s;
+
+/* vacuum_s -- what vacuum() does to a database, it is printed as "name value" lines, as verify_s is */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct vacuum_s {
+    /* the pages moved to lower ones */
+    pub moved_pages: usize,
+    /* the length of the file before and after */
+    pub old_size: u64,
+    pub new_size: u64,
+    /* the bytes the file takes on the disk before and after */
+    pub old_usage: u64,
+    pub new_usage: u64,
+    /* the free chunks whose pages are punched out of the file */
+    pub punched_chunks: usize,
+}
+
+impl vacuum_s {
+    /* reclaimed() -- the bytes given back to the file system */
+    pub fn reclaimed(&self) -> u64 {
+        self.old_usage.saturating_sub(self.new_usage)
+    }
+}
+
+impl fmt::Display for vacuum_s {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "moved_pages {}", self.moved_pages)?;
+        writeln!(f, "old_size {}", self.old_size)?;
+        writeln!(f, "new_size {}", self.new_size)?;
+        writeln!(f, "punched_chunks {}", self.punched_chunks)?;
+        writeln!(f, "reclaimed {}", self.reclaimed())
+    }
+}
+
+/* ref_t -- where the pointer to a page is kept */
+#[derive(Debug, Clone, Copy)]
+enum ref_t {
+    Root,
+    /* record i of an internal page */
+    Child(gpid_t, usize),
+    /* the overflow pointer of record i of a leaf */
+    Ovf(gpid_t, usize),
+    /* the next of the page before it in an overflow chain */
+    Next(gpid_t),
+}
+
+impl kvdb_s {
+    /*
+     * vacuum() -- move the pages of the tree to the lowest free pages, cut
+     *             the free pages off the end of the file, and punch out the
+     *             free chunks below it. The tree lock is held alone while
+     *             the pages are moved, so the operations under way only go
+     *             down from the pages they hold, and none of them gets to a
+     *             page again once it is latched here. Each move is then one
+     *             operation of the page, its parent and the leaf before it.
+     *             The file is cut after a checkpoint, which has everything
+     *             before in the file.
+     */
+    pub fn vacuum(&self) -> Result<vacuum_s> {
+        let mut v = vacuum_s { old_size: self.hd().file_size.get(), old_usage: self.file.disk_usage()?, ..Default::default() };
+        {
+            let _t = self.tree.write().unwrap();
+            let (level, root) = self.root();
+            if level > 0 {
+                let mut prev_leaf = GPID_NIL;
+                self.vac_page(&mut v, root, ref_t::Root, &mut prev_leaf)?;
+            }
+        }
+        /* the new pages come from the lowest chunk which has free ones, and not from the end of the file */
+        {
+            let mut alc = self.alc.lock().unwrap();
+            let ck = alc.find_ck();
+            if ck < alc.curr_ck {
+                self.turn_ck(&mut alc, ck)?;
+            }
+        }
+        self.sync()?;
+        self.vac_file(&mut v)?;
+        v.new_size = self.hd().file_size.get();
+        v.new_usage = self.file.disk_usage()?;
+        Ok(v)
+    }
+
+    /* vac_page() -- move the page gpid pointed to by r, and the pages under it, in the order of their keys */
+    fn vac_page(&self, v: &mut vacuum_s, gpid: gpid_t, r: ref_t, prev_leaf: &mut gpid_t) -> Result<()> {
+        let gpid = self.vac_move(v, gpid, r, *prev_leaf)?;
+        let p = self.get_page(gpid)?;
+        if !p.is_leaf() {
+            let children: Vec<gpid_t> = (0..p.record_num()).map(|i| p.child(i)).collect();
+            self.put_page(p);
+            for (i, child) in children.into_iter().enumerate() {
+                self.vac_page(v, child, ref_t::Child(gpid, i), prev_leaf)?;
+            }
+            return Ok(());
+        }
+        let ovfs: Vec<(usize, gpid_t)> = (0..p.record_num())
+            .filter(|i| p.is_overflow(*i))
+            .map(|i| (i, p.record(i).ovf_ptr().0))
+            .collect();
+        self.put_page(p);
+        *prev_leaf = gpid;
+        for (i, mut next) in ovfs {
+            let mut r = ref_t::Ovf(gpid, i);
+            while next != GPID_NIL {
+                let g = self.vac_move(v, next, r, GPID_NIL)?;
+                let p = self.get_page(g)?;
+                next = p.next();
+                self.put_page(p);
+                r = ref_t::Next(g);
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * vac_move() -- move the page gpid to the lowest free page below it, if
+     *               there is one, and return where it is. The pointer r to
+     *               it is changed, and so is the next of prev_leaf if it is a
+     *               leaf, in the same operation. If one of them does not
+     *               point to gpid, the move is undone and is an error.
+     */
+    fn vac_move(&self, v: &mut vacuum_s, gpid: gpid_t, r: ref_t, prev_leaf: gpid_t) -> Result<gpid_t> {
+        let n = self.mtr(|| {
+            let n = match self.alloc_page_below(gpid)? {
+                Some(n) => n,
+                None => return Ok(gpid),
+            };
+            /* the page with the pointer is latched first, as on the way down, so none gets to the old page after it is copied */
+            let mut x = match r {
+                ref_t::Root => None,
+                ref_t::Child(x, _) | ref_t::Ovf(x, _) | ref_t::Next(x) => Some(self.get_page_mut(x)?),
+            };
+            let old = self.get_page_mut(gpid)?;
+            let leaf = old.is_leaf();
+            let mut p = self.new_page(n)?;
+            p.copy_from(&old);
+            self.put_page(p);
+            match (r, x.as_mut()) {
+                (ref_t::Root, _) => {
+                    let level = self.root().0;
+                    self.set_root(n, level)?;
+                }
+                (ref_t::Child(_, i), Some(x)) => {
+                    if x.child(i) != gpid {
+                        return Err(lost_ref(x.gpid(), gpid));
+                    }
+                    x.set_child(i, n);
+                }
+                (ref_t::Ovf(_, i), Some(x)) => {
+                    if x.record(i).ovf_ptr().0 != gpid {
+                        return Err(lost_ref(x.gpid(), gpid));
+                    }
+                    x.set_ovf_gpid(i, n);
+                }
+                (ref_t::Next(_), Some(x)) => {
+                    if x.next() != gpid {
+                        return Err(lost_ref(x.gpid(), gpid));
+                    }
+                    x.set_next(n);
+                }
+                _ => unreachable!(),
+            }
+            if let Some(x) = x {
+                self.put_page(x);
+            }
+            self.put_page(old);
+            if leaf && prev_leaf != GPID_NIL {
+                let mut l = self.get_page_mut(prev_leaf)?;
+                if l.next() != gpid {
+                    return Err(lost_ref(prev_leaf, gpid));
+                }
+                l.set_next(n);
+            }
+            self.free_page(gpid)?;
+            Ok(n)
+        })?;
+        if n != gpid {
+            v.moved_pages += 1;
+        }
+        Ok(n)
+    }
+
+    /*
+     * vac_file() -- cut the file after the last busy page, and punch out the
+     *               pages of the free chunks below it. The allocator is held
+     *               meanwhile, so that none of them is taken. The pages cut
+     *               off may have been dirtied and freed since the sync, they
+     *               are dropped from the cache first, or their write-back
+     *               would make the file long again. A chunk cut off is
+     *               opened as a new one when the file grows again.
+     */
+    fn vac_file(&self, v: &mut vacuum_s) -> Result<()> {
+        let alc = self.alc.lock().unwrap();
+        /* the file grows only under the allocator, the header is not held while the writes in flight are waited for */
+        let file_size = self.hd().file_size.get();
+        let ps = self.pg_size;
+        let head = bitmap_pages(ps);
+        let cks = (0..MAX_CHUNK_NUM).take_while(|ck| ((ck_pos(*ck, ps) + PAGE_BITMAP_LEN) as u64) <= file_size).count();
+        kvdb_assert(alc.curr_ck != ckid_t::MAX);
+        /* the last chunk with a busy page stays, and so does the current one */
+        let last = (0..cks).rev().find(|ck| alc.bpn.n[*ck].get() as usize > head).unwrap_or(0).max(alc.curr_ck);
+        let end = alc.peek_pb(&self.file, last, busy_end)?;
+        /* the busy page numbers are mapped, the file never ends before them */
+        let meta_end = (BUSY_PAGE_NUM_POS + mem::size_of::<busy_page_num_s>()) as u64;
+        let size = (kvdb_s::get_page_pos(kvdb_s::get_gpid(last, end), ps) as u64).max(meta_end);
+        if size < file_size {
+            self.ch.discard(kvdb_s::get_gpid(last, end));
+            self.file.set_len(size)?;
+            self.hd().file_size.set(size);
+        }
+        for ck in (0..last).filter(|ck| alc.bpn.n[*ck].get() as usize == head) {
+            let pos = kvdb_s::get_page_pos(kvdb_s::get_gpid(ck, head), ps);
+            if !self.file.punch_hole(pos as u64, (ck_pos(ck + 1, ps) - pos) as u64)? {
+                break;
+            }
+            v.punched_chunks += 1;
+        }
+        Ok(())
+    }
+}
+
+/* lost_ref() -- the error of the page x which should point to gpid, but does not */
+fn lost_ref(x: gpid_t, gpid: gpid_t) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("page {} does not point to page {}", x, gpid))
+}
+
+/* busy_end() -- the pages of a chunk up to its last busy one */
+fn busy_end(pb: &page_bitmap_s) -> lpid_t {
+    match pb.w.iter().rposition(|w| w.get() != 0) {
+        Some(i) => (i << 6) + 64 - pb.w[i].get().leading_zeros() as usize,
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::fs;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    use crate::kv::storage::allocator::ckid_t;
+    use crate::kv::storage::inner::bitmap_pages;
+    use crate::kv::storage::kvdb::{kvdb_s, options_s};
+
+    /* take the free pages of the current chunk without logging it, the bits taken are returned */
+    fn fill_curr_ck(db: &kvdb_s) -> (ckid_t, Vec<u64>) {
+        let mut alc = db.alc.lock().unwrap();
+        let ck = alc.curr_ck;
+        let pb = alc.pb(&db.file, ck).unwrap();
+        let mask: Vec<u64> = pb.w.iter().map(|w| !w.get()).collect();
+        for w in pb.w.iter_mut() {
+            w.set(u64::MAX);
+        }
+        let n = alc.bpn.n[ck].get();
+        alc.bpn.n[ck].set(n + mask.iter().map(|m| m.count_ones()).sum::<u32>());
+        (ck, mask)
+    }
+
+    /* give the bits taken by fill_curr_ck() back */
+    fn unfill_ck(db: &kvdb_s, ck: ckid_t, mask: &[u64]) {
+        let mut alc = db.alc.lock().unwrap();
+        let pb = alc.pb(&db.file, ck).unwrap();
+        for (w, m) in pb.w.iter_mut().zip(mask) {
+            w.set(w.get() & !*m);
+        }
+        let n = alc.bpn.n[ck].get();
+        alc.bpn.n[ck].set(n - mask.iter().map(|m| m.count_ones()).sum::<u32>());
+        alc.low_ck = alc.low_ck.min(ck);
+    }
+
+    fn val(i: u64) -> Vec<u8> {
+        if i.is_multiple_of(10) { vec![i as u8; 1500] } else { i.to_string().into_bytes() }
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let path = std::env::temp_dir().join(format!("lycee-vacuum-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let ps = 512;
+        let opts = options_s { page_size: ps, cache_size: 64 * ps, ..Default::default() };
+        let db = kvdb_s::open_with(&path, &opts).unwrap();
+        let head = bitmap_pages(ps);
+        let put = |r: std::ops::Range<u64>| r.for_each(|i| db.put(&i.to_be_bytes(), &val(i)).unwrap());
+        let del = |r: std::ops::Range<u64>| r.for_each(|i| db.del(&i.to_be_bytes()).unwrap());
+
+        /* three chunks of records, the first two are filled up in between, and a page of the last is lost */
+        put(0..2000);
+        let (ck0, mask0) = fill_curr_ck(&db);
+        put(2000..4000);
+        let (ck1, mask1) = fill_curr_ck(&db);
+        put(4000..5000);
+        assert_eq!((0, 1, 2), (ck0, ck1, db.alc.lock().unwrap().curr_ck));
+        let lost = db.mtr(|| db.alloc_page()).unwrap();
+        assert_eq!(2, kvdb_s::get_ck(lost));
+
+        /* the records of the second chunk are gone, the pages left there and in the last chunk go to the first one */
+        del(2000..4000);
+        del(0..1000);
+        unfill_ck(&db, ck0, &mask0);
+        unfill_ck(&db, ck1, &mask1);
+        let v = db.vacuum().unwrap();
+        assert!(v.moved_pages > 0, "{}", v);
+        assert_eq!(1, v.punched_chunks);
+        assert_eq!((kvdb_s::get_page_pos(lost, ps) + ps) as u64, v.new_size);
+        assert_eq!(v.new_size, db.file.len().unwrap());
+        assert!(v.new_size < v.old_size && v.reclaimed() > 0);
+        {
+            let alc = db.alc.lock().unwrap();
+            assert_eq!((0, head, head + 1), (alc.curr_ck, alc.bpn.n[1].get() as usize, alc.bpn.n[2].get() as usize));
+        }
+        let check = |db: &kvdb_s| {
+            let v = db.verify().unwrap();
+            let problems: Vec<(&str, _)> = v.problems.iter().map(|p| (p.kind, p.gpid)).collect();
+            assert_eq!(vec![("leak", lost)], problems);
+            assert_eq!(2000, v.records);
+            for i in (1000..2000).chain(4000..5000) {
+                assert_eq!(val(i), db.get(&i.to_be_bytes()).unwrap());
+            }
+        };
+        check(&db);
+
+        /* the new pages come from the first chunk */
+        let n = db.alc.lock().unwrap().bpn.n[0].get();
+        put(5000..5100);
+        assert!(db.alc.lock().unwrap().bpn.n[0].get() > n);
+        del(5000..5100);
+        drop(db);
+        let db = kvdb_s::open_with(&path, &opts).unwrap();
+        check(&db);
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_vacuum_threads() {
+        let path = std::env::temp_dir().join(format!("lycee-vacuum-threads-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let ps = 512;
+        let opts = options_s { page_size: ps, cache_size: 64 * ps, ..Default::default() };
+        let db = Arc::new(kvdb_s::open_with(&path, &opts).unwrap());
+        for i in 0..4000u64 {
+            db.put(&i.to_be_bytes(), &val(i)).unwrap();
+        }
+        for i in 0..2000u64 {
+            db.del(&i.to_be_bytes()).unwrap();
+        }
+
+        /* readers, cursors and writers go on while the pages are moved under them, and the file is cut */
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut threads = Vec::new();
+        for t in 0..6u64 {
+            let (db, stop) = (db.clone(), stop.clone());
+            threads.push(thread::spawn(move || {
+                let mut rounds = 0;
+                while rounds == 0 || !stop.load(Ordering::Relaxed) {
+                    if t >= 4 {
+                        /* each round leaves the odd keys of its range */
+                        let r = 4000 + (t - 4) * 1000..5000 + (t - 4) * 1000;
+                        r.clone().for_each(|i| db.put(&i.to_be_bytes(), &val(i)).unwrap());
+                        r.step_by(2).for_each(|i| db.del(&i.to_be_bytes()).unwrap());
+                    } else if t % 2 == 0 {
+                        for i in (2000 + t..4000).step_by(7) {
+                            assert_eq!(val(i), db.get(&i.to_be_bytes()).unwrap());
+                        }
+                    } else {
+                        let keys: Vec<u64> = db.iter(&[], None).unwrap()
+                            .map(|(k, v)| {
+                                let i = u64::from_be_bytes(k[..].try_into().unwrap());
+                                assert_eq!(val(i), v);
+                                i
+                            })
+                            .filter(|i| *i < 4000)
+                            .collect();
+                        assert_eq!((2000..4000).collect::<Vec<u64>>(), keys);
+                    }
+                    rounds += 1;
+                }
+            }));
+        }
+        let v = db.vacuum().unwrap();
+        stop.store(true, Ordering::Relaxed);
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert!(v.moved_pages > 0, "{}", v);
+        assert!(db.file.len().unwrap() <= db.hd().file_size.get());
+        drop(db);
+
+        let db = kvdb_s::open_with(&path, &opts).unwrap();
+        let r = db.verify().unwrap();
+        assert!(r.problems.is_empty(), "{:?}", r.problems);
+        assert_eq!(3000, r.records);
+        for i in 0..6000u64 {
+            match db.get(&i.to_be_bytes()) {
+                Ok(v) => assert!(((2000..4000).contains(&i) || (i >= 4000 && i % 2 == 1)) && v == val(i), "{}", i),
+                Err(_) => assert!(i < 2000 || (i >= 4000 && i.is_multiple_of(2)), "{}", i),
+            }
+        }
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+}
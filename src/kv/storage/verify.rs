@@ -0,0 +1,315 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::Result;
+
+use crate::kv::storage::allocator::{ck_pos, ckid_t};
+use crate::kv::storage::inner::{bitmap_pages, GPID_NIL, gpid_t, MAX_CHUNK_NUM, PAGE_BITMAP_LEN};
+use crate::kv::storage::kvdb::kvdb_s;
+use crate::kv::storage::page::{PAGE_OVERFLOW, record_s};
+
+/*
+ * verify_s -- what verify() finds in a database. It is printed as a report
+ *             of "name value" lines, followed by one line for each problem:
+ *
+ *                 problem <kind> <gpid> <detail>
+ *
+ *             where gpid is "-" for the header, and kind is one of:
+ *
+ *                 read       the page can not be read, or its checksum does not match
+ *                 level      a leaf is not at the lowest level, or an internal page is
+ *                 order      the keys of a page are not in order
+ *                 bound      a key is out of the range its parent gives the page
+ *                 chain      the next leaf of a leaf is not the one by the tree
+ *                 overflow   the chain of an overflow value is broken
+ *                 record_num the records in the leaves are not the number of the header
+ *                 double     the page is reached more than once
+ *                 free       the page is reached, but it is not busy in the bitmap
+ *                 leak       the page is busy in the bitmap, but it is not reached
+ *                 busy_num   the busy pages of a chunk are not its busy page number
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct verify_s {
+    /* the levels of the tree, and the records in the header */
+    pub level: u32,
+    pub record_num: usize,
+    /* the pages reached from the root, and the records in the leaves */
+    pub tree_pages: usize,
+    pub leaf_pages: usize,
+    pub ovf_pages: usize,
+    pub records: usize,
+    /* the pages busy in the bitmaps, but the ones of the bitmaps */
+    pub busy_pages: usize,
+    pub problems: Vec<problem_s>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct problem_s {
+    pub kind: &'static str,
+    pub gpid: gpid_t,
+    pub detail: String,
+}
+
+impl verify_s {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+    fn problem(&mut self, kind: &'static str, gpid: gpid_t, detail: String) {
+        self.problems.push(problem_s { kind, gpid, detail });
+    }
+}
+
+impl fmt::Display for verify_s {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "status {}", if self.is_ok() { "ok" } else { "corrupt" })?;
+        writeln!(f, "level {}", self.level)?;
+        writeln!(f, "record_num {}", self.record_num)?;
+        writeln!(f, "records {}", self.records)?;
+        writeln!(f, "tree_pages {}", self.tree_pages)?;
+        writeln!(f, "leaf_pages {}", self.leaf_pages)?;
+        writeln!(f, "ovf_pages {}", self.ovf_pages)?;
+        writeln!(f, "busy_pages {}", self.busy_pages)?;
+        writeln!(f, "problems {}", self.problems.len())?;
+        for p in self.problems.iter() {
+            writeln!(f, "problem {} {} {}", p.kind, fmt_gpid(p.gpid), p.detail)?;
+        }
+        Ok(())
+    }
+}
+
+fn fmt_gpid(gpid: gpid_t) -> String {
+    if gpid == GPID_NIL { "-".to_string() } else { gpid.to_string() }
+}
+
+/* a page to visit, with the range of keys its parent gives it, the upper bound is exclusive */
+struct node_s {
+    gpid: gpid_t,
+    lo: Option<Vec<u8>>,
+    hi: Option<Vec<u8>>,
+}
+
+impl kvdb_s {
+    /*
+     * verify() -- walk the tree from the root level by level, and check it
+     *             against the header and the allocator. Only what can not be
+     *             read at all is an error, the rest is in the problems.
+     *             It is an offline check: the whole tree must stay as it is,
+     *             as for level_stats(), so the tree lock is held for the
+     *             whole walk, and the gets, puts, dels and cursors started
+     *             meanwhile wait for it. Use stats() on a db in service.
+     */
+    pub fn verify(&self) -> Result<verify_s> {
+        let _t = self.tree.write().unwrap();
+        let mut v = verify_s::default();
+        let (level, root) = self.root();
+        v.level = level;
+        v.record_num = self.hd().record_num.get() as usize;
+        let mut reached = BTreeSet::new();
+        let mut leaves = Vec::new();
+        let mut nodes = if level > 0 { vec![node_s { gpid: root, lo: None, hi: None }] } else { Vec::new() };
+        let mut depth = 0;
+        while !nodes.is_empty() {
+            depth += 1;
+            let mut children = Vec::new();
+            for nd in nodes {
+                if !reach(&mut v, &mut reached, nd.gpid) {
+                    continue;
+                }
+                let p = match self.get_page(nd.gpid) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        v.problem("read", nd.gpid, e.to_string());
+                        continue;
+                    }
+                };
+                v.tree_pages += 1;
+                let n = p.record_num();
+                if p.is_leaf() != (depth == level) {
+                    v.problem("level", nd.gpid, format!("{} at level {} of {}",
+                                                        if p.is_leaf() { "a leaf" } else { "an internal page" }, level + 1 - depth, level));
+                    self.put_page(p);
+                    continue;
+                }
+                /* the first key of an internal page is never used */
+                let first = if p.is_leaf() { 0 } else { 1 };
+                let mut last: Option<Vec<u8>> = None;
+                for i in first..n {
+                    let k = p.key(i);
+                    if last.as_ref().is_some_and(|l| *l >= k) {
+                        v.problem("order", nd.gpid, format!("key {} is not greater than key {}", i, i - 1));
+                    }
+                    if nd.lo.as_ref().is_some_and(|lo| k < *lo) || nd.hi.as_ref().is_some_and(|hi| k >= *hi) {
+                        v.problem("bound", nd.gpid, format!("key {} is out of the range of the page", i));
+                    }
+                    last = Some(k);
+                }
+                if p.is_leaf() {
+                    v.leaf_pages += 1;
+                    v.records += n;
+                    leaves.push((nd.gpid, p.next()));
+                    for i in (0..n).filter(|i| p.is_overflow(*i)) {
+                        self.verify_ovf(&mut v, &mut reached, nd.gpid, &p.record(i));
+                    }
+                } else {
+                    for i in 0..n {
+                        children.push(node_s {
+                            gpid: p.child(i),
+                            lo: if i == 0 { nd.lo.clone() } else { Some(p.key(i)) },
+                            hi: if i + 1 < n { Some(p.key(i + 1)) } else { nd.hi.clone() },
+                        });
+                    }
+                }
+                self.put_page(p);
+            }
+            nodes = children;
+        }
+        /* the leaves are found in the order of their keys */
+        for (i, (gpid, next)) in leaves.iter().enumerate() {
+            let want = leaves.get(i + 1).map_or(GPID_NIL, |l| l.0);
+            if *next != want {
+                v.problem("chain", *gpid, format!("the next leaf is {}, not {}", fmt_gpid(*next), fmt_gpid(want)));
+            }
+        }
+        if v.records != v.record_num {
+            let detail = format!("{} records in the leaves, {} in the header", v.records, v.record_num);
+            v.problem("record_num", GPID_NIL, detail);
+        }
+        self.verify_alc(&mut v, &reached)?;
+        Ok(v)
+    }
+
+    /* verify_ovf() -- follow the overflow chain of the record rec in the leaf */
+    fn verify_ovf(&self, v: &mut verify_s, reached: &mut BTreeSet<gpid_t>, leaf: gpid_t, rec: &record_s) {
+        let (mut gpid, len) = rec.ovf_ptr();
+        let want = self.ovf_pages(len);
+        let mut n = 0;
+        while gpid != GPID_NIL && n < want {
+            if !reach(v, reached, gpid) {
+                return;
+            }
+            let p = match self.get_page(gpid) {
+                Ok(p) => p,
+                Err(e) => return v.problem("read", gpid, e.to_string()),
+            };
+            if p.flags() & PAGE_OVERFLOW == 0 {
+                self.put_page(p);
+                return v.problem("overflow", gpid, "it is not an overflow page".to_string());
+            }
+            v.ovf_pages += 1;
+            n += 1;
+            gpid = p.next();
+            self.put_page(p);
+        }
+        if n < want || gpid != GPID_NIL {
+            v.problem("overflow", leaf, format!("the value of {} bytes has {} pages, not {}",
+                                                len, if n < want { n.to_string() } else { "more".to_string() }, want));
+        }
+    }
+
+    /* verify_alc() -- the pages reached should be the busy ones of the bitmaps, which should be the busy page numbers */
+    fn verify_alc(&self, v: &mut verify_s, reached: &BTreeSet<gpid_t>) -> Result<()> {
+        let file_size = self.hd().file_size.get();
+        let head = bitmap_pages(self.pg_size);
+        let alc = self.alc.lock().unwrap();
+        let mut busy: BTreeMap<ckid_t, Vec<u64>> = BTreeMap::new();
+        /* the file may grow into a chunk which is never opened, but not over the whole bitmap of it */
+        for ck in (0..MAX_CHUNK_NUM).take_while(|ck| ((ck_pos(*ck, self.pg_size) + PAGE_BITMAP_LEN) as u64) <= file_size) {
+            let w = match alc.peek_pb(&self.file, ck, |pb| pb.w.iter().map(|w| w.get()).collect::<Vec<u64>>()) {
+                Ok(w) => w,
+                Err(e) => {
+                    v.problem("read", kvdb_s::get_gpid(ck, 0), e.to_string());
+                    continue;
+                }
+            };
+            let n: usize = w.iter().map(|w| w.count_ones() as usize).sum();
+            if n != alc.bpn.n[ck].get() as usize {
+                v.problem("busy_num", kvdb_s::get_gpid(ck, 0),
+                          format!("{} pages are busy in the bitmap, {} by the busy page number", n, alc.bpn.n[ck].get()));
+            }
+            for (i, mut word) in w.iter().copied().enumerate() {
+                while word != 0 {
+                    let lpid = i * 64 + word.trailing_zeros() as usize;
+                    word &= word - 1;
+                    if lpid < head {
+                        continue;
+                    }
+                    v.busy_pages += 1;
+                    let gpid = kvdb_s::get_gpid(ck, lpid);
+                    if !reached.contains(&gpid) {
+                        v.problem("leak", gpid, "it is busy but not reached".to_string());
+                    }
+                }
+            }
+            busy.insert(ck, w);
+        }
+        for gpid in reached.iter() {
+            let (ck, lpid) = (kvdb_s::get_ck(*gpid), kvdb_s::get_lpid(*gpid));
+            let set = busy.get(&ck).is_some_and(|w| lpid >= head && lpid < w.len() * 64 && w[lpid >> 6] & (1 << (lpid & 63)) != 0);
+            if !set {
+                v.problem("free", *gpid, "it is reached but not busy".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/* reach() -- note that the page gpid is reached, false if it was already */
+fn reach(v: &mut verify_s, reached: &mut BTreeSet<gpid_t>, gpid: gpid_t) -> bool {
+    if !reached.insert(gpid) {
+        v.problem("double", gpid, "it is reached more than once".to_string());
+        return false;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::kv::storage::inner::GPID_NIL;
+    use crate::kv::storage::kvdb::kvdb_s;
+
+    #[test]
+    fn test_verify() {
+        let path = std::env::temp_dir().join(format!("lycee-verify-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let db = kvdb_s::open(&path).unwrap();
+        for i in 0..3000u64 {
+            let v = if i % 100 == 0 { vec![i as u8; 10000] } else { i.to_string().into_bytes() };
+            db.put(&i.to_be_bytes(), &v).unwrap();
+        }
+        for i in (0..3000u64).step_by(3) {
+            db.del(&i.to_be_bytes()).unwrap();
+        }
+        let v = db.verify().unwrap();
+        assert!(v.is_ok(), "{}", v);
+        assert_eq!((2000, 2000), (v.records, v.record_num));
+        assert!(v.level > 1 && v.ovf_pages > 0);
+        assert_eq!(v.busy_pages, v.tree_pages + v.ovf_pages);
+        assert!(v.to_string().starts_with("status ok\n"));
+
+        /* a page taken but never linked is leaked, a leaf pointing nowhere breaks the chain */
+        let leak = db.mtr(|| db.alloc_page()).unwrap();
+        let mut leaf = db.root().1;
+        loop {
+            let p = db.get_page(leaf).unwrap();
+            if p.is_leaf() {
+                break;
+            }
+            leaf = p.child(0);
+        }
+        db.mtr(|| {
+            let mut p = db.get_page_mut(leaf)?;
+            p.set_next(GPID_NIL);
+            db.put_page(p);
+            Ok(())
+        }).unwrap();
+        let n = db.hd().record_num.get();
+        db.hd().record_num.set(n + 1);
+        let v = db.verify().unwrap();
+        let problems: Vec<(&str, _)> = v.problems.iter().map(|p| (p.kind, p.gpid)).collect();
+        assert_eq!(vec![("chain", leaf), ("record_num", GPID_NIL), ("leak", leak)], problems);
+        assert!(v.to_string().contains(&format!("\nproblem leak {} ", leak)));
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,781 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::kv::storage::allocator::{ck_pos, ckid_t};
+use crate::kv::storage::crc64::{kv_crc64, kv_crc64_check, kv_crc64_seal};
+use crate::kv::storage::dwb::get_u64;
+use crate::kv::storage::inner::{bitmap_pages, BUSY_PAGE_NUM_POS, corrupt, DPT_MAX, file_header_s, gpid_t, PAGE_BITMAP_CRC, PAGE_BITMAP_LEN};
+use crate::kv::storage::kvdb::{kvdb_s, pg_t};
+use crate::kv::storage::mmap::CFile;
+use crate::kv::storage::page::seal_page;
+
+/*
+ * The write-ahead log of a database is kept next to it, in segments named
+ * after it with a "-wal." suffix and the lsn they start at, in 16 hex
+ * digits. It is a sequence of groups, each one holds the records of an
+ * operation, and is replayed as a whole or not at all:
+ *
+ *     [body len: u32][crc64 of lsn and body: u64][lsn: u64][body]
+ *
+ * The lsn of a group is the offset of its end in the log, and the one in
+ * its header is the offset of its start, which tells a group from what is
+ * left in a reused segment. The records of a body only redo, they are one
+ * of:
+ *
+ *     [WAL_PAGE][gpid: u64][image of the page]
+ *     [WAL_ALLOC][gpid: u64]
+ *     [WAL_FREE][gpid: u64]
+ *     [WAL_ROOT][root_gpid: u64][level: u32]
+ *     [WAL_COUNT][record_num: u64][total_pages: u64]
+ *
+ * A page goes to the data file only once the log is durable up to the lsn
+ * of its last change, which the cache makes sure of, and so do the header
+ * and the bitmaps, which are kept in private maps until then. The pages
+ * allocated by an operation which did not reach the log may be left busy.
+ *
+ * A checkpoint writes the header and the bitmaps with the dirty page table
+ * of the cache, and the least lsn of the changes which may not be in the
+ * file yet, where the replay starts on open. It goes on up to the first
+ * torn group, and skips the images of the pages which were written before
+ * the checkpoint. The segments before that lsn are not needed any more,
+ * a few of them are kept to be reused as new segments, the others are
+ * removed. Checkpoints are fuzzy, the writers go on meanwhile, only the
+ * pages dirty since before the last checkpoint are written out, so that
+ * the log to replay is about the one written between two checkpoints.
+ *
+ * The log is made durable by group commit: the first writer which needs it
+ * syncs the file for all the groups appended so far, and the ones arriving
+ * meanwhile wait for it, then the next one of them syncs what they have
+ * appended since, in one go.
+ */
+const WAL_PAGE: u8 = 1;
+const WAL_ALLOC: u8 = 2;
+const WAL_FREE: u8 = 3;
+const WAL_ROOT: u8 = 4;
+const WAL_COUNT: u8 = 5;
+
+const GROUP_HEADER_LEN: usize = 20;
+/* the buffered groups are written out, though not synced, when they take this many bytes */
+const WAL_BUF_LEN: usize = 1 << 20;
+/* the segments kept for reuse once they are not needed */
+const WAL_SPARE_NUM: usize = 2;
+
+/* sync_policy_t -- when a write waits for its changes to be durable in the log */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum sync_policy_t {
+    /* before it returns */
+    Always,
+    /* within the interval, the log is synced in the background */
+    Every(Duration),
+    /* only at sync() or close, or when the log is forced by the cache */
+    Never,
+}
+
+/* wal_stats_s -- the counters of the log, to tune the sync policy */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct wal_stats_s {
+    /* the groups appended, one for each operation */
+    pub groups: u64,
+    /* the syncs of the file, and the most groups made durable by one of them */
+    pub syncs: u64,
+    pub max_batch: u64,
+    /* the time spent in the syncs */
+    pub sync_time: Duration,
+    /* the waits of the writers for their groups to be durable, and how long they took */
+    pub waits: u64,
+    pub wait_time: Duration,
+    pub max_wait: Duration,
+    /* the segments started, and the ones which were not needed any more */
+    pub segments: u64,
+    pub recycled: u64,
+}
+
+/*
+ * wal_s -- the log of a database, the groups are buffered until they are
+ *          flushed. synced is signaled when a sync is done, and cv wakes up
+ *          the syncer, which exits once stop is set. A group is appended
+ *          and its pages are marked dirty in the cache under gate shared,
+ *          so that a checkpoint sees the pages of all the groups before it.
+ */
+pub(crate) struct wal_s {
+    name: PathBuf,
+    seg_len: u64,
+    b: Mutex<wal_buf_s>,
+    pub(crate) gate: RwLock<()>,
+    synced: Condvar,
+    stop: Mutex<bool>,
+    cv: Condvar,
+}
+
+struct wal_buf_s {
+    /* the groups appended after what is written */
+    buf: Vec<u8>,
+    /* the lsn of the end of the log, of what is written to the file, and of what is durable */
+    lsn: u64,
+    written: u64,
+    flushed: u64,
+    /* the segment being written and its start, the older ones, and the files to reuse */
+    seg: Arc<CFile>,
+    seg_start: u64,
+    segs: Vec<u64>,
+    spares: Vec<PathBuf>,
+    /* a writer is syncing the file, without the lock */
+    syncing: bool,
+    /* the groups appended before the last sync */
+    synced_groups: u64,
+    st: wal_stats_s,
+}
+
+/*
+ * mtr_s -- a mini-transaction, the changes of one operation, which go to the
+ *          log as one group when it commits. The pages it modifies stay
+ *          latched until then, so that nobody sees a change before it is in
+ *          the log, and the pages it frees are given back only after that.
+ */
+#[derive(Default)]
+pub(crate) struct mtr_s {
+    pages: Vec<pg_t>,
+    /* the images of the pages before the operation first modified them, to undo it */
+    before: Vec<(gpid_t, Vec<u8>)>,
+    allocs: Vec<gpid_t>,
+    frees: Vec<gpid_t>,
+    root: Option<(gpid_t, u32)>,
+    /* the root before the operation changed it */
+    old_root: Option<(gpid_t, u32)>,
+    pub(crate) records: isize,
+}
+
+thread_local! {
+    /* the mini-transaction of the operation running on the thread */
+    static MTR: RefCell<Option<mtr_s>> = const { RefCell::new(None) };
+    /* the lsn of the last group committed by the thread */
+    static LSN: Cell<u64> = const { Cell::new(0) };
+}
+
+/* mtr_lsn() -- the lsn of the last operation of the thread, a write waits for it to be durable */
+pub(crate) fn mtr_lsn() -> u64 {
+    LSN.with(|l| l.get())
+}
+
+/* mtr_keep() -- hand the modified page made by f to the mini-transaction of the thread, false if there is none */
+pub(crate) fn mtr_keep<F>(f: F) -> bool
+    where F: FnOnce() -> pg_t {
+    MTR.with(|m| match m.borrow_mut().as_mut() {
+        Some(m) => {
+            m.pages.push(f());
+            true
+        }
+        None => false,
+    })
+}
+
+/* mtr_save() -- keep the image b of the page gpid before the running operation first modifies it */
+pub(crate) fn mtr_save(gpid: gpid_t, b: &[u8]) {
+    MTR.with(|m| {
+        if let Some(m) = m.borrow_mut().as_mut() {
+            if !m.before.iter().any(|(g, _)| *g == gpid) {
+                m.before.push((gpid, b.to_vec()));
+            }
+        }
+    })
+}
+
+/* mtr_take() -- the page gpid if the mini-transaction of the thread holds it */
+pub(crate) fn mtr_take(gpid: gpid_t) -> Option<pg_t> {
+    MTR.with(|m| {
+        let mut m = m.borrow_mut();
+        let m = m.as_mut()?;
+        let i = m.pages.iter().position(|p| p.gpid() == gpid)?;
+        Some(m.pages.swap_remove(i))
+    })
+}
+
+fn put_u64(b: &mut Vec<u8>, v: u64) {
+    b.extend_from_slice(&v.to_le_bytes());
+}
+
+impl wal_s {
+    /*
+     * open() -- start the log of the database name at lsn, in a new segment.
+     *           The segments left are not needed, they are removed if the
+     *           database is a new one, or kept for reuse.
+     */
+    pub(crate) fn open<P: AsRef<Path>>(name: P, seg_len: u64, lsn: u64, new: bool) -> Result<wal_s> {
+        let name = name.as_ref().to_path_buf();
+        let mut spares = Vec::new();
+        for (start, path) in segments(&name)? {
+            if new || spares.len() >= WAL_SPARE_NUM || start == lsn {
+                CFile::remove(path)?;
+            } else {
+                spares.push(path);
+            }
+        }
+        let seg = open_seg(&name, lsn, &mut spares)?;
+        let b = wal_buf_s {
+            buf: Vec::new(),
+            lsn,
+            written: lsn,
+            flushed: lsn,
+            seg: Arc::new(seg),
+            seg_start: lsn,
+            segs: Vec::new(),
+            spares,
+            syncing: false,
+            synced_groups: 0,
+            st: wal_stats_s { segments: 1, ..Default::default() },
+        };
+        Ok(wal_s { name, seg_len, b: Mutex::new(b), gate: RwLock::new(()), synced: Condvar::new(), stop: Mutex::new(false), cv: Condvar::new() })
+    }
+
+    /*
+     * append() -- add a group of records, f adds the last ones under the lock
+     *             of the log, for the changes which should be logged in the
+     *             order they are made. The start and the end lsn of the group
+     *             are returned, and whether enough is buffered to be written
+     *             out.
+     */
+    pub(crate) fn append<F>(&self, mut body: Vec<u8>, f: F) -> (u64, u64, bool)
+        where F: FnOnce(&mut Vec<u8>) {
+        let mut b = self.b.lock().unwrap();
+        f(&mut body);
+        let start = b.lsn;
+        let mut h = Vec::with_capacity(GROUP_HEADER_LEN);
+        put_u64(&mut h, start);
+        h.extend_from_slice(&body);
+        b.buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        b.buf.extend_from_slice(&kv_crc64(&h).to_le_bytes());
+        b.buf.extend_from_slice(&h);
+        b.lsn += (GROUP_HEADER_LEN + body.len()) as u64;
+        b.st.groups += 1;
+        (start, b.lsn, b.buf.len() >= WAL_BUF_LEN)
+    }
+
+    /* the lsn of the end of the log */
+    pub(crate) fn lsn(&self) -> u64 {
+        self.b.lock().unwrap().lsn
+    }
+
+    /* write() -- write out the buffered groups, without waiting for them to be durable */
+    pub(crate) fn write(&self) -> Result<()> {
+        let mut b = self.b.lock().unwrap();
+        self.write_buf(&mut b)
+    }
+
+    /* write_buf() -- write out the buffered groups, a full segment is synced and followed by a new one */
+    fn write_buf(&self, b: &mut wal_buf_s) -> Result<()> {
+        if b.buf.is_empty() {
+            return Ok(());
+        }
+        b.seg.write_at(&b.buf, b.written - b.seg_start)?;
+        b.written = b.lsn;
+        b.buf.clear();
+        if b.written - b.seg_start >= self.seg_len {
+            b.seg.sync_data()?;
+            b.flushed = b.written;
+            let seg = open_seg(&self.name, b.written, &mut b.spares)?;
+            b.seg = Arc::new(seg);
+            let start = mem::replace(&mut b.seg_start, b.written);
+            b.segs.push(start);
+            b.st.segments += 1;
+        }
+        Ok(())
+    }
+
+    /*
+     * flush() -- make the log durable up to lsn at least. If another writer
+     *            is syncing, this one waits for it, and syncs the groups
+     *            appended meanwhile only if its own is among them.
+     */
+    pub(crate) fn flush(&self, lsn: u64) -> Result<()> {
+        let mut b = self.b.lock().unwrap();
+        if b.flushed >= lsn {
+            return Ok(());
+        }
+        let t0 = Instant::now();
+        while b.syncing {
+            b = self.synced.wait(b).unwrap();
+        }
+        if b.flushed < lsn {
+            b = self.sync_buf(b)?;
+        }
+        let wait = t0.elapsed();
+        b.st.waits += 1;
+        b.st.wait_time += wait;
+        b.st.max_wait = b.st.max_wait.max(wait);
+        Ok(())
+    }
+
+    /* sync_buf() -- write out the buffered groups and sync them, the lock is released during the sync */
+    fn sync_buf<'a>(&'a self, mut b: MutexGuard<'a, wal_buf_s>) -> Result<MutexGuard<'a, wal_buf_s>> {
+        self.write_buf(&mut b)?;
+        let (target, groups, seg) = (b.written, b.st.groups, b.seg.clone());
+        b.syncing = true;
+        drop(b);
+        let t0 = Instant::now();
+        let ret = seg.sync_data();
+        let elapsed = t0.elapsed();
+        let mut b = self.b.lock().unwrap();
+        b.syncing = false;
+        self.synced.notify_all();
+        ret?;
+        b.flushed = b.flushed.max(target);
+        b.st.syncs += 1;
+        b.st.sync_time += elapsed;
+        b.st.max_batch = b.st.max_batch.max(groups - b.synced_groups);
+        b.synced_groups = groups;
+        Ok(b)
+    }
+
+    /* stats() -- the counters of the log since it is opened */
+    pub(crate) fn stats(&self) -> wal_stats_s {
+        self.b.lock().unwrap().st.clone()
+    }
+
+    /*
+     * recycle() -- give up the segments which end before lsn, where the
+     *              replay would start. A few of them are kept to be reused.
+     */
+    pub(crate) fn recycle(&self, lsn: u64) -> Result<()> {
+        let mut b = self.b.lock().unwrap();
+        while !b.segs.is_empty() {
+            let end = if b.segs.len() > 1 { b.segs[1] } else { b.seg_start };
+            if end > lsn {
+                break;
+            }
+            let path = seg_path(&self.name, b.segs.remove(0));
+            if b.spares.len() < WAL_SPARE_NUM {
+                b.spares.push(path);
+            } else {
+                CFile::remove(path)?;
+            }
+            b.st.recycled += 1;
+        }
+        Ok(())
+    }
+
+    /* remove() -- remove the files of the log, the database should be closed cleanly */
+    pub(crate) fn remove(&self) -> Result<()> {
+        let b = self.b.lock().unwrap();
+        let segs = b.segs.iter().chain(Some(&b.seg_start)).map(|s| seg_path(&self.name, *s));
+        for path in segs.chain(b.spares.iter().cloned()) {
+            match CFile::remove(&path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /* start_syncer() -- a thread which makes the log durable every interval, until stop_syncer() */
+    pub(crate) fn start_syncer(wal: Arc<wal_s>, interval: Duration) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut stop = wal.stop.lock().unwrap();
+            loop {
+                stop = wal.cv.wait_timeout(stop, interval).unwrap().0;
+                if *stop {
+                    break;
+                }
+                drop(stop);
+                /* an error is met again by the next writer which waits for the log */
+                let _ = wal.flush(wal.lsn());
+                stop = wal.stop.lock().unwrap();
+            }
+        })
+    }
+
+    pub(crate) fn stop_syncer(&self, syncer: thread::JoinHandle<()>) {
+        *self.stop.lock().unwrap() = true;
+        self.cv.notify_all();
+        let _ = syncer.join();
+    }
+
+    /*
+     * replay() -- redo the log of the database name over the data file and
+     *             its header h, from the redo lsn of the last checkpoint.
+     *             The number of groups replayed and the end of the log are
+     *             returned. The bitmaps and the busy page numbers are
+     *             written, the header is left to the caller.
+     */
+    pub(crate) fn replay<P: AsRef<Path>>(name: P, file: &CFile, h: &mut file_header_s) -> Result<(usize, u64)> {
+        let ps = h.page_size.get() as usize;
+        let segs = segments(name.as_ref())?;
+        /* the pages written before the checkpoint, up to their lsn in the table */
+        let dpt: Option<HashMap<gpid_t, u64>> = if h.dpt_num.get() <= DPT_MAX as u64 {
+            Some(h.dpt[..h.dpt_num.get() as usize].iter().map(|e| (e.gpid.get(), e.rec_lsn.get())).collect())
+        } else {
+            None
+        };
+        let mut pbs: BTreeMap<ckid_t, Vec<u8>> = BTreeMap::new();
+        let mut lsn = h.redo_lsn.get();
+        let mut n = 0;
+        let first = segs.range(..=lsn).next_back().map(|(s, _)| *s);
+        for (&start, path) in segs.range(first.unwrap_or(u64::MAX)..) {
+            /* a segment is missing, or the one before is torn */
+            if start > lsn {
+                break;
+            }
+            let seg = CFile::open(path)?;
+            let len = seg.len()?;
+            while let Some(body) = read_group(&seg, start, len, lsn)? {
+                let written = lsn < h.ckpt_lsn.get();
+                let mut r = &body[..];
+                while !r.is_empty() {
+                    match r[0] {
+                        WAL_PAGE => {
+                            let gpid = get_u64(&r[1..]);
+                            let skip = written && dpt.as_ref().is_some_and(|d| d.get(&gpid).is_none_or(|&l| lsn < l));
+                            if !skip {
+                                let mut b = r[9..9 + ps].to_vec();
+                                seal_page(&mut b);
+                                write_grow(file, &b, kvdb_s::get_page_pos(gpid, ps))?;
+                            }
+                            r = &r[9 + ps..];
+                        }
+                        t @ WAL_ALLOC | t @ WAL_FREE => {
+                            let gpid = get_u64(&r[1..]);
+                            let (ck, lpid) = (kvdb_s::get_ck(gpid), kvdb_s::get_lpid(gpid));
+                            let pb = match pbs.get_mut(&ck) {
+                                Some(pb) => pb,
+                                None => {
+                                    let mut pb = read_zero(file, PAGE_BITMAP_LEN, ck_pos(ck, ps))?;
+                                    /* one all zero is of a chunk opened after the checkpoint, as allocator_s::pb_intact() tells */
+                                    let busy = read_zero(file, mem::size_of::<u32>(), BUSY_PAGE_NUM_POS + ck * mem::size_of::<u32>())?;
+                                    let zero = u32::from_le_bytes([busy[0], busy[1], busy[2], busy[3]]) as usize <= bitmap_pages(ps)
+                                        && pb.iter().all(|b| *b == 0);
+                                    if !kv_crc64_check(&pb, PAGE_BITMAP_CRC) && !zero {
+                                        return Err(corrupt(kvdb_s::get_gpid(ck, 0)));
+                                    }
+                                    /* the bitmap pages at the head of a new chunk are always busy */
+                                    if pb[0] & 1 == 0 {
+                                        for i in 0..bitmap_pages(ps) {
+                                            pb[i >> 3] |= 1 << (i & 7);
+                                        }
+                                    }
+                                    pbs.entry(ck).or_insert(pb)
+                                }
+                            };
+                            if t == WAL_ALLOC {
+                                pb[lpid >> 3] |= 1 << (lpid & 7);
+                            } else {
+                                pb[lpid >> 3] &= !(1 << (lpid & 7));
+                            }
+                            r = &r[9..];
+                        }
+                        WAL_ROOT => {
+                            h.root_gpid.set(get_u64(&r[1..]));
+                            h.level.set(u32::from_le_bytes([r[9], r[10], r[11], r[12]]));
+                            r = &r[13..];
+                        }
+                        WAL_COUNT => {
+                            h.record_num.set(get_u64(&r[1..]));
+                            h.total_pages.set(get_u64(&r[9..]));
+                            r = &r[17..];
+                        }
+                        t => {
+                            return Err(Error::new(ErrorKind::InvalidData, format!("unknown log record {} in the group at {:x}", t, lsn)));
+                        }
+                    }
+                }
+                lsn += (GROUP_HEADER_LEN + body.len()) as u64;
+                n += 1;
+            }
+        }
+        /* the log was durable up to the checkpoint before the header was written */
+        if lsn < h.ckpt_lsn.get() {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("the log ends at {:x}, before the checkpoint at {:x}", lsn, h.ckpt_lsn.get())));
+        }
+        /*
+         * the busy page numbers are counted again from the bitmaps, a crash
+         * in a checkpoint may leave them written, but not the bitmaps
+         */
+        for (ck, pb) in pbs.iter_mut() {
+            let n: u32 = pb[..PAGE_BITMAP_CRC].iter().map(|b| b.count_ones()).sum();
+            kv_crc64_seal(pb, PAGE_BITMAP_CRC);
+            write_grow(file, pb, ck_pos(*ck, ps))?;
+            write_grow(file, &n.to_le_bytes(), BUSY_PAGE_NUM_POS + ck * mem::size_of::<u32>())?;
+        }
+        Ok((n, lsn))
+    }
+}
+
+/* seg_path() -- the segment of the log of the database name which starts at lsn */
+fn seg_path(name: &Path, lsn: u64) -> PathBuf {
+    let mut path = OsString::from(name);
+    path.push(format!("-wal.{:016x}", lsn));
+    PathBuf::from(path)
+}
+
+/* segments() -- the segments of the log of the database name, by the lsn they start at */
+fn segments(name: &Path) -> Result<BTreeMap<u64, PathBuf>> {
+    let dir = match name.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut prefix = name.file_name().unwrap_or_default().to_os_string();
+    prefix.push("-wal.");
+    let prefix = prefix.to_string_lossy().into_owned();
+    let mut segs = BTreeMap::new();
+    for path in CFile::list(dir)? {
+        let f = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        if f.len() == prefix.len() + 16 && f.starts_with(&prefix) {
+            if let Ok(lsn) = u64::from_str_radix(&f[prefix.len()..], 16) {
+                segs.insert(lsn, path);
+            }
+        }
+    }
+    Ok(segs)
+}
+
+/* open_seg() -- a new segment starting at lsn, a spare one is reused if there is any */
+fn open_seg(name: &Path, lsn: u64, spares: &mut Vec<PathBuf>) -> Result<CFile> {
+    let path = seg_path(name, lsn);
+    if let Some(spare) = spares.pop() {
+        CFile::rename(spare, &path)?;
+    }
+    let seg = CFile::open(&path)?;
+    /* the entry of the segment should be durable as well as what is written in it */
+    let dir = name.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    CFile::sync_dir(dir)?;
+    Ok(seg)
+}
+
+/* read_group() -- the body of the group at lsn in the segment from start, None past the last one */
+fn read_group(seg: &CFile, start: u64, len: u64, lsn: u64) -> Result<Option<Vec<u8>>> {
+    let off = lsn - start;
+    let mut h = [0u8; GROUP_HEADER_LEN];
+    if off + GROUP_HEADER_LEN as u64 > len {
+        return Ok(None);
+    }
+    seg.read_at(&mut h, off)?;
+    let n = u32::from_le_bytes([h[0], h[1], h[2], h[3]]) as u64;
+    if get_u64(&h[12..]) != lsn || off + GROUP_HEADER_LEN as u64 + n > len {
+        return Ok(None);
+    }
+    let mut b = vec![0u8; 8 + n as usize];
+    b[..8].copy_from_slice(&h[12..]);
+    seg.read_at(&mut b[8..], off + GROUP_HEADER_LEN as u64)?;
+    if kv_crc64(&b) != get_u64(&h[4..]) {
+        return Ok(None);
+    }
+    Ok(Some(b.split_off(8)))
+}
+
+/* read_zero() -- read len bytes at pos, the part beyond the end of the file is zero */
+fn read_zero(file: &CFile, len: usize, pos: usize) -> Result<Vec<u8>> {
+    let mut b = vec![0u8; len];
+    let end = (file.len()? as usize).min(pos + len);
+    if end > pos {
+        file.read_at(&mut b[..end - pos], pos as u64)?;
+    }
+    Ok(b)
+}
+
+/* write_grow() -- write b at pos, the file grows to hold it if it is too short */
+fn write_grow(file: &CFile, b: &[u8], pos: usize) -> Result<()> {
+    let end = (pos + b.len()) as u64;
+    if file.len()? < end {
+        file.set_len(end)?;
+    }
+    file.write_at(b, pos as u64)
+}
+
+impl kvdb_s {
+    /*
+     * mtr() -- run f as one operation of the log. If f fails, the changes
+     *          it made since it was last logged are undone instead, see
+     *          mtr_rollback(). The operations do not nest.
+     */
+    pub(crate) fn mtr<R, F>(&self, f: F) -> Result<R>
+        where F: FnOnce() -> Result<R> {
+        MTR.with(|m| {
+            assert!(m.borrow().is_none(), "an operation is started inside another one");
+            *m.borrow_mut() = Some(mtr_s::default());
+        });
+        let ret = f();
+        let m = MTR.with(|m| m.borrow_mut().take()).unwrap();
+        match ret {
+            Ok(r) => {
+                self.mtr_commit(m)?;
+                Ok(r)
+            }
+            Err(e) => {
+                self.mtr_rollback(m)?;
+                Err(e)
+            }
+        }
+    }
+
+    /*
+     * mtr_split() -- commit the changes of the running operation so far, and
+     *                go on with a new mini-transaction. The tree lock is
+     *                released only after the changes of the root are logged.
+     */
+    pub(crate) fn mtr_split(&self) -> Result<()> {
+        let m = MTR.with(|m| m.borrow_mut().as_mut().map(mem::take));
+        match m {
+            Some(m) => self.mtr_commit(m),
+            None => Ok(()),
+        }
+    }
+
+    /*
+     * mtr_abort() -- undo the changes of the running operation since it was
+     *                last logged, and go on with a new mini-transaction. It
+     *                is called before the tree lock is released, if the root
+     *                may have been changed.
+     */
+    pub(crate) fn mtr_abort(&self) -> Result<()> {
+        let m = MTR.with(|m| m.borrow_mut().as_mut().map(mem::take));
+        match m {
+            Some(m) => self.mtr_rollback(m),
+            None => Ok(()),
+        }
+    }
+
+    /*
+     * mtr_rollback() -- undo the changes of m, nothing of it is logged. The
+     *                   pages get their images back and are released clean,
+     *                   the pages allocated are given back, and the root is
+     *                   restored. The pages freed and the records counted
+     *                   are simply dropped. A page modified before the last
+     *                   mtr_split() and held since then has no image, it is
+     *                   logged alone as it is.
+     */
+    fn mtr_rollback(&self, m: mtr_s) -> Result<()> {
+        for mut p in m.pages {
+            if let Some((_, b)) = m.before.iter().find(|(gpid, _)| *gpid == p.gpid()) {
+                p.restore(b);
+            }
+        }
+        if !m.allocs.is_empty() {
+            let mut alc = self.alc.lock().unwrap();
+            m.allocs.iter().for_each(|gpid| { alc.pending.remove(gpid); });
+        }
+        for gpid in m.allocs {
+            self.release_page(gpid)?;
+        }
+        if let Some((root, level)) = m.old_root {
+            let mut h = self.hd();
+            h.root_gpid.set(root);
+            h.level.set(level);
+        }
+        Ok(())
+    }
+
+    /* mtr_note() -- add a change to the mini-transaction of the thread, it is logged alone if there is none */
+    pub(crate) fn mtr_note<F>(&self, f: F) -> Result<()>
+        where F: FnOnce(&mut mtr_s) {
+        let mut f = Some(f);
+        if MTR.with(|m| m.borrow_mut().as_mut().map(|m| (f.take().unwrap())(m))).is_none() {
+            let mut m = mtr_s::default();
+            (f.take().unwrap())(&mut m);
+            self.mtr_commit(m)?;
+        }
+        Ok(())
+    }
+
+    /* the page gpid is allocated by the running operation */
+    pub(crate) fn mtr_alloc(&self, gpid: gpid_t) -> Result<()> {
+        self.mtr_note(|m| m.allocs.push(gpid))
+    }
+
+    /* the page gpid is freed by the running operation, it is given back when the operation is logged */
+    pub(crate) fn mtr_free(&self, gpid: gpid_t) -> Result<()> {
+        self.mtr_note(|m| m.frees.push(gpid))
+    }
+
+    /* the root of the tree is changed from old by the running operation, which holds the tree lock */
+    pub(crate) fn mtr_root(&self, root: gpid_t, level: u32, old: (gpid_t, u32)) -> Result<()> {
+        self.mtr_note(|m| {
+            m.old_root.get_or_insert(old);
+            m.root = Some((root, level));
+        })
+    }
+
+    /*
+     * mtr_commit() -- log the changes of m as one group, and release its
+     *                 pages with the lsn of it. The counters of the header
+     *                 are changed under the lock of the log, so that they
+     *                 are logged in order.
+     */
+    fn mtr_commit(&self, m: mtr_s) -> Result<()> {
+        let mut body = Vec::new();
+        for p in m.pages.iter() {
+            body.push(WAL_PAGE);
+            put_u64(&mut body, p.gpid());
+            body.extend_from_slice(p.as_bytes());
+        }
+        for &gpid in m.allocs.iter() {
+            body.push(WAL_ALLOC);
+            put_u64(&mut body, gpid);
+        }
+        for &gpid in m.frees.iter() {
+            body.push(WAL_FREE);
+            put_u64(&mut body, gpid);
+        }
+        if let Some((root, level)) = m.root {
+            body.push(WAL_ROOT);
+            put_u64(&mut body, root);
+            body.extend_from_slice(&level.to_le_bytes());
+        }
+        let pages = m.allocs.len() as isize - m.frees.len() as isize;
+        if body.is_empty() && m.records == 0 {
+            return Ok(());
+        }
+        let gate = self.wal.gate.read().unwrap();
+        let (start, lsn, full) = self.wal.append(body, |b| {
+            if m.records != 0 || pages != 0 {
+                let mut h = self.hd();
+                let (records, total) = (h.record_num.get(), h.total_pages.get());
+                h.record_num.set(records.wrapping_add_signed(m.records as i64));
+                h.total_pages.set(total.wrapping_add_signed(pages as i64));
+                b.push(WAL_COUNT);
+                put_u64(b, h.record_num.get());
+                put_u64(b, h.total_pages.get());
+            }
+        });
+        LSN.with(|l| l.set(lsn));
+        for mut p in m.pages {
+            p.set_lsn(start, lsn);
+        }
+        /* the pages allocated are in the log before the next checkpoint, which waits for the gate */
+        if !m.allocs.is_empty() {
+            let mut alc = self.alc.lock().unwrap();
+            m.allocs.iter().for_each(|gpid| { alc.pending.remove(gpid); });
+        }
+        drop(gate);
+        for gpid in m.frees {
+            self.release_page(gpid)?;
+        }
+        if full {
+            self.wal.write()?;
+        }
+        Ok(())
+    }
+}
+
+/*
+ * log_page() -- log the page p of the cache alone, for a change made out of
+ *               any operation, and return the start and end of the group.
+ *               The gate of the log should be held until the page is dirty.
+ */
+pub(crate) fn log_page(wal: &wal_s, gpid: gpid_t, p: &[u8]) -> (u64, u64) {
+    let mut body = Vec::with_capacity(9 + p.len());
+    body.push(WAL_PAGE);
+    put_u64(&mut body, gpid);
+    body.extend_from_slice(p);
+    let (start, lsn, full) = wal.append(body, |_| {});
+    /* the groups stay buffered if it fails, the next write or flush meets the error again */
+    if full {
+        let _ = wal.write();
+    }
+    (start, lsn)
+}
@@ -5,20 +5,18 @@ use std::path::Path;
 
 use backtrace::{Backtrace, BacktraceSymbol, SymbolName};
 
+/* array_init![v; n] -- an array of n clones of the expression v, evaluated once for each element */
+#[allow(unused_macros)]
 macro_rules! array_init {
-    ($default:expr; $usize:expr)=>{
-   unsafe {
-            let mut array: [_; $usize] = std::mem::MaybeUninit::uninit().assume_init();
-            for elem in array.iter_mut() {
-                std::ptr::write(elem, $default);
-            }
-            array
-        }
-    }
+    ($default:expr; $usize:expr) => {
+        std::array::from_fn::<_, $usize, _>(|_| $default)
+    };
 }
 
 pub mod kv;
 
+/* the code is generated by tonic-build */
+#[allow(clippy::all)]
 pub mod proto {
     pub mod coprocessor {
         tonic::include_proto!("coprocessor");
@@ -177,11 +175,11 @@ impl Display for TraceSymbol {
 
 pub fn innermost_symbol(b: &Backtrace) -> TraceSymbol {
     let frames = b.frames();
-    if frames.len() == 0 {
+    if frames.is_empty() {
         return TraceSymbol { symbol: None }
     }
     let symbols = frames[0].symbols();
-    if symbols.len() == 0 {
+    if symbols.is_empty() {
         return TraceSymbol { symbol: None }
     }
     TraceSymbol { symbol: Some(symbols[0].clone()) }